@@ -222,7 +222,7 @@ impl NetBuffer {
 
     /// Return an iterator that will return slices that represent portions
     /// of the data in this buffer.
-    pub fn iter(&self, length: usize) -> BufferIterator {
+    pub fn iter(&self, length: usize) -> BufferIterator<'_> {
         BufferIterator {
             current_frag: &self.fragments,
             remaining: length,
@@ -264,23 +264,21 @@ impl NetBuffer {
             size <= FRAGMENT_SIZE,
             "Header can't be larger than a fragment"
         );
-        if self.fragments.is_none() || self.fragments.as_ref().unwrap().range.start < size {
-            // Prepend a new frag. We place the data at the end of the frag
-            // to allow space for subsequent headers to be added.
-            let mut new_head_frag = FRAGMENT_POOL.lock().unwrap().alloc();
-            new_head_frag.range = FRAGMENT_SIZE - size..FRAGMENT_SIZE;
-            new_head_frag.next = if self.fragments.is_none() {
-                None
-            } else {
-                self.fragments.take()
-            };
-
-            self.fragments = Some(new_head_frag);
-        } else {
-            // There is sufficient space in the first frag to add the header.
-            // Adjust the start of the frag head
-            let frag = self.fragments.as_mut().unwrap();
-            frag.range.start -= size;
+        match self.fragments.as_mut() {
+            Some(frag) if frag.range.start >= size => {
+                // There is sufficient space in the first frag to add the header.
+                // Adjust the start of the frag head
+                frag.range.start -= size;
+            }
+
+            _ => {
+                // Prepend a new frag. We place the data at the end of the frag
+                // to allow space for subsequent headers to be added.
+                let mut new_head_frag = FRAGMENT_POOL.lock().unwrap().alloc();
+                new_head_frag.range = FRAGMENT_SIZE - size..FRAGMENT_SIZE;
+                new_head_frag.next = self.fragments.take();
+                self.fragments = Some(new_head_frag);
+            }
         }
 
         // Zero out contents of header.
@@ -450,15 +448,14 @@ impl NetBuffer {
     /// end.
     pub fn append_buffer(&mut self, mut other: NetBuffer) {
         self.length += other.length;
-        if self.fragments.is_none() {
-            self.fragments = other.fragments.take();
-        } else {
-            let mut last_frag = self.fragments.as_mut().unwrap();
+        if let Some(mut last_frag) = self.fragments.as_mut() {
             while last_frag.next.is_some() {
                 last_frag = last_frag.next.as_mut().unwrap();
             }
 
             last_frag.next = other.fragments.take();
+        } else {
+            self.fragments = other.fragments.take();
         }
     }
 }
@@ -487,6 +484,7 @@ impl<'a> Iterator for BufferIterator<'a> {
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_range_loop)]
 mod tests {
     // At one point, I would check at the end of each of these tests if all
     // buffers were freed, but that would fail intermittently. It turns out
@@ -0,0 +1,811 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Dynamic Host Configuration Protocol client, as described in RFC 2131.
+// Options are described in RFC 2132.
+//
+// This obtains an IPv4 address, netmask, default router, and DNS servers
+// and installs them into the interface configuration. The lease is renewed
+// from the server that granted it when half of it has elapsed (T1), and
+// from any server (rebinding) if that fails (T2).
+//
+// Message encoding is shared with the server in dhcp_server.rs.
+
+use crate::netif;
+use crate::timer;
+use crate::udp;
+use crate::util;
+use std::sync::{LazyLock, Mutex};
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+pub const BOOTREQUEST: u8 = 1;
+pub const BOOTREPLY: u8 = 2;
+
+pub const DHCPDISCOVER: u8 = 1;
+pub const DHCPOFFER: u8 = 2;
+pub const DHCPREQUEST: u8 = 3;
+pub const DHCPDECLINE: u8 = 4;
+pub const DHCPACK: u8 = 5;
+pub const DHCPNAK: u8 = 6;
+pub const DHCPRELEASE: u8 = 7;
+pub const DHCPINFORM: u8 = 8;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS_SERVER: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAM_REQUEST_LIST: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_END: u8 = 255;
+
+const FLAG_BROADCAST: u16 = 0x8000;
+const HTYPE_ETHERNET: u8 = 1;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const FIXED_HEADER_LEN: usize = 236;
+
+// Some relays drop BOOTP messages shorter than this (RFC 1542, 2.1)
+const MIN_MESSAGE_LEN: usize = 300;
+
+pub const INFINITE_LEASE: u32 = 0xffffffff;
+
+const INITIAL_RETRANSMIT_TIMEOUT: u32 = 4000; // ms
+const MAX_RETRANSMIT_TIMEOUT: u32 = 64000; // ms
+const MAX_REQUEST_RETRIES: u32 = 4;
+const MIN_RENEW_RETRANSMIT: u64 = 60000; // ms
+
+// The timer module takes a 32-bit millisecond timeout, so leases longer
+// than about 49 days are treated as infinite.
+const MAX_TIMER_MS: u64 = 0x7fffffff;
+
+const PARAMETER_REQUEST_LIST: [u8; 6] = [
+    OPT_SUBNET_MASK,
+    OPT_ROUTER,
+    OPT_DNS_SERVER,
+    OPT_LEASE_TIME,
+    OPT_RENEWAL_TIME,
+    OPT_REBINDING_TIME,
+];
+
+//    0               1               2               3
+//    +---------------+---------------+---------------+---------------+
+//  0 |     op (1)    |   htype (1)   |   hlen (1)    |   hops (1)    |
+//    +---------------+---------------+---------------+---------------+
+//  4 |                            xid (4)                            |
+//    +-------------------------------+-------------------------------+
+//  8 |           secs (2)            |           flags (2)           |
+//    +-------------------------------+-------------------------------+
+// 12 |                          ciaddr  (4)                          |
+//    +---------------------------------------------------------------+
+// 16 |                          yiaddr  (4)                          |
+//    +---------------------------------------------------------------+
+// 20 |                          siaddr  (4)                          |
+//    +---------------------------------------------------------------+
+// 24 |                          giaddr  (4)                          |
+//    +---------------------------------------------------------------+
+// 28 |                          chaddr  (16)                         |
+//    +---------------------------------------------------------------+
+// 44 |                          sname   (64)                         |
+//    +---------------------------------------------------------------+
+// 108|                          file    (128)                        |
+//    +---------------------------------------------------------------+
+// 236|                       magic cookie (4)                        |
+//    +---------------------------------------------------------------+
+// 240|                          options (variable)                   |
+//    +---------------------------------------------------------------+
+
+/// A decoded DHCP message. Only the options this stack uses are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DHCPMessage {
+    pub op: u8,
+    pub xid: u32,
+    pub secs: u16,
+    pub flags: u16,
+    pub ciaddr: util::IPAddr,
+    pub yiaddr: util::IPAddr,
+    pub siaddr: util::IPAddr,
+    pub giaddr: util::IPAddr,
+    pub chaddr: [u8; 6],
+    pub message_type: u8,
+    pub subnet_mask: Option<util::IPAddr>,
+    pub routers: Vec<util::IPAddr>,
+    pub dns_servers: Vec<util::IPAddr>,
+    pub requested_ip: Option<util::IPAddr>,
+    pub lease_time: Option<u32>,
+    pub server_id: Option<util::IPAddr>,
+    pub renewal_time: Option<u32>,
+    pub rebinding_time: Option<u32>,
+    pub param_request_list: Vec<u8>,
+}
+
+impl DHCPMessage {
+    pub fn new(op: u8, message_type: u8, xid: u32) -> DHCPMessage {
+        DHCPMessage {
+            op,
+            xid,
+            secs: 0,
+            flags: 0,
+            ciaddr: util::IPAddr::new(),
+            yiaddr: util::IPAddr::new(),
+            siaddr: util::IPAddr::new(),
+            giaddr: util::IPAddr::new(),
+            chaddr: [0; 6],
+            message_type,
+            subnet_mask: None,
+            routers: Vec::new(),
+            dns_servers: Vec::new(),
+            requested_ip: None,
+            lease_time: None,
+            server_id: None,
+            renewal_time: None,
+            rebinding_time: None,
+            param_request_list: Vec::new(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![0u8; FIXED_HEADER_LEN];
+        data[0] = self.op;
+        data[1] = HTYPE_ETHERNET;
+        data[2] = self.chaddr.len() as u8;
+        util::set_be32(&mut data[4..8], self.xid);
+        util::set_be16(&mut data[8..10], self.secs);
+        util::set_be16(&mut data[10..12], self.flags);
+        self.ciaddr.copy_to(&mut data[12..16]);
+        self.yiaddr.copy_to(&mut data[16..20]);
+        self.siaddr.copy_to(&mut data[20..24]);
+        self.giaddr.copy_to(&mut data[24..28]);
+        data[28..34].copy_from_slice(&self.chaddr);
+        data.extend_from_slice(&MAGIC_COOKIE);
+
+        data.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, self.message_type]);
+        if let Some(server_id) = self.server_id {
+            append_addr_option(&mut data, OPT_SERVER_ID, &[server_id]);
+        }
+
+        if let Some(requested_ip) = self.requested_ip {
+            append_addr_option(&mut data, OPT_REQUESTED_IP, &[requested_ip]);
+        }
+
+        if let Some(subnet_mask) = self.subnet_mask {
+            append_addr_option(&mut data, OPT_SUBNET_MASK, &[subnet_mask]);
+        }
+
+        append_addr_option(&mut data, OPT_ROUTER, &self.routers);
+        append_addr_option(&mut data, OPT_DNS_SERVER, &self.dns_servers);
+        for (option, value) in [
+            (OPT_LEASE_TIME, self.lease_time),
+            (OPT_RENEWAL_TIME, self.renewal_time),
+            (OPT_REBINDING_TIME, self.rebinding_time),
+        ] {
+            if let Some(value) = value {
+                data.extend_from_slice(&[option, 4]);
+                data.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+
+        if !self.param_request_list.is_empty() {
+            data.extend_from_slice(&[OPT_PARAM_REQUEST_LIST, self.param_request_list.len() as u8]);
+            data.extend_from_slice(&self.param_request_list);
+        }
+
+        data.push(OPT_END);
+        if data.len() < MIN_MESSAGE_LEN {
+            data.resize(MIN_MESSAGE_LEN, OPT_PAD);
+        }
+
+        data
+    }
+
+    /// Decode a message, returning None if it is malformed or doesn't have
+    /// a message type option (which would make it a plain BOOTP message).
+    pub fn parse(data: &[u8]) -> Option<DHCPMessage> {
+        if data.len() < FIXED_HEADER_LEN + MAGIC_COOKIE.len()
+            || data[1] != HTYPE_ETHERNET
+            || data[2] != 6
+            || data[FIXED_HEADER_LEN..FIXED_HEADER_LEN + 4] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let mut message = DHCPMessage::new(data[0], 0, util::get_be32(&data[4..8]));
+        message.secs = util::get_be16(&data[8..10]);
+        message.flags = util::get_be16(&data[10..12]);
+        message.ciaddr = util::IPAddr::new_from(&data[12..16]);
+        message.yiaddr = util::IPAddr::new_from(&data[16..20]);
+        message.siaddr = util::IPAddr::new_from(&data[20..24]);
+        message.giaddr = util::IPAddr::new_from(&data[24..28]);
+        message.chaddr.copy_from_slice(&data[28..34]);
+
+        let mut offset = FIXED_HEADER_LEN + MAGIC_COOKIE.len();
+        while offset < data.len() {
+            let option = data[offset];
+            if option == OPT_PAD {
+                offset += 1;
+                continue;
+            }
+
+            if option == OPT_END {
+                break;
+            }
+
+            if offset + 2 > data.len() || offset + 2 + data[offset + 1] as usize > data.len() {
+                return None;
+            }
+
+            let value = &data[offset + 2..offset + 2 + data[offset + 1] as usize];
+            offset += 2 + value.len();
+            match option {
+                OPT_MESSAGE_TYPE if value.len() == 1 => message.message_type = value[0],
+                OPT_SUBNET_MASK if value.len() == 4 => {
+                    message.subnet_mask = Some(util::IPAddr::new_from(value))
+                }
+                OPT_ROUTER => message.routers = parse_addr_list(value),
+                OPT_DNS_SERVER => message.dns_servers = parse_addr_list(value),
+                OPT_REQUESTED_IP if value.len() == 4 => {
+                    message.requested_ip = Some(util::IPAddr::new_from(value))
+                }
+                OPT_SERVER_ID if value.len() == 4 => {
+                    message.server_id = Some(util::IPAddr::new_from(value))
+                }
+                OPT_LEASE_TIME if value.len() == 4 => {
+                    message.lease_time = Some(util::get_be32(value))
+                }
+                OPT_RENEWAL_TIME if value.len() == 4 => {
+                    message.renewal_time = Some(util::get_be32(value))
+                }
+                OPT_REBINDING_TIME if value.len() == 4 => {
+                    message.rebinding_time = Some(util::get_be32(value))
+                }
+                OPT_PARAM_REQUEST_LIST => message.param_request_list = value.to_vec(),
+                _ => {}
+            }
+        }
+
+        if message.message_type == 0 {
+            return None;
+        }
+
+        Some(message)
+    }
+}
+
+fn append_addr_option(data: &mut Vec<u8>, option: u8, addrs: &[util::IPAddr]) {
+    if addrs.is_empty() {
+        return;
+    }
+
+    data.extend_from_slice(&[option, (addrs.len() * 4) as u8]);
+    for addr in addrs {
+        let mut bytes = [0u8; 4];
+        addr.copy_to(&mut bytes);
+        data.extend_from_slice(&bytes);
+    }
+}
+
+fn parse_addr_list(value: &[u8]) -> Vec<util::IPAddr> {
+    value.chunks_exact(4).map(util::IPAddr::new_from).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientState {
+    Stopped,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+/// Information about the current lease.
+#[derive(Debug, Clone)]
+pub struct DHCPLease {
+    pub addr: util::IPAddr,
+    pub netmask: util::IPAddr,
+    pub router: Option<util::IPAddr>,
+    pub dns_servers: Vec<util::IPAddr>,
+    pub server_id: util::IPAddr,
+    pub lease_time: u32, // seconds
+}
+
+struct DHCPClient {
+    socket: Option<udp::SocketReference>,
+    state: ClientState,
+    xid: u32,
+    start_time: u64,
+    retransmit_timer: i32,
+    lease_timer: i32,
+    retransmit_timeout: u32,
+    retries: u32,
+
+    // Offered or leased address, and the server that provided it.
+    offered_addr: util::IPAddr,
+    server_id: util::IPAddr,
+    lease: Option<DHCPLease>,
+
+    // Absolute times (ms) for renewal, rebinding, and expiration. None
+    // means the lease doesn't expire.
+    renew_time: Option<u64>,
+    rebind_time: Option<u64>,
+    expire_time: Option<u64>,
+}
+
+static CLIENT: LazyLock<Mutex<DHCPClient>> = LazyLock::new(|| {
+    Mutex::new(DHCPClient {
+        socket: None,
+        state: ClientState::Stopped,
+        xid: 0,
+        start_time: 0,
+        retransmit_timer: -1,
+        lease_timer: -1,
+        retransmit_timeout: INITIAL_RETRANSMIT_TIMEOUT,
+        retries: 0,
+        offered_addr: util::IPAddr::new(),
+        server_id: util::IPAddr::new(),
+        lease: None,
+        renew_time: None,
+        rebind_time: None,
+        expire_time: None,
+    })
+});
+
+/// Start acquiring an IPv4 address using DHCP. Any existing IPv4
+/// configuration is removed until a lease is obtained.
+pub fn dhcp_start() -> Result<(), &'static str> {
+    let mut guard = CLIENT.lock().unwrap();
+    if guard.state != ClientState::Stopped {
+        return Err("DHCP client already running");
+    }
+
+    if guard.socket.is_none() {
+        let mut socket = udp::udp_open(CLIENT_PORT)?;
+        udp::udp_set_broadcast(&mut socket, true);
+        guard.socket = Some(socket.clone());
+        std::thread::spawn(move || {
+            dhcp_receive_thread(socket);
+        });
+    }
+
+    clear_config();
+    start_discovery(&mut guard);
+
+    Ok(())
+}
+
+/// Give up the current lease (telling the server it can be reused) and
+/// stop the client. The IPv4 address is removed from the interface.
+pub fn dhcp_release() {
+    let mut guard = CLIENT.lock().unwrap();
+    if guard.state == ClientState::Stopped {
+        return;
+    }
+
+    timer::cancel_timer(guard.retransmit_timer);
+    timer::cancel_timer(guard.lease_timer);
+    if let Some(lease) = guard.lease.take() {
+        let mut message = new_request(&guard, DHCPRELEASE);
+        message.ciaddr = lease.addr;
+        message.server_id = Some(lease.server_id);
+        send_message(&guard, &message, lease.server_id);
+    }
+
+    guard.state = ClientState::Stopped;
+    clear_config();
+}
+
+/// Return the current lease, or None if we don't have one yet.
+pub fn dhcp_get_lease() -> Option<DHCPLease> {
+    CLIENT.lock().unwrap().lease.clone()
+}
+
+fn dhcp_receive_thread(mut socket: udp::SocketReference) {
+    let mut data = [0u8; netif::MTU];
+    loop {
+        let mut source_addr = util::IPAddr::new();
+        let mut source_port = 0;
+        let len = udp::udp_recv(&mut socket, &mut data, &mut source_addr, &mut source_port);
+        if len < 0 {
+            if udp::udp_is_closed(&mut socket) {
+                return;
+            }
+
+            continue;
+        }
+
+        if source_port != SERVER_PORT {
+            continue;
+        }
+
+        if let Some(message) = DHCPMessage::parse(&data[..len as usize]) {
+            handle_message(&message);
+        }
+    }
+}
+
+fn handle_message(message: &DHCPMessage) {
+    let mut guard = CLIENT.lock().unwrap();
+    if message.op != BOOTREPLY || message.xid != guard.xid || message.chaddr != netif::HW_ADDR {
+        return;
+    }
+
+    match (guard.state, message.message_type) {
+        (ClientState::Selecting, DHCPOFFER) => {
+            let server_id = match message.server_id {
+                Some(server_id) => server_id,
+                None => return,
+            };
+
+            // Take the first offer.
+            println!("DHCP: offered {} by {}", message.yiaddr, server_id);
+            guard.offered_addr = message.yiaddr;
+            guard.server_id = server_id;
+            guard.state = ClientState::Requesting;
+            guard.retries = 0;
+            guard.retransmit_timeout = INITIAL_RETRANSMIT_TIMEOUT;
+            send_request(&mut guard);
+        }
+
+        (ClientState::Requesting | ClientState::Renewing | ClientState::Rebinding, DHCPACK) => {
+            bind(&mut guard, message);
+        }
+
+        (ClientState::Requesting | ClientState::Renewing | ClientState::Rebinding, DHCPNAK) => {
+            println!("DHCP: server refused lease, restarting");
+            guard.lease = None;
+            clear_config();
+            start_discovery(&mut guard);
+        }
+
+        _ => {}
+    }
+}
+
+fn start_discovery(client: &mut DHCPClient) {
+    timer::cancel_timer(client.lease_timer);
+    client.state = ClientState::Selecting;
+    client.start_time = timer::current_time_ms();
+    client.retransmit_timeout = INITIAL_RETRANSMIT_TIMEOUT;
+    client.retries = 0;
+    client.lease = None;
+    send_discover(client);
+}
+
+fn send_discover(client: &mut DHCPClient) {
+    client.xid = rand::random::<u32>();
+    let mut message = new_request(client, DHCPDISCOVER);
+    message.flags = FLAG_BROADCAST;
+    message.param_request_list = PARAMETER_REQUEST_LIST.to_vec();
+    send_message(client, &message, broadcast_addr());
+    schedule_retransmit(client);
+}
+
+fn send_request(client: &mut DHCPClient) {
+    let mut message = new_request(client, DHCPREQUEST);
+    message.param_request_list = PARAMETER_REQUEST_LIST.to_vec();
+    let dest_addr = match client.state {
+        // RFC 2131, 4.3.2
+        ClientState::Requesting => {
+            message.flags = FLAG_BROADCAST;
+            message.requested_ip = Some(client.offered_addr);
+            message.server_id = Some(client.server_id);
+            broadcast_addr()
+        }
+
+        ClientState::Renewing => {
+            message.ciaddr = client.offered_addr;
+            client.server_id
+        }
+
+        _ => {
+            message.ciaddr = client.offered_addr;
+            broadcast_addr()
+        }
+    };
+
+    send_message(client, &message, dest_addr);
+    schedule_retransmit(client);
+}
+
+fn new_request(client: &DHCPClient, message_type: u8) -> DHCPMessage {
+    let mut message = DHCPMessage::new(BOOTREQUEST, message_type, client.xid);
+    message.chaddr = netif::HW_ADDR;
+    message.secs = ((timer::current_time_ms() - client.start_time) / 1000).min(0xffff) as u16;
+    message
+}
+
+fn send_message(client: &DHCPClient, message: &DHCPMessage, dest_addr: util::IPAddr) {
+    if let Some(socket) = &client.socket {
+        let mut socket = socket.clone();
+        if let Err(msg) = udp::udp_send(&mut socket, dest_addr, SERVER_PORT, &message.encode()) {
+            println!("DHCP: error sending message: {}", msg);
+        }
+    }
+}
+
+fn schedule_retransmit(client: &mut DHCPClient) {
+    timer::cancel_timer(client.retransmit_timer);
+    let now = timer::current_time_ms();
+    let timeout = match client.state {
+        // While renewing or rebinding, wait half the remaining time until
+        // the next state change, but at least a minute (RFC 2131, 4.4.5)
+        ClientState::Renewing | ClientState::Rebinding => {
+            let deadline = if client.state == ClientState::Renewing {
+                client.rebind_time
+            } else {
+                client.expire_time
+            };
+
+            match deadline {
+                Some(deadline) => {
+                    (deadline.saturating_sub(now) / 2).max(MIN_RENEW_RETRANSMIT) as u32
+                }
+                None => MIN_RENEW_RETRANSMIT as u32,
+            }
+        }
+
+        // Exponential backoff, randomized by +/- 1 second (RFC 2131, 4.1)
+        _ => {
+            let timeout = client.retransmit_timeout + rand::random::<u32>() % 2000 - 1000;
+            client.retransmit_timeout = (client.retransmit_timeout * 2).min(MAX_RETRANSMIT_TIMEOUT);
+            timeout
+        }
+    };
+
+    client.retransmit_timer = timer::set_timer(timeout, retransmit_timeout);
+}
+
+fn retransmit_timeout() {
+    let mut guard = CLIENT.lock().unwrap();
+    match guard.state {
+        ClientState::Selecting => send_discover(&mut guard),
+        ClientState::Requesting => {
+            guard.retries += 1;
+            if guard.retries > MAX_REQUEST_RETRIES {
+                println!("DHCP: no response to request, restarting");
+                start_discovery(&mut guard);
+            } else {
+                send_request(&mut guard);
+            }
+        }
+
+        ClientState::Renewing | ClientState::Rebinding => send_request(&mut guard),
+
+        _ => {}
+    }
+}
+
+fn bind(client: &mut DHCPClient, message: &DHCPMessage) {
+    let lease_time = message.lease_time.unwrap_or(INFINITE_LEASE);
+    let lease = DHCPLease {
+        addr: message.yiaddr,
+        netmask: message
+            .subnet_mask
+            .unwrap_or_else(|| default_netmask(message.yiaddr)),
+        router: message.routers.first().copied(),
+        dns_servers: message.dns_servers.clone(),
+        server_id: message.server_id.unwrap_or(client.server_id),
+        lease_time,
+    };
+
+    if client.state == ClientState::Requesting {
+        println!(
+            "DHCP: bound to {} netmask {} lease {}s",
+            lease.addr, lease.netmask, lease_time
+        );
+    }
+
+    netif::set_ipv4_config(lease.addr, lease.netmask, lease.router);
+
+    let now = timer::current_time_ms();
+    let lease_ms = lease_time as u64 * 1000;
+    if lease_time == INFINITE_LEASE || lease_ms > MAX_TIMER_MS {
+        client.renew_time = None;
+        client.rebind_time = None;
+        client.expire_time = None;
+    } else {
+        // Default T1 and T2 are 50% and 87.5% of the lease (RFC 2131, 4.4.5)
+        let t1 = message
+            .renewal_time
+            .map(|t| t as u64 * 1000)
+            .unwrap_or(lease_ms / 2)
+            .min(lease_ms);
+        let t2 = message
+            .rebinding_time
+            .map(|t| t as u64 * 1000)
+            .unwrap_or(lease_ms * 7 / 8)
+            .clamp(t1, lease_ms);
+        client.renew_time = Some(now + t1);
+        client.rebind_time = Some(now + t2);
+        client.expire_time = Some(now + lease_ms);
+    }
+
+    client.offered_addr = lease.addr;
+    client.server_id = lease.server_id;
+    client.lease = Some(lease);
+    client.state = ClientState::Bound;
+    timer::cancel_timer(client.retransmit_timer);
+    schedule_lease_timer(client);
+}
+
+fn schedule_lease_timer(client: &mut DHCPClient) {
+    timer::cancel_timer(client.lease_timer);
+    let next = match client.state {
+        ClientState::Bound => client.renew_time,
+        ClientState::Renewing => client.rebind_time,
+        ClientState::Rebinding => client.expire_time,
+        _ => None,
+    };
+
+    client.lease_timer = match next {
+        Some(time) => {
+            let timeout = time.saturating_sub(timer::current_time_ms()) as u32;
+            timer::set_timer(timeout, lease_timeout)
+        }
+        None => -1,
+    };
+}
+
+fn lease_timeout() {
+    let mut guard = CLIENT.lock().unwrap();
+    match guard.state {
+        ClientState::Bound => {
+            println!("DHCP: renewing lease");
+            guard.state = ClientState::Renewing;
+        }
+
+        ClientState::Renewing => {
+            println!("DHCP: rebinding lease");
+            guard.state = ClientState::Rebinding;
+        }
+
+        ClientState::Rebinding => {
+            println!("DHCP: lease expired");
+            clear_config();
+            start_discovery(&mut guard);
+            return;
+        }
+
+        _ => return,
+    }
+
+    guard.xid = rand::random::<u32>();
+    guard.start_time = timer::current_time_ms();
+    send_request(&mut guard);
+    schedule_lease_timer(&mut guard);
+}
+
+fn clear_config() {
+    netif::set_ipv4_config(util::IPAddr::new(), util::IPAddr::new(), None);
+}
+
+fn broadcast_addr() -> util::IPAddr {
+    util::IPAddr::new_from(&[255, 255, 255, 255])
+}
+
+// Classful netmask, used if the server doesn't supply one.
+fn default_netmask(addr: util::IPAddr) -> util::IPAddr {
+    let mut bytes = [0u8; 4];
+    addr.copy_to(&mut bytes);
+    match bytes[0] {
+        0..=127 => util::IPAddr::new_from(&[255, 0, 0, 0]),
+        128..=191 => util::IPAddr::new_from(&[255, 255, 0, 0]),
+        _ => util::IPAddr::new_from(&[255, 255, 255, 0]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_parse_message() {
+        let mut message = DHCPMessage::new(BOOTREPLY, DHCPACK, 0x12345678);
+        message.flags = FLAG_BROADCAST;
+        message.yiaddr = util::IPAddr::new_from(&[192, 168, 1, 100]);
+        message.chaddr = [1, 2, 3, 4, 5, 6];
+        message.subnet_mask = Some(util::IPAddr::new_from(&[255, 255, 255, 0]));
+        message.routers = vec![util::IPAddr::new_from(&[192, 168, 1, 1])];
+        message.dns_servers = vec![
+            util::IPAddr::new_from(&[8, 8, 8, 8]),
+            util::IPAddr::new_from(&[8, 8, 4, 4]),
+        ];
+        message.server_id = Some(util::IPAddr::new_from(&[192, 168, 1, 1]));
+        message.lease_time = Some(3600);
+        message.renewal_time = Some(1800);
+        message.rebinding_time = Some(3150);
+
+        let data = message.encode();
+        assert_eq!(data.len(), MIN_MESSAGE_LEN);
+        assert_eq!(data[0], BOOTREPLY);
+        assert_eq!(&data[16..20], &[192, 168, 1, 100]);
+        assert_eq!(&data[236..240], &MAGIC_COOKIE);
+        assert_eq!(&data[240..243], &[OPT_MESSAGE_TYPE, 1, DHCPACK]);
+
+        assert_eq!(DHCPMessage::parse(&data), Some(message));
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let mut data = vec![0u8; 240];
+        data[0] = BOOTREPLY;
+        data[1] = HTYPE_ETHERNET;
+        data[2] = 6;
+        data[236..240].copy_from_slice(&MAGIC_COOKIE);
+        data.extend_from_slice(&[
+            OPT_PAD,
+            OPT_MESSAGE_TYPE,
+            1,
+            DHCPOFFER,
+            OPT_PAD,
+            OPT_PAD,
+            OPT_LEASE_TIME,
+            4,
+            0,
+            0,
+            0x0e,
+            0x10,
+            99, // Unknown option
+            2,
+            0xaa,
+            0xbb,
+            OPT_END,
+            OPT_MESSAGE_TYPE, // After end, ignored
+            1,
+            DHCPNAK,
+        ]);
+
+        let message = DHCPMessage::parse(&data).unwrap();
+        assert_eq!(message.message_type, DHCPOFFER);
+        assert_eq!(message.lease_time, Some(3600));
+        assert!(message.server_id.is_none());
+
+        // Option runs past end of packet
+        data.truncate(244);
+        data.extend_from_slice(&[OPT_LEASE_TIME, 4, 0, 0]);
+        assert!(DHCPMessage::parse(&data).is_none());
+
+        // No message type (BOOTP)
+        data.truncate(240);
+        data.push(OPT_END);
+        assert!(DHCPMessage::parse(&data).is_none());
+
+        // Bad magic cookie
+        data[236] = 0;
+        assert!(DHCPMessage::parse(&data).is_none());
+    }
+
+    #[test]
+    fn test_default_netmask() {
+        assert_eq!(
+            default_netmask(util::IPAddr::new_from(&[10, 1, 2, 3])),
+            util::IPAddr::new_from(&[255, 0, 0, 0])
+        );
+        assert_eq!(
+            default_netmask(util::IPAddr::new_from(&[172, 16, 2, 3])),
+            util::IPAddr::new_from(&[255, 255, 0, 0])
+        );
+        assert_eq!(
+            default_netmask(util::IPAddr::new_from(&[192, 168, 2, 3])),
+            util::IPAddr::new_from(&[255, 255, 255, 0])
+        );
+    }
+}
@@ -0,0 +1,619 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// DHCP server (RFC 2131), which hands out addresses to hosts on the other
+// side of the interface.
+//
+// Addresses come from a contiguous pool, or from static assignments keyed
+// by hardware address. Leases can optionally be saved to a file so clients
+// keep the same address when the server restarts. The file has one lease
+// per line:
+//
+//     02:00:5e:10:00:03 192.168.1.100 1735689600
+//
+// The last field is the expiration time in seconds since the epoch.
+// Addresses that a client declined (because something else is already
+// using them) are saved with "declined" in place of the hardware address.
+
+use crate::dhcp;
+use crate::netif;
+use crate::timer;
+use crate::udp;
+use crate::util;
+use std::sync::Mutex;
+
+// How long an offered address is held for a client before it can be
+// given to someone else.
+const OFFER_HOLD_TIME: u64 = 60000; // ms
+
+pub struct DHCPServerConfig {
+    /// First and last addresses (inclusive) that can be assigned
+    /// dynamically.
+    pub pool_start: util::IPAddr,
+    pub pool_end: util::IPAddr,
+
+    pub netmask: util::IPAddr,
+    pub router: Option<util::IPAddr>,
+    pub dns_servers: Vec<util::IPAddr>,
+
+    /// Seconds.
+    pub lease_time: u32,
+
+    /// Hardware addresses that always get the same IP address. These may
+    /// be outside the pool.
+    pub static_leases: Vec<([u8; 6], util::IPAddr)>,
+
+    /// If set, leases are loaded from and saved to this file.
+    pub lease_file: Option<String>,
+}
+
+impl DHCPServerConfig {
+    pub fn new(pool_start: util::IPAddr, pool_end: util::IPAddr) -> DHCPServerConfig {
+        DHCPServerConfig {
+            pool_start,
+            pool_end,
+            netmask: util::IPAddr::new_from(&[255, 255, 255, 0]),
+            router: None,
+            dns_servers: Vec::new(),
+            lease_time: 3600,
+            static_leases: Vec::new(),
+            lease_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Lease {
+    hw_addr: [u8; 6],
+    addr: u32,
+    expire_time: u64, // ms since epoch
+
+    // Not assigned to any client, but can't be handed out because some
+    // other host is using it. hw_addr is all zeroes.
+    declined: bool,
+}
+
+struct LeaseTable {
+    pool_start: u32,
+    pool_end: u32,
+    static_leases: Vec<([u8; 6], u32)>,
+    leases: Vec<Lease>,
+}
+
+impl LeaseTable {
+    fn new(pool_start: u32, pool_end: u32, static_leases: Vec<([u8; 6], u32)>) -> LeaseTable {
+        LeaseTable {
+            pool_start,
+            pool_end,
+            static_leases,
+            leases: Vec::new(),
+        }
+    }
+
+    fn find_lease(&self, hw_addr: &[u8; 6], now: u64) -> Option<u32> {
+        self.leases
+            .iter()
+            .find(|lease| !lease.declined && lease.hw_addr == *hw_addr && lease.expire_time > now)
+            .map(|lease| lease.addr)
+    }
+
+    // Returns true if this address can be given to the host with this
+    // hardware address.
+    fn is_available(&self, addr: u32, hw_addr: &[u8; 6], now: u64) -> bool {
+        !self
+            .static_leases
+            .iter()
+            .any(|(static_hw, static_addr)| *static_addr == addr && static_hw != hw_addr)
+            && !self.leases.iter().any(|lease| {
+                lease.addr == addr
+                    && (lease.declined || lease.hw_addr != *hw_addr)
+                    && lease.expire_time > now
+            })
+    }
+
+    /// Pick an address for a client: a static assignment, the address it
+    /// already has, the address it asked for, or the first free one in the
+    /// pool, in that order.
+    fn choose_addr(
+        &self,
+        hw_addr: &[u8; 6],
+        requested: Option<u32>,
+        reserved: u32,
+        now: u64,
+    ) -> Option<u32> {
+        if let Some((_, addr)) = self.static_leases.iter().find(|(hw, _)| hw == hw_addr) {
+            return Some(*addr);
+        }
+
+        if let Some(addr) = self.find_lease(hw_addr, now) {
+            return Some(addr);
+        }
+
+        if let Some(addr) = requested {
+            if addr >= self.pool_start
+                && addr <= self.pool_end
+                && addr != reserved
+                && self.is_available(addr, hw_addr, now)
+            {
+                return Some(addr);
+            }
+        }
+
+        (self.pool_start..=self.pool_end)
+            .find(|addr| *addr != reserved && self.is_available(*addr, hw_addr, now))
+    }
+
+    /// Record that an address is assigned to a client until the given time.
+    fn assign(&mut self, hw_addr: &[u8; 6], addr: u32, expire_time: u64) {
+        self.leases
+            .retain(|lease| (lease.declined || lease.hw_addr != *hw_addr) && lease.addr != addr);
+        self.leases.push(Lease {
+            hw_addr: *hw_addr,
+            addr,
+            expire_time,
+            declined: false,
+        });
+    }
+
+    /// Keep an address that is in use by some other host from being handed
+    /// out until the given time.
+    fn decline(&mut self, addr: u32, expire_time: u64) {
+        self.leases.retain(|lease| lease.addr != addr);
+        self.leases.push(Lease {
+            hw_addr: [0; 6],
+            addr,
+            expire_time,
+            declined: true,
+        });
+    }
+
+    fn release(&mut self, hw_addr: &[u8; 6], addr: u32) {
+        self.leases
+            .retain(|lease| lease.declined || !(lease.hw_addr == *hw_addr && lease.addr == addr));
+    }
+
+    fn remove_expired(&mut self, now: u64) {
+        self.leases.retain(|lease| lease.expire_time > now);
+    }
+}
+
+struct DHCPServer {
+    config: DHCPServerConfig,
+    table: LeaseTable,
+    socket: udp::SocketReference,
+}
+
+static SERVER: Mutex<Option<DHCPServer>> = Mutex::new(None);
+
+/// Start serving addresses on the DHCP server port. The interface's own
+/// IPv4 address is used as the server identifier and is never handed out.
+pub fn dhcp_server_start(config: DHCPServerConfig) -> Result<(), &'static str> {
+    let pool_start = addr_to_u32(config.pool_start).ok_or("Invalid pool start")?;
+    let pool_end = addr_to_u32(config.pool_end).ok_or("Invalid pool end")?;
+    if pool_start > pool_end {
+        return Err("Invalid pool range");
+    }
+
+    let mut static_leases = Vec::new();
+    for (hw_addr, addr) in &config.static_leases {
+        static_leases.push((*hw_addr, addr_to_u32(*addr).ok_or("Invalid static lease")?));
+    }
+
+    let mut guard = SERVER.lock().unwrap();
+    if guard.is_some() {
+        return Err("DHCP server already running");
+    }
+
+    let mut table = LeaseTable::new(pool_start, pool_end, static_leases);
+    if let Some(path) = &config.lease_file {
+        // It's fine if this doesn't exist yet.
+        if let Ok(contents) = std::fs::read_to_string(path) {
+            table.leases = parse_leases(&contents);
+            table.remove_expired(timer::current_time_ms());
+            println!("DHCP server: loaded {} leases", table.leases.len());
+        }
+    }
+
+    let mut socket = udp::udp_open(dhcp::SERVER_PORT)?;
+    udp::udp_set_broadcast(&mut socket, true);
+    *guard = Some(DHCPServer {
+        config,
+        table,
+        socket: socket.clone(),
+    });
+
+    std::thread::spawn(move || {
+        dhcp_server_thread(socket);
+    });
+
+    Ok(())
+}
+
+fn dhcp_server_thread(mut socket: udp::SocketReference) {
+    let mut data = [0u8; netif::MTU];
+    loop {
+        let mut source_addr = util::IPAddr::new();
+        let mut source_port = 0;
+        let len = udp::udp_recv(&mut socket, &mut data, &mut source_addr, &mut source_port);
+        if len < 0 {
+            if udp::udp_is_closed(&mut socket) {
+                return;
+            }
+
+            continue;
+        }
+
+        if let Some(message) = dhcp::DHCPMessage::parse(&data[..len as usize]) {
+            if message.op == dhcp::BOOTREQUEST {
+                let mut guard = SERVER.lock().unwrap();
+                if let Some(server) = guard.as_mut() {
+                    handle_request(server, &message);
+                }
+            }
+        }
+    }
+}
+
+fn handle_request(server: &mut DHCPServer, request: &dhcp::DHCPMessage) {
+    let now = timer::current_time_ms();
+    let server_id = netif::get_ipv4_addr();
+    let reserved = addr_to_u32(server_id).unwrap_or(0);
+    let hw_addr = request.chaddr;
+    server.table.remove_expired(now);
+
+    match request.message_type {
+        dhcp::DHCPDISCOVER => {
+            let requested = request.requested_ip.and_then(addr_to_u32);
+            match server.table.choose_addr(&hw_addr, requested, reserved, now) {
+                Some(addr) => {
+                    server.table.assign(&hw_addr, addr, now + OFFER_HOLD_TIME);
+                    let reply = build_reply(server, request, dhcp::DHCPOFFER, addr);
+                    send_reply(server, request, &reply);
+                }
+
+                None => println!("DHCP server: address pool exhausted"),
+            }
+        }
+
+        dhcp::DHCPREQUEST => {
+            // If the client is responding to another server's offer, it
+            // isn't going to use ours.
+            if matches!(request.server_id, Some(id) if id != server_id) {
+                if let Some(addr) = server.table.find_lease(&hw_addr, now) {
+                    server.table.release(&hw_addr, addr);
+                }
+
+                return;
+            }
+
+            // In SELECTING or INIT-REBOOT, the address is in the requested
+            // IP option. When renewing or rebinding, it's in ciaddr.
+            let requested = request
+                .requested_ip
+                .or(Some(request.ciaddr))
+                .and_then(addr_to_u32);
+            let chosen = server.table.choose_addr(&hw_addr, requested, reserved, now);
+            match (requested, chosen) {
+                (Some(requested), Some(chosen)) if requested == chosen => {
+                    let expire_time = if server.config.lease_time == dhcp::INFINITE_LEASE {
+                        u64::MAX
+                    } else {
+                        now + server.config.lease_time as u64 * 1000
+                    };
+
+                    server.table.assign(&hw_addr, chosen, expire_time);
+                    save_leases(server);
+                    let reply = build_reply(server, request, dhcp::DHCPACK, chosen);
+                    send_reply(server, request, &reply);
+                    println!(
+                        "DHCP server: leased {} to {}",
+                        u32_to_addr(chosen),
+                        format_hw_addr(&hw_addr)
+                    );
+                }
+
+                _ => {
+                    let mut reply =
+                        dhcp::DHCPMessage::new(dhcp::BOOTREPLY, dhcp::DHCPNAK, request.xid);
+                    reply.chaddr = hw_addr;
+                    reply.giaddr = request.giaddr;
+                    reply.server_id = Some(server_id);
+                    send_reply(server, request, &reply);
+                }
+            }
+        }
+
+        // Clients must include the server identifier in these, so ignore
+        // any that are meant for another server.
+        dhcp::DHCPDECLINE | dhcp::DHCPRELEASE if request.server_id != Some(server_id) => {}
+
+        dhcp::DHCPDECLINE => {
+            if let Some(addr) = request.requested_ip.and_then(addr_to_u32) {
+                println!(
+                    "DHCP server: {} is in use by another host",
+                    u32_to_addr(addr)
+                );
+                server
+                    .table
+                    .decline(addr, now + server.config.lease_time as u64 * 1000);
+                save_leases(server);
+            }
+        }
+
+        dhcp::DHCPRELEASE => {
+            if let Some(addr) = addr_to_u32(request.ciaddr) {
+                server.table.release(&hw_addr, addr);
+                save_leases(server);
+            }
+        }
+
+        dhcp::DHCPINFORM => {
+            // The client already has an address, and just wants the other
+            // configuration parameters.
+            let mut reply = build_reply(server, request, dhcp::DHCPACK, 0);
+            reply.lease_time = None;
+            send_reply(server, request, &reply);
+        }
+
+        _ => {}
+    }
+}
+
+fn build_reply(
+    server: &DHCPServer,
+    request: &dhcp::DHCPMessage,
+    message_type: u8,
+    addr: u32,
+) -> dhcp::DHCPMessage {
+    let mut reply = dhcp::DHCPMessage::new(dhcp::BOOTREPLY, message_type, request.xid);
+    reply.flags = request.flags;
+    reply.ciaddr = request.ciaddr;
+    if addr != 0 {
+        reply.yiaddr = u32_to_addr(addr);
+    }
+
+    reply.giaddr = request.giaddr;
+    reply.chaddr = request.chaddr;
+    reply.server_id = Some(netif::get_ipv4_addr());
+    reply.subnet_mask = Some(server.config.netmask);
+    reply.routers = server.config.router.into_iter().collect();
+    reply.dns_servers = server.config.dns_servers.clone();
+    reply.lease_time = Some(server.config.lease_time);
+    reply
+}
+
+// RFC 2131, 4.1. TUN devices don't have a link layer, so there is no way
+// to unicast to a client that doesn't have its address configured yet.
+// Anything that isn't going to a relay or a configured client is broadcast.
+fn send_reply(server: &DHCPServer, request: &dhcp::DHCPMessage, reply: &dhcp::DHCPMessage) {
+    let (dest_addr, dest_port) = if request.giaddr != util::IPAddr::new() {
+        (request.giaddr, dhcp::SERVER_PORT)
+    } else if request.ciaddr != util::IPAddr::new() && reply.message_type != dhcp::DHCPNAK {
+        (request.ciaddr, dhcp::CLIENT_PORT)
+    } else {
+        (
+            util::IPAddr::new_from(&[255, 255, 255, 255]),
+            dhcp::CLIENT_PORT,
+        )
+    };
+
+    let mut socket = server.socket.clone();
+    if let Err(msg) = udp::udp_send(&mut socket, dest_addr, dest_port, &reply.encode()) {
+        println!("DHCP server: error sending reply: {}", msg);
+    }
+}
+
+fn save_leases(server: &DHCPServer) {
+    if let Some(path) = &server.config.lease_file {
+        if let Err(err) = std::fs::write(path, format_leases(&server.table.leases)) {
+            println!("DHCP server: error saving leases: {}", err);
+        }
+    }
+}
+
+fn format_leases(leases: &[Lease]) -> String {
+    let mut contents = String::new();
+    for lease in leases {
+        let owner = if lease.declined {
+            String::from("declined")
+        } else {
+            format_hw_addr(&lease.hw_addr)
+        };
+
+        contents += &format!(
+            "{} {} {}\n",
+            owner,
+            u32_to_addr(lease.addr),
+            lease.expire_time / 1000
+        );
+    }
+
+    contents
+}
+
+// Lines that can't be parsed are skipped.
+fn parse_leases(contents: &str) -> Vec<Lease> {
+    let mut leases = Vec::new();
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            continue;
+        }
+
+        let declined = fields[0] == "declined";
+        let hw_bytes: Vec<u8> = if declined {
+            vec![0; 6]
+        } else {
+            fields[0]
+                .split(':')
+                .filter_map(|byte| u8::from_str_radix(byte, 16).ok())
+                .collect()
+        };
+        let addr = fields[1].parse::<std::net::Ipv4Addr>();
+        let expire_time = fields[2].parse::<u64>();
+        if let (6, Ok(addr), Ok(expire_time)) = (hw_bytes.len(), addr, expire_time) {
+            let mut hw_addr = [0u8; 6];
+            hw_addr.copy_from_slice(&hw_bytes);
+            leases.push(Lease {
+                hw_addr,
+                addr: u32::from(addr),
+                expire_time: expire_time.saturating_mul(1000),
+                declined,
+            });
+        }
+    }
+
+    leases
+}
+
+fn format_hw_addr(hw_addr: &[u8; 6]) -> String {
+    hw_addr
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+fn addr_to_u32(addr: util::IPAddr) -> Option<u32> {
+    match addr {
+        util::IPAddr::V4(bytes) if bytes != [0; 4] => Some(u32::from_be_bytes(bytes)),
+        _ => None,
+    }
+}
+
+fn u32_to_addr(addr: u32) -> util::IPAddr {
+    util::IPAddr::V4(addr.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HW1: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const HW2: [u8; 6] = [2, 0, 0, 0, 0, 2];
+    const HW3: [u8; 6] = [2, 0, 0, 0, 0, 3];
+
+    #[test]
+    fn test_choose_addr() {
+        let mut table = LeaseTable::new(100, 102, vec![(HW3, 101)]);
+
+        // Skips the reserved and statically assigned addresses.
+        assert_eq!(table.choose_addr(&HW1, None, 100, 0), Some(102));
+        table.assign(&HW1, 102, 1000);
+
+        // Same client gets the same address.
+        assert_eq!(table.choose_addr(&HW1, Some(100), 0, 500), Some(102));
+
+        // Requested address is honored if it's free.
+        assert_eq!(table.choose_addr(&HW2, Some(100), 0, 500), Some(100));
+
+        // But not if someone else has it.
+        assert_eq!(table.choose_addr(&HW2, Some(102), 0, 500), Some(100));
+        table.assign(&HW2, 100, 1000);
+
+        // Static lease.
+        assert_eq!(table.choose_addr(&HW3, None, 0, 500), Some(101));
+
+        // Pool is exhausted
+        assert_eq!(table.choose_addr(&[2, 0, 0, 0, 0, 4], None, 0, 500), None);
+
+        // ...until a lease expires.
+        assert_eq!(
+            table.choose_addr(&[2, 0, 0, 0, 0, 4], None, 0, 1000),
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn test_assign_release() {
+        let mut table = LeaseTable::new(100, 110, Vec::new());
+        table.assign(&HW1, 100, 1000);
+        table.assign(&HW1, 105, 1000);
+        assert_eq!(table.leases.len(), 1);
+        assert_eq!(table.find_lease(&HW1, 0), Some(105));
+
+        table.release(&HW2, 105);
+        assert_eq!(table.find_lease(&HW1, 0), Some(105));
+        table.release(&HW1, 105);
+        assert_eq!(table.find_lease(&HW1, 0), None);
+    }
+
+    #[test]
+    fn test_decline() {
+        let mut table = LeaseTable::new(100, 103, Vec::new());
+        table.assign(&HW1, 100, 1000);
+        table.assign(&HW2, 101, 1000);
+        table.decline(100, 2000);
+        table.decline(101, 2000);
+
+        // Both declined addresses stay out of the pool, even for the
+        // clients that had them.
+        assert_eq!(table.find_lease(&HW1, 0), None);
+        assert_eq!(table.choose_addr(&HW1, Some(100), 0, 500), Some(102));
+        table.assign(&HW1, 102, 1000);
+        assert_eq!(table.choose_addr(&HW2, Some(101), 0, 500), Some(103));
+        table.assign(&HW2, 103, 1000);
+        assert_eq!(table.choose_addr(&HW3, None, 0, 500), None);
+        assert_eq!(table.leases.len(), 4);
+
+        // Releasing doesn't free them either
+        table.release(&[0; 6], 100);
+        assert_eq!(table.choose_addr(&HW3, None, 0, 500), None);
+
+        // They come back once the hold expires
+        assert_eq!(table.choose_addr(&HW3, None, 0, 2000), Some(100));
+    }
+
+    #[test]
+    fn test_lease_file() {
+        let leases = vec![
+            Lease {
+                hw_addr: [0x02, 0x00, 0x5e, 0x10, 0x00, 0x03],
+                addr: 0xc0a80164,
+                expire_time: 1735689600000,
+                declined: false,
+            },
+            Lease {
+                hw_addr: HW1,
+                addr: 0xc0a80165,
+                expire_time: 1735689700000,
+                declined: false,
+            },
+            Lease {
+                hw_addr: [0; 6],
+                addr: 0xc0a80166,
+                expire_time: 1735689800000,
+                declined: true,
+            },
+        ];
+
+        let contents = format_leases(&leases);
+        assert!(contents.starts_with("02:00:5e:10:00:03 192.168.1.100 1735689600\n"));
+        assert!(contents.ends_with("declined 192.168.1.102 1735689800\n"));
+        assert_eq!(parse_leases(&contents), leases);
+
+        let contents = "garbage\n02:00:00 192.168.1.1 10\n02:00:00:00:00:01 192.168.1.1 10\n";
+        assert_eq!(
+            parse_leases(contents),
+            vec![Lease {
+                hw_addr: HW1,
+                addr: 0xc0a80101,
+                expire_time: 10000,
+                declined: false,
+            }]
+        );
+    }
+}
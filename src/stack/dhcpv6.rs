@@ -0,0 +1,839 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// DHCPv6 client, as described in RFC 8415.
+//
+// This supports two modes, which are normally selected by flags in router
+// advertisements:
+// - Stateful: addresses are requested from a server with the
+//   Solicit/Advertise/Request/Reply exchange. They are renewed from the same
+//   server at T1, and from any server at T2.
+// - Stateless: only other configuration (DNS servers) is requested with
+//   Information-Request, and is refreshed periodically.
+//
+// Addresses are installed with nd::configure_address, so they go through
+// duplicate address detection and lifetime tracking the same way SLAAC
+// addresses do.
+//
+// Only a single non-temporary address association (IA_NA) is requested.
+// The first server to advertise addresses is used, rather than collecting
+// advertisements and picking the one with the highest preference.
+
+use crate::nd;
+use crate::netif;
+use crate::timer;
+use crate::udp;
+use crate::util;
+use std::sync::{LazyLock, Mutex};
+
+const CLIENT_PORT: u16 = 546;
+const SERVER_PORT: u16 = 547;
+
+const ALL_DHCP_RELAY_AGENTS_AND_SERVERS: [u8; 16] =
+    [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 2];
+
+const SOLICIT: u8 = 1;
+const ADVERTISE: u8 = 2;
+const REQUEST: u8 = 3;
+const RENEW: u8 = 5;
+const REBIND: u8 = 6;
+const REPLY: u8 = 7;
+const INFORMATION_REQUEST: u8 = 11;
+
+const OPT_CLIENTID: u16 = 1;
+const OPT_SERVERID: u16 = 2;
+const OPT_IA_NA: u16 = 3;
+const OPT_IAADDR: u16 = 5;
+const OPT_ORO: u16 = 6;
+const OPT_ELAPSED_TIME: u16 = 8;
+const OPT_STATUS_CODE: u16 = 13;
+const OPT_DNS_SERVERS: u16 = 23;
+const OPT_INFORMATION_REFRESH_TIME: u16 = 32;
+
+const STATUS_SUCCESS: u16 = 0;
+
+const DUID_LL: u16 = 3;
+const HWTYPE_ETHERNET: u16 = 1;
+const IAID: u32 = 1;
+
+const INFINITY: u32 = 0xffffffff;
+
+// Retransmission parameters (RFC 8415, section 7.6), in ms.
+const SOL_TIMEOUT: u32 = 1000;
+const SOL_MAX_RT: u32 = 3600000;
+const REQ_TIMEOUT: u32 = 1000;
+const REQ_MAX_RT: u32 = 30000;
+const REQ_MAX_RC: u32 = 10;
+const REN_TIMEOUT: u32 = 10000;
+const REN_MAX_RT: u32 = 600000;
+const REB_TIMEOUT: u32 = 10000;
+const REB_MAX_RT: u32 = 600000;
+const INF_TIMEOUT: u32 = 1000;
+const INF_MAX_RT: u32 = 3600000;
+
+// Seconds
+const IRT_DEFAULT: u32 = 86400;
+const IRT_MINIMUM: u32 = 600;
+
+// The timer module takes a 32-bit millisecond timeout.
+const MAX_TIMER_MS: u64 = 0x7fffffff;
+
+//    0               1               2               3
+//    +---------------+-----------------------------------------------+
+//  0 |    msg-type   |               transaction-id                  |
+//    +---------------+-----------------------------------------------+
+//  4 |                            options                            |
+//    |                           (variable)                          |
+//    +---------------------------------------------------------------+
+//
+// Each option is:
+//    +-------------------------------+-------------------------------+
+//  0 |          option-code          |           option-len          |
+//    +-------------------------------+-------------------------------+
+//  4 |                          option-data                          |
+//    +---------------------------------------------------------------+
+
+#[derive(Debug, Clone, PartialEq)]
+struct IAAddress {
+    addr: util::IPAddr,
+    preferred_lifetime: u32,
+    valid_lifetime: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct IdentityAssociation {
+    iaid: u32,
+    t1: u32,
+    t2: u32,
+    addresses: Vec<IAAddress>,
+    status_code: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DHCPv6Message {
+    message_type: u8,
+    transaction_id: u32,
+    client_id: Option<Vec<u8>>,
+    server_id: Option<Vec<u8>>,
+    ia_na: Option<IdentityAssociation>,
+    status_code: u16,
+    elapsed_time: u16,
+    option_request: Vec<u16>,
+    dns_servers: Vec<util::IPAddr>,
+    information_refresh_time: Option<u32>,
+}
+
+impl DHCPv6Message {
+    fn new(message_type: u8, transaction_id: u32) -> DHCPv6Message {
+        DHCPv6Message {
+            message_type,
+            transaction_id,
+            client_id: None,
+            server_id: None,
+            ia_na: None,
+            status_code: STATUS_SUCCESS,
+            elapsed_time: 0,
+            option_request: Vec::new(),
+            dns_servers: Vec::new(),
+            information_refresh_time: None,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = self.transaction_id.to_be_bytes().to_vec();
+        data[0] = self.message_type;
+        if let Some(client_id) = &self.client_id {
+            append_option(&mut data, OPT_CLIENTID, client_id);
+        }
+
+        if let Some(server_id) = &self.server_id {
+            append_option(&mut data, OPT_SERVERID, server_id);
+        }
+
+        append_option(
+            &mut data,
+            OPT_ELAPSED_TIME,
+            &self.elapsed_time.to_be_bytes(),
+        );
+        if !self.option_request.is_empty() {
+            let value: Vec<u8> = self
+                .option_request
+                .iter()
+                .flat_map(|option| option.to_be_bytes())
+                .collect();
+            append_option(&mut data, OPT_ORO, &value);
+        }
+
+        if let Some(ia_na) = &self.ia_na {
+            let mut value = Vec::new();
+            value.extend_from_slice(&ia_na.iaid.to_be_bytes());
+            value.extend_from_slice(&ia_na.t1.to_be_bytes());
+            value.extend_from_slice(&ia_na.t2.to_be_bytes());
+            for address in &ia_na.addresses {
+                let mut addr_value = [0u8; 24];
+                address.addr.copy_to(&mut addr_value[0..16]);
+                util::set_be32(&mut addr_value[16..20], address.preferred_lifetime);
+                util::set_be32(&mut addr_value[20..24], address.valid_lifetime);
+                append_option(&mut value, OPT_IAADDR, &addr_value);
+            }
+
+            append_option(&mut data, OPT_IA_NA, &value);
+        }
+
+        if !self.dns_servers.is_empty() {
+            let mut value = vec![0u8; self.dns_servers.len() * 16];
+            for (i, server) in self.dns_servers.iter().enumerate() {
+                server.copy_to(&mut value[i * 16..(i + 1) * 16]);
+            }
+
+            append_option(&mut data, OPT_DNS_SERVERS, &value);
+        }
+
+        if let Some(refresh_time) = self.information_refresh_time {
+            append_option(
+                &mut data,
+                OPT_INFORMATION_REFRESH_TIME,
+                &refresh_time.to_be_bytes(),
+            );
+        }
+
+        data
+    }
+
+    fn parse(data: &[u8]) -> Option<DHCPv6Message> {
+        if data.len() < 4 {
+            return None;
+        }
+
+        let mut message = DHCPv6Message::new(data[0], util::get_be32(&data[0..4]) & 0xffffff);
+        for (option, value) in parse_options(&data[4..])? {
+            match option {
+                OPT_CLIENTID => message.client_id = Some(value.to_vec()),
+                OPT_SERVERID => message.server_id = Some(value.to_vec()),
+                OPT_IA_NA => message.ia_na = Some(parse_ia_na(value)?),
+                OPT_STATUS_CODE if value.len() >= 2 => message.status_code = util::get_be16(value),
+                OPT_ELAPSED_TIME if value.len() == 2 => {
+                    message.elapsed_time = util::get_be16(value)
+                }
+                OPT_ORO => {
+                    message.option_request = value.chunks_exact(2).map(util::get_be16).collect()
+                }
+                OPT_DNS_SERVERS => {
+                    message.dns_servers =
+                        value.chunks_exact(16).map(util::IPAddr::new_from).collect()
+                }
+                OPT_INFORMATION_REFRESH_TIME if value.len() == 4 => {
+                    message.information_refresh_time = Some(util::get_be32(value))
+                }
+                _ => {}
+            }
+        }
+
+        Some(message)
+    }
+}
+
+fn append_option(data: &mut Vec<u8>, option: u16, value: &[u8]) {
+    data.extend_from_slice(&option.to_be_bytes());
+    data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    data.extend_from_slice(value);
+}
+
+fn parse_options(mut data: &[u8]) -> Option<Vec<(u16, &[u8])>> {
+    let mut options = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 4 {
+            return None;
+        }
+
+        let len = util::get_be16(&data[2..4]) as usize;
+        if data.len() < 4 + len {
+            return None;
+        }
+
+        options.push((util::get_be16(&data[0..2]), &data[4..4 + len]));
+        data = &data[4 + len..];
+    }
+
+    Some(options)
+}
+
+//    +---------------------------------------------------------------+
+//  0 |                        IAID (4 octets)                        |
+//    +---------------------------------------------------------------+
+//  4 |                              T1                               |
+//    +---------------------------------------------------------------+
+//  8 |                              T2                               |
+//    +---------------------------------------------------------------+
+// 12 |                         IA_NA-options                         |
+//    +---------------------------------------------------------------+
+//
+// IA Address option:
+//    +---------------------------------------------------------------+
+//  0 |                         IPv6-address                          |
+//    |                          (16 octets)                          |
+//    +---------------------------------------------------------------+
+// 16 |                      preferred-lifetime                       |
+//    +---------------------------------------------------------------+
+// 20 |                        valid-lifetime                         |
+//    +---------------------------------------------------------------+
+// 24 |                        IAaddr-options                         |
+//    +---------------------------------------------------------------+
+
+fn parse_ia_na(data: &[u8]) -> Option<IdentityAssociation> {
+    if data.len() < 12 {
+        return None;
+    }
+
+    let mut ia_na = IdentityAssociation {
+        iaid: util::get_be32(&data[0..4]),
+        t1: util::get_be32(&data[4..8]),
+        t2: util::get_be32(&data[8..12]),
+        addresses: Vec::new(),
+        status_code: STATUS_SUCCESS,
+    };
+
+    for (option, value) in parse_options(&data[12..])? {
+        match option {
+            OPT_IAADDR if value.len() >= 24 => {
+                let address = IAAddress {
+                    addr: util::IPAddr::new_from(&value[0..16]),
+                    preferred_lifetime: util::get_be32(&value[16..20]),
+                    valid_lifetime: util::get_be32(&value[20..24]),
+                };
+
+                // RFC 8415, 18.2.10.1
+                if address.preferred_lifetime <= address.valid_lifetime {
+                    ia_na.addresses.push(address);
+                }
+            }
+            OPT_STATUS_CODE if value.len() >= 2 => ia_na.status_code = util::get_be16(value),
+            _ => {}
+        }
+    }
+
+    Some(ia_na)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ClientState {
+    Stopped,
+    Soliciting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+    InformationRequesting,
+    InformationBound,
+}
+
+struct DHCPv6Client {
+    socket: Option<udp::SocketReference>,
+    state: ClientState,
+    transaction_id: u32,
+    start_time: u64,
+    retransmit_timer: i32,
+    lease_timer: i32,
+    retransmit_timeout: u32,
+    retransmit_count: u32,
+    server_id: Vec<u8>,
+    addresses: Vec<IAAddress>,
+    dns_servers: Vec<util::IPAddr>,
+
+    // Absolute times (ms). None if infinite.
+    renew_time: Option<u64>,
+    rebind_time: Option<u64>,
+    expire_time: Option<u64>,
+}
+
+static CLIENT: LazyLock<Mutex<DHCPv6Client>> = LazyLock::new(|| {
+    Mutex::new(DHCPv6Client {
+        socket: None,
+        state: ClientState::Stopped,
+        transaction_id: 0,
+        start_time: 0,
+        retransmit_timer: -1,
+        lease_timer: -1,
+        retransmit_timeout: 0,
+        retransmit_count: 0,
+        server_id: Vec::new(),
+        addresses: Vec::new(),
+        dns_servers: Vec::new(),
+        renew_time: None,
+        rebind_time: None,
+        expire_time: None,
+    })
+});
+
+/// Start the DHCPv6 client. If stateful is true, this requests addresses
+/// as well as other configuration. This is normally started automatically
+/// when a router advertisement has the managed or other configuration flag
+/// set, but can be called directly on networks without a router.
+pub fn dhcpv6_start(stateful: bool) -> Result<(), &'static str> {
+    let mut guard = CLIENT.lock().unwrap();
+    if guard.state != ClientState::Stopped {
+        return Err("DHCPv6 client already running");
+    }
+
+    if guard.socket.is_none() {
+        let socket = udp::udp_open(CLIENT_PORT)?;
+        guard.socket = Some(socket.clone());
+        std::thread::spawn(move || {
+            dhcpv6_receive_thread(socket);
+        });
+    }
+
+    if stateful {
+        start_exchange(&mut guard, ClientState::Soliciting);
+    } else {
+        start_exchange(&mut guard, ClientState::InformationRequesting);
+    }
+
+    Ok(())
+}
+
+/// Called by neighbor discovery when a router advertisement indicates
+/// DHCPv6 is available. If the client is only getting other configuration
+/// and the router now says addresses are available, it switches to
+/// stateful mode.
+pub fn start_from_advertisement(stateful: bool) {
+    let mut guard = CLIENT.lock().unwrap();
+    match guard.state {
+        ClientState::Stopped => {
+            drop(guard);
+            if let Err(msg) = dhcpv6_start(stateful) {
+                println!("DHCPv6: failed to start: {}", msg);
+            }
+        }
+
+        ClientState::InformationRequesting | ClientState::InformationBound if stateful => {
+            timer::cancel_timer(guard.lease_timer);
+            start_exchange(&mut guard, ClientState::Soliciting);
+        }
+
+        _ => {}
+    }
+}
+
+/// Return DNS servers learned from DHCPv6.
+pub fn dhcpv6_get_dns_servers() -> Vec<util::IPAddr> {
+    CLIENT.lock().unwrap().dns_servers.clone()
+}
+
+fn dhcpv6_receive_thread(mut socket: udp::SocketReference) {
+    let mut data = [0u8; netif::MTU];
+    loop {
+        let mut source_addr = util::IPAddr::new();
+        let mut source_port = 0;
+        let len = udp::udp_recv(&mut socket, &mut data, &mut source_addr, &mut source_port);
+        if len < 0 {
+            if udp::udp_is_closed(&mut socket) {
+                return;
+            }
+
+            continue;
+        }
+
+        if source_port != SERVER_PORT {
+            continue;
+        }
+
+        if let Some(message) = DHCPv6Message::parse(&data[..len as usize]) {
+            handle_message(&message);
+        }
+    }
+}
+
+fn handle_message(message: &DHCPv6Message) {
+    let mut guard = CLIENT.lock().unwrap();
+    if message.transaction_id != guard.transaction_id
+        || message.client_id.as_deref() != Some(&client_duid())
+        || message.server_id.is_none()
+    {
+        return;
+    }
+
+    match (guard.state, message.message_type) {
+        (ClientState::Soliciting, ADVERTISE) => {
+            let has_addresses = matches!(&message.ia_na,
+                Some(ia_na) if ia_na.status_code == STATUS_SUCCESS && !ia_na.addresses.is_empty());
+            if message.status_code != STATUS_SUCCESS || !has_addresses {
+                return;
+            }
+
+            guard.server_id = message.server_id.clone().unwrap();
+            guard.addresses = message.ia_na.as_ref().unwrap().addresses.clone();
+            start_exchange(&mut guard, ClientState::Requesting);
+        }
+
+        (ClientState::Requesting | ClientState::Renewing | ClientState::Rebinding, REPLY) => {
+            let ia_na = match &message.ia_na {
+                Some(ia_na)
+                    if message.status_code == STATUS_SUCCESS
+                        && ia_na.status_code == STATUS_SUCCESS
+                        && !ia_na.addresses.is_empty() =>
+                {
+                    ia_na
+                }
+
+                _ => {
+                    println!("DHCPv6: server didn't assign addresses, restarting");
+                    start_exchange(&mut guard, ClientState::Soliciting);
+                    return;
+                }
+            };
+
+            guard.server_id = message.server_id.clone().unwrap();
+            guard.dns_servers = message.dns_servers.clone();
+            bind(&mut guard, ia_na);
+        }
+
+        (ClientState::InformationRequesting, REPLY) => {
+            timer::cancel_timer(guard.retransmit_timer);
+            guard.dns_servers = message.dns_servers.clone();
+            guard.state = ClientState::InformationBound;
+
+            // RFC 8415, 21.23
+            let refresh = message
+                .information_refresh_time
+                .unwrap_or(IRT_DEFAULT)
+                .max(IRT_MINIMUM);
+            guard.lease_timer = if refresh == INFINITY {
+                -1
+            } else {
+                timer::set_timer(clamp_timeout(refresh as u64 * 1000), lease_timeout)
+            };
+        }
+
+        _ => {}
+    }
+}
+
+fn bind(client: &mut DHCPv6Client, ia_na: &IdentityAssociation) {
+    for address in &ia_na.addresses {
+        if client.state == ClientState::Requesting {
+            println!("DHCPv6: assigned {}", address.addr);
+        }
+
+        // The prefix length isn't known from DHCPv6. Routers advertise the
+        // on-link prefix separately.
+        nd::configure_address(
+            address.addr,
+            128,
+            address.preferred_lifetime,
+            address.valid_lifetime,
+        );
+    }
+
+    // If the server leaves T1 and T2 up to the client, use the recommended
+    // 0.5 and 0.8 times the shortest preferred lifetime (RFC 8415, 21.4)
+    let shortest_preferred = ia_na
+        .addresses
+        .iter()
+        .map(|address| address.preferred_lifetime)
+        .min()
+        .unwrap_or(INFINITY);
+    let longest_valid = ia_na
+        .addresses
+        .iter()
+        .map(|address| address.valid_lifetime)
+        .max()
+        .unwrap_or(INFINITY);
+    let (t1, t2) = if ia_na.t1 == 0 && ia_na.t2 == 0 && shortest_preferred != INFINITY {
+        (shortest_preferred / 2, shortest_preferred / 5 * 4)
+    } else {
+        (ia_na.t1, ia_na.t2.max(ia_na.t1))
+    };
+
+    let now = timer::current_time_ms();
+    let to_deadline = |seconds: u32| {
+        if seconds == INFINITY {
+            None
+        } else {
+            Some(now + seconds as u64 * 1000)
+        }
+    };
+
+    client.renew_time = to_deadline(t1);
+    client.rebind_time = to_deadline(t2);
+    client.expire_time = to_deadline(longest_valid);
+    client.addresses = ia_na.addresses.clone();
+    client.state = ClientState::Bound;
+    timer::cancel_timer(client.retransmit_timer);
+    schedule_lease_timer(client);
+}
+
+fn schedule_lease_timer(client: &mut DHCPv6Client) {
+    timer::cancel_timer(client.lease_timer);
+    let next = match client.state {
+        ClientState::Bound => client.renew_time,
+        ClientState::Renewing => client.rebind_time,
+        ClientState::Rebinding => client.expire_time,
+        _ => None,
+    };
+
+    client.lease_timer = match next {
+        Some(time) => timer::set_timer(
+            clamp_timeout(time.saturating_sub(timer::current_time_ms())),
+            lease_timeout,
+        ),
+        None => -1,
+    };
+}
+
+fn lease_timeout() {
+    let mut guard = CLIENT.lock().unwrap();
+    match guard.state {
+        ClientState::Bound => {
+            println!("DHCPv6: renewing");
+            start_exchange(&mut guard, ClientState::Renewing);
+            schedule_lease_timer(&mut guard);
+        }
+
+        ClientState::Renewing => {
+            println!("DHCPv6: rebinding");
+            start_exchange(&mut guard, ClientState::Rebinding);
+            schedule_lease_timer(&mut guard);
+        }
+
+        ClientState::Rebinding => {
+            // The addresses themselves are removed by the lifetime tracking
+            // in nd.
+            println!("DHCPv6: lease expired");
+            start_exchange(&mut guard, ClientState::Soliciting);
+        }
+
+        ClientState::InformationBound => {
+            start_exchange(&mut guard, ClientState::InformationRequesting)
+        }
+
+        _ => {}
+    }
+}
+
+fn start_exchange(client: &mut DHCPv6Client, state: ClientState) {
+    client.state = state;
+    client.transaction_id = rand::random::<u32>() & 0xffffff;
+    client.start_time = timer::current_time_ms();
+    client.retransmit_count = 0;
+    client.retransmit_timeout = 0;
+    transmit(client);
+}
+
+fn transmit(client: &mut DHCPv6Client) {
+    let message_type = match client.state {
+        ClientState::Soliciting => SOLICIT,
+        ClientState::Requesting => REQUEST,
+        ClientState::Renewing => RENEW,
+        ClientState::Rebinding => REBIND,
+        ClientState::InformationRequesting => INFORMATION_REQUEST,
+        _ => return,
+    };
+
+    let mut message = DHCPv6Message::new(message_type, client.transaction_id);
+    message.client_id = Some(client_duid());
+    message.option_request = vec![OPT_DNS_SERVERS];
+
+    // Hundredths of a second since the exchange started.
+    let elapsed = (timer::current_time_ms() - client.start_time) / 10;
+    message.elapsed_time = elapsed.min(0xffff) as u16;
+
+    if matches!(
+        client.state,
+        ClientState::Requesting | ClientState::Renewing
+    ) {
+        message.server_id = Some(client.server_id.clone());
+    }
+
+    if message_type != INFORMATION_REQUEST {
+        let addresses = if message_type == SOLICIT {
+            Vec::new()
+        } else {
+            client.addresses.clone()
+        };
+
+        message.ia_na = Some(IdentityAssociation {
+            iaid: IAID,
+            t1: 0,
+            t2: 0,
+            addresses,
+            status_code: STATUS_SUCCESS,
+        });
+    }
+
+    if let Some(socket) = &client.socket {
+        let mut socket = socket.clone();
+        if let Err(msg) = udp::udp_send(
+            &mut socket,
+            util::IPAddr::V6(ALL_DHCP_RELAY_AGENTS_AND_SERVERS),
+            SERVER_PORT,
+            &message.encode(),
+        ) {
+            println!("DHCPv6: error sending message: {}", msg);
+        }
+    }
+
+    schedule_retransmit(client);
+}
+
+// RFC 8415, 15
+fn schedule_retransmit(client: &mut DHCPv6Client) {
+    let (initial, maximum, max_count) = match client.state {
+        ClientState::Soliciting => (SOL_TIMEOUT, SOL_MAX_RT, 0),
+        ClientState::Requesting => (REQ_TIMEOUT, REQ_MAX_RT, REQ_MAX_RC),
+        ClientState::Renewing => (REN_TIMEOUT, REN_MAX_RT, 0),
+        ClientState::Rebinding => (REB_TIMEOUT, REB_MAX_RT, 0),
+        ClientState::InformationRequesting => (INF_TIMEOUT, INF_MAX_RT, 0),
+        _ => return,
+    };
+
+    timer::cancel_timer(client.retransmit_timer);
+    client.retransmit_count += 1;
+    if max_count != 0 && client.retransmit_count > max_count {
+        client.retransmit_timer = timer::set_timer(0, || {
+            let mut guard = CLIENT.lock().unwrap();
+            println!("DHCPv6: no reply from server, restarting");
+            start_exchange(&mut guard, ClientState::Soliciting);
+        });
+
+        return;
+    }
+
+    client.retransmit_timeout = next_retransmit_timeout(
+        client.retransmit_timeout,
+        initial,
+        maximum,
+        rand::random::<f64>() * 0.2 - 0.1,
+    );
+    client.retransmit_timer = timer::set_timer(client.retransmit_timeout, || {
+        let mut guard = CLIENT.lock().unwrap();
+        transmit(&mut guard);
+    });
+}
+
+// The first timeout is the initial value, and each after that doubles, up
+// to a maximum. Each is randomized by the jitter factor (+/- 0.1)
+fn next_retransmit_timeout(previous: u32, initial: u32, maximum: u32, jitter: f64) -> u32 {
+    let base = if previous == 0 {
+        initial as f64
+    } else {
+        previous as f64 * 2.0
+    };
+
+    let mut timeout = base + jitter * base;
+    if timeout > maximum as f64 {
+        timeout = maximum as f64 + jitter * maximum as f64;
+    }
+
+    timeout as u32
+}
+
+fn clamp_timeout(timeout_ms: u64) -> u32 {
+    timeout_ms.min(MAX_TIMER_MS) as u32
+}
+
+// DUID based on link-layer address (RFC 8415, 11.4)
+fn client_duid() -> Vec<u8> {
+    let mut duid = Vec::new();
+    duid.extend_from_slice(&DUID_LL.to_be_bytes());
+    duid.extend_from_slice(&HWTYPE_ETHERNET.to_be_bytes());
+    duid.extend_from_slice(&netif::HW_ADDR);
+    duid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_parse_message() {
+        let mut message = DHCPv6Message::new(REPLY, 0x123456);
+        message.client_id = Some(client_duid());
+        message.server_id = Some(vec![0, 1, 2, 3]);
+        message.elapsed_time = 100;
+        message.option_request = vec![OPT_DNS_SERVERS];
+        message.ia_na = Some(IdentityAssociation {
+            iaid: IAID,
+            t1: 1800,
+            t2: 2880,
+            addresses: vec![IAAddress {
+                addr: util::IPAddr::new_from(&[
+                    0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0x01,
+                ]),
+                preferred_lifetime: 3600,
+                valid_lifetime: 7200,
+            }],
+            status_code: STATUS_SUCCESS,
+        });
+        message.dns_servers = vec![util::IPAddr::new_from(&[
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x53,
+        ])];
+        message.information_refresh_time = Some(1000);
+
+        let data = message.encode();
+        assert_eq!(&data[0..4], &[REPLY, 0x12, 0x34, 0x56]);
+        assert_eq!(&data[4..8], &[0, OPT_CLIENTID as u8, 0, 10]);
+        assert_eq!(&data[8..14], &[0, 3, 0, 1, 0x02, 0x00]);
+
+        assert_eq!(DHCPv6Message::parse(&data), Some(message));
+    }
+
+    #[test]
+    fn test_parse_ia_status() {
+        // IA_NA with a NoAddrsAvail status code and no addresses
+        let mut value = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+        append_option(&mut value, OPT_STATUS_CODE, &[0, 2]);
+        let mut data = vec![ADVERTISE, 0, 0, 1];
+        append_option(&mut data, OPT_IA_NA, &value);
+
+        let message = DHCPv6Message::parse(&data).unwrap();
+        assert_eq!(message.transaction_id, 1);
+        let ia_na = message.ia_na.unwrap();
+        assert_eq!(ia_na.status_code, 2);
+        assert!(ia_na.addresses.is_empty());
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        assert!(DHCPv6Message::parse(&[REPLY, 0, 0]).is_none());
+
+        // Option length runs past end
+        assert!(DHCPv6Message::parse(&[REPLY, 0, 0, 1, 0, 1, 0, 8, 0, 0]).is_none());
+
+        // Truncated IA_NA
+        assert!(DHCPv6Message::parse(&[REPLY, 0, 0, 1, 0, 3, 0, 4, 0, 0, 0, 1]).is_none());
+
+        // Address with preferred lifetime greater than valid is dropped
+        let mut value = vec![0u8; 12];
+        let mut addr_value = [0u8; 24];
+        util::set_be32(&mut addr_value[16..20], 100);
+        util::set_be32(&mut addr_value[20..24], 50);
+        append_option(&mut value, OPT_IAADDR, &addr_value);
+        let mut data = vec![REPLY, 0, 0, 1];
+        append_option(&mut data, OPT_IA_NA, &value);
+        let message = DHCPv6Message::parse(&data).unwrap();
+        assert!(message.ia_na.unwrap().addresses.is_empty());
+    }
+
+    #[test]
+    fn test_retransmit_timeout() {
+        assert_eq!(next_retransmit_timeout(0, 1000, 30000, 0.0), 1000);
+        assert_eq!(next_retransmit_timeout(1000, 1000, 30000, 0.0), 2000);
+        assert_eq!(next_retransmit_timeout(1000, 1000, 30000, 0.1), 2200);
+        assert_eq!(next_retransmit_timeout(20000, 1000, 30000, 0.0), 30000);
+        assert_eq!(next_retransmit_timeout(20000, 1000, 30000, -0.1), 27000);
+    }
+}
@@ -0,0 +1,791 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Domain Name System stub resolver (RFC 1034, 1035).
+//
+// This sends recursive queries to the configured servers and caches the
+// results for as long as their TTL allows. Queries are sent over UDP. If
+// the response doesn't fit in a UDP datagram, the server sets the truncated
+// flag and the query is repeated over TCP (RFC 7766).
+//
+// All queries share one UDP socket. A thread reads responses from it and
+// hands them to the waiting caller, matching them by query ID.
+//
+// Message encoding is also used by the multicast DNS responder in mdns.rs.
+
+use crate::dhcp;
+use crate::dhcpv6;
+use crate::ip;
+use crate::netif;
+use crate::tcp;
+use crate::timer;
+use crate::udp;
+use crate::util;
+use std::collections::HashMap;
+use std::sync::{mpsc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DNS_PORT: u16 = 53;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+pub const CLASS_IN: u16 = 1;
+
+pub const FLAG_RESPONSE: u16 = 0x8000;
+pub const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NAME_ERROR: u16 = 3;
+
+const HEADER_LEN: usize = 12;
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+// Limits how many compression pointers will be followed, to catch loops.
+const MAX_POINTERS: usize = 16;
+
+const QUERY_TIMEOUT: u64 = 2000; // ms
+const QUERY_ATTEMPTS: u32 = 3;
+
+// Seconds to remember that a name has no records of the requested type
+// (RFC 2308 would use the SOA minimum, but this keeps things simple).
+const NEGATIVE_CACHE_TTL: u32 = 60;
+
+const MAX_MESSAGE_LEN: usize = 65535;
+
+//    0               1               2               3
+//    +-------------------------------+-------------------------------+
+//  0 |              ID               |QR| Opcode |AA|TC|RD|RA| Z|RCODE|
+//    +-------------------------------+-------------------------------+
+//  4 |           QDCOUNT             |           ANCOUNT             |
+//    +-------------------------------+-------------------------------+
+//  8 |           NSCOUNT             |           ARCOUNT             |
+//    +-------------------------------+-------------------------------+
+// 12 |                 Questions, then resource records              |
+//    +---------------------------------------------------------------+
+//
+// Each resource record is:
+//    +---------------------------------------------------------------+
+//    |                          Name (variable)                      |
+//    +-------------------------------+-------------------------------+
+//    |             Type              |             Class             |
+//    +-------------------------------+-------------------------------+
+//    |                              TTL                              |
+//    +-------------------------------+-------------------------------+
+//    |           RDLENGTH            |         RDATA (variable)      |
+//    +-------------------------------+-------------------------------+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DNSQuestion {
+    pub name: String,
+    pub record_type: u16,
+    pub class: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DNSRecord {
+    pub name: String,
+    pub record_type: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DNSMessage {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<DNSQuestion>,
+    pub answers: Vec<DNSRecord>,
+    pub authority: Vec<DNSRecord>,
+    pub additional: Vec<DNSRecord>,
+}
+
+impl DNSMessage {
+    pub fn new(id: u16, flags: u16) -> DNSMessage {
+        DNSMessage {
+            id,
+            flags,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+        }
+    }
+
+    /// Names are written without compression.
+    pub fn encode(&self) -> Result<Vec<u8>, &'static str> {
+        let mut data = vec![0u8; HEADER_LEN];
+        util::set_be16(&mut data[0..2], self.id);
+        util::set_be16(&mut data[2..4], self.flags);
+        util::set_be16(&mut data[4..6], self.questions.len() as u16);
+        util::set_be16(&mut data[6..8], self.answers.len() as u16);
+        util::set_be16(&mut data[8..10], self.authority.len() as u16);
+        util::set_be16(&mut data[10..12], self.additional.len() as u16);
+        for question in &self.questions {
+            encode_name(&mut data, &question.name)?;
+            data.extend_from_slice(&question.record_type.to_be_bytes());
+            data.extend_from_slice(&question.class.to_be_bytes());
+        }
+
+        for record in self
+            .answers
+            .iter()
+            .chain(self.authority.iter())
+            .chain(self.additional.iter())
+        {
+            encode_name(&mut data, &record.name)?;
+            data.extend_from_slice(&record.record_type.to_be_bytes());
+            data.extend_from_slice(&record.class.to_be_bytes());
+            data.extend_from_slice(&record.ttl.to_be_bytes());
+            data.extend_from_slice(&(record.data.len() as u16).to_be_bytes());
+            data.extend_from_slice(&record.data);
+        }
+
+        if data.len() > MAX_MESSAGE_LEN {
+            return Err("Message too long");
+        }
+
+        Ok(data)
+    }
+
+    pub fn parse(data: &[u8]) -> Option<DNSMessage> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+
+        let mut message = DNSMessage::new(util::get_be16(&data[0..2]), util::get_be16(&data[2..4]));
+        let question_count = util::get_be16(&data[4..6]);
+        let answer_count = util::get_be16(&data[6..8]);
+        let authority_count = util::get_be16(&data[8..10]);
+        let additional_count = util::get_be16(&data[10..12]);
+
+        let mut offset = HEADER_LEN;
+        for _ in 0..question_count {
+            let (name, next) = read_name(data, offset)?;
+            if next + 4 > data.len() {
+                return None;
+            }
+
+            message.questions.push(DNSQuestion {
+                name,
+                record_type: util::get_be16(&data[next..next + 2]),
+                class: util::get_be16(&data[next + 2..next + 4]),
+            });
+            offset = next + 4;
+        }
+
+        for (count, section) in [
+            (answer_count, &mut message.answers),
+            (authority_count, &mut message.authority),
+            (additional_count, &mut message.additional),
+        ] {
+            for _ in 0..count {
+                let (record, next) = read_record(data, offset)?;
+                section.push(record);
+                offset = next;
+            }
+        }
+
+        Some(message)
+    }
+}
+
+/// Append a name in wire format (a sequence of length prefixed labels).
+pub fn encode_name(data: &mut Vec<u8>, name: &str) -> Result<(), &'static str> {
+    let start = data.len();
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() {
+            // The root name has no labels.
+            if name.trim_end_matches('.').is_empty() {
+                break;
+            }
+
+            return Err("Empty label in name");
+        }
+
+        if label.len() > MAX_LABEL_LEN {
+            return Err("Label too long");
+        }
+
+        data.push(label.len() as u8);
+        data.extend_from_slice(label.as_bytes());
+    }
+
+    data.push(0);
+    if data.len() - start > MAX_NAME_LEN {
+        return Err("Name too long");
+    }
+
+    Ok(())
+}
+
+/// Decode a possibly compressed name starting at offset. Returns the name
+/// and the offset just past it in the original location.
+pub fn read_name(data: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end_offset = None;
+    let mut pointer_count = 0;
+    loop {
+        let len = *data.get(offset)? as usize;
+        if len == 0 {
+            offset += 1;
+            break;
+        }
+
+        if (len & 0xc0) == 0xc0 {
+            // Compression pointer
+            pointer_count += 1;
+            if pointer_count > MAX_POINTERS || offset + 2 > data.len() {
+                return None;
+            }
+
+            if end_offset.is_none() {
+                end_offset = Some(offset + 2);
+            }
+
+            offset = (util::get_be16(&data[offset..offset + 2]) & 0x3fff) as usize;
+            continue;
+        }
+
+        if len > MAX_LABEL_LEN || offset + 1 + len > data.len() {
+            return None;
+        }
+
+        if !name.is_empty() {
+            name.push('.');
+        }
+
+        name.push_str(&String::from_utf8_lossy(
+            &data[offset + 1..offset + 1 + len],
+        ));
+        if name.len() > MAX_NAME_LEN {
+            return None;
+        }
+
+        offset += 1 + len;
+    }
+
+    Some((name, end_offset.unwrap_or(offset)))
+}
+
+fn read_record(data: &[u8], offset: usize) -> Option<(DNSRecord, usize)> {
+    let (name, offset) = read_name(data, offset)?;
+    if offset + 10 > data.len() {
+        return None;
+    }
+
+    let data_len = util::get_be16(&data[offset + 8..offset + 10]) as usize;
+    if offset + 10 + data_len > data.len() {
+        return None;
+    }
+
+    // A CNAME target may use compression pointers into the rest of the
+    // message, so expand it while the whole message is available.
+    let record_type = util::get_be16(&data[offset..offset + 2]);
+    let mut record_data = data[offset + 10..offset + 10 + data_len].to_vec();
+    if record_type == TYPE_CNAME {
+        let (target, _) = read_name(data, offset + 10)?;
+        record_data.clear();
+        encode_name(&mut record_data, &target).ok()?;
+    }
+
+    let record = DNSRecord {
+        name,
+        record_type,
+        class: util::get_be16(&data[offset + 2..offset + 4]),
+        ttl: util::get_be32(&data[offset + 4..offset + 8]),
+        data: record_data,
+    };
+
+    Some((record, offset + 10 + data_len))
+}
+
+struct Resolver {
+    socket: Option<udp::SocketReference>,
+    servers: Vec<util::IPAddr>,
+    pending: HashMap<u16, mpsc::Sender<(util::IPAddr, DNSMessage)>>,
+
+    // (name, type) -> (addresses, absolute expire time in ms)
+    cache: HashMap<(String, u16), (Vec<util::IPAddr>, u64)>,
+}
+
+static RESOLVER: LazyLock<Mutex<Resolver>> = LazyLock::new(|| {
+    Mutex::new(Resolver {
+        socket: None,
+        servers: Vec::new(),
+        pending: HashMap::new(),
+        cache: HashMap::new(),
+    })
+});
+
+/// Use these DNS servers instead of ones learned from DHCP or DHCPv6.
+/// Passing an empty list goes back to the learned servers.
+pub fn dns_set_servers(servers: Vec<util::IPAddr>) {
+    RESOLVER.lock().unwrap().servers = servers;
+}
+
+/// Return the servers that queries will be sent to, in order.
+pub fn dns_get_servers() -> Vec<util::IPAddr> {
+    let configured = RESOLVER.lock().unwrap().servers.clone();
+    if !configured.is_empty() {
+        return configured;
+    }
+
+    let mut servers = dhcpv6::dhcpv6_get_dns_servers();
+    if let Some(lease) = dhcp::dhcp_get_lease() {
+        servers.extend(lease.dns_servers);
+    }
+
+    servers
+}
+
+/// Look up all IPv6 and IPv4 addresses for a host name. IPv6 addresses are
+/// returned first. If the name is already a numeric address, it is returned
+/// without doing a query.
+pub fn dns_resolve(name: &str) -> Result<Vec<util::IPAddr>, &'static str> {
+    if let Some(addr) = util::IPAddr::parse(name) {
+        return Ok(vec![addr]);
+    }
+
+    let v6_result = dns_lookup(name, TYPE_AAAA);
+    let v4_result = dns_lookup(name, TYPE_A);
+    match (v6_result, v4_result) {
+        (Err(msg), Err(_)) => Err(msg),
+        (v6_result, v4_result) => {
+            let mut addrs = v6_result.unwrap_or_default();
+            addrs.extend(v4_result.unwrap_or_default());
+            if addrs.is_empty() {
+                Err("Host not found")
+            } else {
+                Ok(addrs)
+            }
+        }
+    }
+}
+
+/// Look up addresses of one type (TYPE_A or TYPE_AAAA) for a name. This
+/// returns an empty list if the name exists but has no records of that type.
+pub fn dns_lookup(name: &str, record_type: u16) -> Result<Vec<util::IPAddr>, &'static str> {
+    let key = (name.to_ascii_lowercase(), record_type);
+    let now = timer::current_time_ms();
+    {
+        let mut guard = RESOLVER.lock().unwrap();
+        match guard.cache.get(&key) {
+            Some((addrs, expire)) if *expire > now => return Ok(addrs.clone()),
+            Some(_) => {
+                guard.cache.remove(&key);
+            }
+            None => {}
+        }
+    }
+
+    let servers = dns_get_servers();
+    if servers.is_empty() {
+        return Err("No DNS servers configured");
+    }
+
+    let mut query = DNSMessage::new(0, FLAG_RECURSION_DESIRED);
+    query.questions.push(DNSQuestion {
+        name: name.to_string(),
+        record_type,
+        class: CLASS_IN,
+    });
+
+    let mut timeout = QUERY_TIMEOUT;
+    for _ in 0..QUERY_ATTEMPTS {
+        for server in &servers {
+            let response = match query_server(*server, &mut query, timeout)? {
+                Some(response) => response,
+                None => continue,
+            };
+
+            let rcode = response.flags & RCODE_MASK;
+            if rcode == RCODE_NAME_ERROR {
+                return Err("Host not found");
+            }
+
+            if rcode != 0 {
+                // Server failure or refused. Try the next one.
+                continue;
+            }
+
+            let (addrs, ttl) = extract_addresses(&response, name, record_type);
+            let ttl = if addrs.is_empty() {
+                NEGATIVE_CACHE_TTL
+            } else {
+                ttl
+            };
+
+            RESOLVER
+                .lock()
+                .unwrap()
+                .cache
+                .insert(key, (addrs.clone(), now + ttl as u64 * 1000));
+            return Ok(addrs);
+        }
+
+        timeout *= 2;
+    }
+
+    Err("DNS query timed out")
+}
+
+// Send a query to one server and wait for the response. Returns None if
+// it timed out.
+fn query_server(
+    server: util::IPAddr,
+    query: &mut DNSMessage,
+    timeout: u64,
+) -> Result<Option<DNSMessage>, &'static str> {
+    // Encode first so nothing needs to be cleaned up if the name is
+    // invalid. The ID is filled in below.
+    let mut encoded = query.encode()?;
+    let (sender, receiver) = mpsc::channel();
+    let mut guard = RESOLVER.lock().unwrap();
+    let mut socket = match &guard.socket {
+        Some(socket) => socket.clone(),
+        None => {
+            let socket = open_socket()?;
+            guard.socket = Some(socket.clone());
+            socket
+        }
+    };
+
+    // Use a random ID to make spoofing responses harder.
+    query.id = loop {
+        let id = rand::random::<u16>();
+        if !guard.pending.contains_key(&id) {
+            break id;
+        }
+    };
+
+    util::set_be16(&mut encoded[0..2], query.id);
+    guard.pending.insert(query.id, sender);
+    drop(guard);
+
+    let result = udp::udp_send(&mut socket, server, DNS_PORT, &encoded);
+    let response = match result {
+        Ok(()) => receiver.recv_timeout(Duration::from_millis(timeout)).ok(),
+        Err(_) => None,
+    };
+
+    RESOLVER.lock().unwrap().pending.remove(&query.id);
+    let response = match response {
+        Some((source_addr, response)) if source_addr == server => response,
+        _ => return Ok(None),
+    };
+
+    if response.questions.first() != query.questions.first() {
+        return Ok(None);
+    }
+
+    if (response.flags & FLAG_TRUNCATED) != 0 {
+        return Ok(tcp_query(server, query, timeout));
+    }
+
+    Ok(Some(response))
+}
+
+fn open_socket() -> Result<udp::SocketReference, &'static str> {
+    let socket = udp::udp_open(0)?;
+    let receive_socket = socket.clone();
+    std::thread::spawn(move || {
+        dns_receive_thread(receive_socket);
+    });
+
+    Ok(socket)
+}
+
+fn dns_receive_thread(mut socket: udp::SocketReference) {
+    let mut data = [0u8; netif::MTU];
+    loop {
+        let mut source_addr = util::IPAddr::new();
+        let mut source_port = 0;
+        let len = udp::udp_recv(&mut socket, &mut data, &mut source_addr, &mut source_port);
+        if len < 0 {
+            if udp::udp_is_closed(&mut socket) {
+                return;
+            }
+
+            continue;
+        }
+
+        if source_port != DNS_PORT {
+            continue;
+        }
+
+        let response = match DNSMessage::parse(&data[..len as usize]) {
+            Some(response) if (response.flags & FLAG_RESPONSE) != 0 => response,
+            _ => continue,
+        };
+
+        let guard = RESOLVER.lock().unwrap();
+        if let Some(sender) = guard.pending.get(&response.id) {
+            let _ = sender.send((source_addr, response));
+        }
+    }
+}
+
+// Over TCP, each message is preceded by a two byte length. The whole
+// exchange, including connecting, must finish within timeout ms.
+fn tcp_query(server: util::IPAddr, query: &DNSMessage, timeout: u64) -> Option<DNSMessage> {
+    let encoded = query.encode().ok()?;
+    let mut request = (encoded.len() as u16).to_be_bytes().to_vec();
+    request.extend_from_slice(&encoded);
+
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+    let remaining_ms = || {
+        deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis() as u32
+    };
+    let mut socket = tcp::tcp_connect_start(server, DNS_PORT, ip::IPMetadata::new()).ok()?;
+    if tcp::tcp_connect_wait(&mut socket, Some(remaining_ms())).is_err() {
+        tcp::tcp_close(&mut socket);
+        return None;
+    }
+
+    tcp::tcp_write(&mut socket, &request);
+
+    let mut response = Vec::new();
+    let mut data = [0u8; 1500];
+    loop {
+        if response.len() >= 2 && response.len() >= 2 + util::get_be16(&response[0..2]) as usize {
+            break;
+        }
+
+        // Returns 0 if the time is up.
+        let got = tcp::tcp_read_timeout(&mut socket, &mut data, Some(remaining_ms()));
+        if got <= 0 {
+            break;
+        }
+
+        response.extend_from_slice(&data[..got as usize]);
+    }
+
+    tcp::tcp_close(&mut socket);
+    if response.len() < 2 {
+        return None;
+    }
+
+    let len = util::get_be16(&response[0..2]) as usize;
+    let response = DNSMessage::parse(response.get(2..2 + len)?)?;
+    if response.id != query.id {
+        return None;
+    }
+
+    Some(response)
+}
+
+// Return the addresses of the requested type for a name, along with the
+// smallest TTL (which is how long the set can be cached). The answers may
+// include a chain of CNAME records leading from the name to the address
+// records. Records that aren't for a name in that chain are ignored, so a
+// server can't slip in addresses for some other name.
+fn extract_addresses(
+    response: &DNSMessage,
+    name: &str,
+    record_type: u16,
+) -> (Vec<util::IPAddr>, u32) {
+    let mut name = name.trim_end_matches('.').to_string();
+    let mut ttl = u32::MAX;
+
+    // Each CNAME can only be used once, which stops loops.
+    for _ in 0..response.answers.len() {
+        let target = response
+            .answers
+            .iter()
+            .filter(|answer| answer.class == CLASS_IN && answer.record_type == TYPE_CNAME)
+            .find(|answer| answer.name.eq_ignore_ascii_case(&name))
+            .and_then(|answer| Some((read_name(&answer.data, 0)?.0, answer.ttl)));
+        match target {
+            Some((target, cname_ttl)) => {
+                name = target;
+                ttl = ttl.min(cname_ttl);
+            }
+
+            None => break,
+        }
+    }
+
+    let mut addrs = Vec::new();
+    for answer in &response.answers {
+        if answer.class != CLASS_IN
+            || answer.record_type != record_type
+            || !answer.name.eq_ignore_ascii_case(&name)
+        {
+            continue;
+        }
+
+        let expected_len = if record_type == TYPE_A { 4 } else { 16 };
+        if answer.data.len() == expected_len {
+            addrs.push(util::IPAddr::new_from(&answer.data));
+            ttl = ttl.min(answer.ttl);
+        }
+    }
+
+    (addrs, ttl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_name() {
+        let mut data = Vec::new();
+        encode_name(&mut data, "www.example.com").unwrap();
+        assert_eq!(data, b"\x03www\x07example\x03com\x00".to_vec());
+
+        // Trailing dot is the same
+        let mut data2 = Vec::new();
+        encode_name(&mut data2, "www.example.com.").unwrap();
+        assert_eq!(data, data2);
+
+        let mut data = Vec::new();
+        encode_name(&mut data, "").unwrap();
+        assert_eq!(data, vec![0]);
+
+        assert!(encode_name(&mut Vec::new(), "a..b").is_err());
+        assert!(encode_name(&mut Vec::new(), &"a".repeat(64)).is_err());
+        let long_name = vec!["a".repeat(60); 5].join(".");
+        assert!(encode_name(&mut Vec::new(), &long_name).is_err());
+    }
+
+    #[test]
+    fn test_read_name_compressed() {
+        // example.com at offset 0, www.<pointer to 0> at 13
+        let data = b"\x07example\x03com\x00\x03www\xc0\x00\xff";
+        assert_eq!(read_name(data, 0), Some(("example.com".to_string(), 13)));
+        assert_eq!(
+            read_name(data, 13),
+            Some(("www.example.com".to_string(), 19))
+        );
+
+        // Pointer loop
+        assert_eq!(read_name(b"\xc0\x00", 0), None);
+
+        // Truncated
+        assert_eq!(read_name(b"\x07exam", 0), None);
+    }
+
+    #[test]
+    fn test_encode_parse_message() {
+        let mut message = DNSMessage::new(0x1234, FLAG_RESPONSE | FLAG_RECURSION_DESIRED);
+        message.questions.push(DNSQuestion {
+            name: "example.com".to_string(),
+            record_type: TYPE_A,
+            class: CLASS_IN,
+        });
+        message.answers.push(DNSRecord {
+            name: "example.com".to_string(),
+            record_type: TYPE_A,
+            class: CLASS_IN,
+            ttl: 300,
+            data: vec![93, 184, 216, 34],
+        });
+        message.additional.push(DNSRecord {
+            name: "example.com".to_string(),
+            record_type: TYPE_AAAA,
+            class: CLASS_IN,
+            ttl: 60,
+            data: vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+        });
+
+        let data = message.encode().unwrap();
+        assert_eq!(
+            &data[0..12],
+            &[0x12, 0x34, 0x81, 0x00, 0, 1, 0, 1, 0, 0, 0, 1]
+        );
+        assert_eq!(DNSMessage::parse(&data), Some(message));
+
+        // Truncated record
+        assert_eq!(DNSMessage::parse(&data[..data.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_extract_addresses() {
+        let mut response = DNSMessage::new(1, FLAG_RESPONSE);
+        response.answers.push(DNSRecord {
+            name: "www.example.com".to_string(),
+            record_type: TYPE_CNAME,
+            class: CLASS_IN,
+            ttl: 100,
+            data: b"\x07example\x03com\x00".to_vec(),
+        });
+
+        for (name, ttl, last) in [
+            ("EXAMPLE.com", 300, 1),
+            ("example.com", 200, 2),
+            ("other.example.com", 10, 3),
+        ] {
+            response.answers.push(DNSRecord {
+                name: name.to_string(),
+                record_type: TYPE_A,
+                class: CLASS_IN,
+                ttl,
+                data: vec![10, 0, 0, last],
+            });
+        }
+
+        // Addresses for names outside the CNAME chain are ignored
+        let expected = vec![
+            util::IPAddr::new_from(&[10, 0, 0, 1]),
+            util::IPAddr::new_from(&[10, 0, 0, 2]),
+        ];
+        let (addrs, ttl) = extract_addresses(&response, "www.example.com.", TYPE_A);
+        assert_eq!(addrs, expected);
+        assert_eq!(ttl, 100);
+
+        let (addrs, ttl) = extract_addresses(&response, "example.com", TYPE_A);
+        assert_eq!(addrs, expected);
+        assert_eq!(ttl, 200);
+
+        let (addrs, _) = extract_addresses(&response, "www.example.com", TYPE_AAAA);
+        assert!(addrs.is_empty());
+
+        let (addrs, _) = extract_addresses(&response, "www.example.org", TYPE_A);
+        assert!(addrs.is_empty());
+
+        // CNAME loop
+        response.answers[1].record_type = TYPE_CNAME;
+        response.answers[1].data = b"\x03www\x07example\x03com\x00".to_vec();
+        let (addrs, _) = extract_addresses(&response, "www.example.com", TYPE_A);
+        assert!(addrs.is_empty());
+    }
+
+    #[test]
+    fn test_parse_cname() {
+        // The target is www.<pointer to the question name>
+        let mut data = b"\x00\x01\x81\x00\x00\x01\x00\x01\x00\x00\x00\x00".to_vec();
+        data.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        data.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x06");
+        data.extend_from_slice(b"\x03www\xc0\x0c");
+        let message = DNSMessage::parse(&data).unwrap();
+        assert_eq!(message.answers[0].name, "example.com");
+        assert_eq!(
+            message.answers[0].data,
+            b"\x03www\x07example\x03com\x00".to_vec()
+        );
+    }
+}
@@ -15,14 +15,23 @@
 //
 
 // Internet Control Message Protocol, as described in RFC 792 and RFC 4443
-
-// XXX This should send errors to the higher layer protocols
-// Right now it only supports pings.
+//
+// Besides answering pings (and passing replies to our own to ping.rs), this
+// passes errors about packets we sent up to
+// the TCP or UDP socket they came from, which is found using the headers
+// quoted in the error message.
 
 use crate::buf;
+use crate::igmp;
 use crate::ip;
+use crate::nd;
 use crate::netif;
+use crate::ping;
+use crate::tcp;
+use crate::timer;
+use crate::udp;
 use crate::util;
+use std::sync::Mutex;
 
 // The header has the same layout for V4 and V6, but the type codes are
 // different.
@@ -34,12 +43,151 @@ use crate::util;
 //  4 |                        Payload...                             |
 //    +---------------------------------------------------------------+
 
-const ICMPV4_ECHO_REQUEST: u8 = 8;
+pub const ICMPV4_ECHO_REQUEST: u8 = 8;
 const ICMPV4_ECHO_REPLY: u8 = 0;
-const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV4_DEST_UNREACHABLE: u8 = 3;
+const ICMPV4_REDIRECT: u8 = 5;
+const ICMPV4_TIME_EXCEEDED: u8 = 11;
+const ICMPV4_PARAMETER_PROBLEM: u8 = 12;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
 const ICMPV6_ECHO_REPLY: u8 = 129;
+const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_PARAMETER_PROBLEM: u8 = 4;
+pub const ICMPV6_MLD_QUERY: u8 = 130;
+pub const ICMPV6_MLDV2_REPORT: u8 = 143;
+
+const ICMPV4_CODE_NET_UNREACHABLE: u8 = 0;
+const ICMPV4_CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+const ICMPV4_CODE_PORT_UNREACHABLE: u8 = 3;
+const ICMPV4_CODE_FRAG_NEEDED: u8 = 4;
+const ICMPV4_CODE_NET_PROHIBITED: u8 = 9;
+const ICMPV4_CODE_HOST_PROHIBITED: u8 = 10;
+const ICMPV4_CODE_ADMIN_PROHIBITED: u8 = 13;
+
+const ICMPV6_CODE_NO_ROUTE: u8 = 0;
+const ICMPV6_CODE_ADMIN_PROHIBITED: u8 = 1;
+const ICMPV6_CODE_PORT_UNREACHABLE: u8 = 4;
+const ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+
+// Errors we send quote as much of the original packet as possible without
+// the error exceeding these sizes (RFC 1812, 4.3.2.3 and RFC 4443, 2.4(c)).
+const MAX_ERROR_LEN_V4: usize = 576;
+const MAX_ERROR_LEN_V6: usize = 1280;
+
+/// Error message reported to sockets when a packet's TTL or hop limit
+/// runs out in transit. Traceroute matches on this to tell routers along
+/// the path apart from the destination.
+pub const ERROR_TIME_EXCEEDED: &str = "Time exceeded";
+
+// Errors are rate limited with a token bucket (RFC 4443, 2.4(f)). This
+// allows short bursts, such as a round of traceroute probes.
+const ERROR_BURST: u64 = 20;
+const ERROR_INTERVAL: u64 = 100; // ms per token
+
+pub const ICMP_HEADER_LEN: usize = 4;
+
+// Error messages contain the IP header and at least the first 8 bytes
+// of the packet that triggered them. We only need to look at the start.
+const MAX_QUOTED_LEN: usize = 128;
+
+/// Fields decoded from the original packet quoted in an ICMP error message.
+/// The transport fields are zero if the quote is too short to include them.
+struct QuotedPacket {
+    source_addr: util::IPAddr,
+    dest_addr: util::IPAddr,
+    protocol: u8,
+    source_port: u16,
+    dest_port: u16,
+    tcp_seq_num: u32,
+    echo_id: Option<u16>,
+    echo_seq: u16,
+}
+
+struct TokenBucket {
+    tokens: u64,
+    last_refill: u64,
+}
+
+static ERROR_RATE_LIMIT: Mutex<TokenBucket> = Mutex::new(TokenBucket::new());
+
+impl TokenBucket {
+    const fn new() -> TokenBucket {
+        TokenBucket {
+            tokens: ERROR_BURST,
+            last_refill: 0,
+        }
+    }
+
+    /// Return true if a message can be sent now.
+    fn take(&mut self, now: u64) -> bool {
+        let refill = now.saturating_sub(self.last_refill) / ERROR_INTERVAL;
+        if refill > 0 {
+            self.tokens = std::cmp::min(ERROR_BURST, self.tokens.saturating_add(refill));
+            self.last_refill += refill * ERROR_INTERVAL;
+        }
+
+        if self.tokens == 0 {
+            return false;
+        }
+
+        self.tokens -= 1;
+        true
+    }
+}
 
-const ICMP_HEADER_LEN: usize = 4;
+/// Describe an ICMP error and whether it is a hard error, which means the
+/// destination will never accept the packet and a connection should be
+/// aborted (RFC 1122, 4.2.3.9). Soft errors, such as a router not
+/// having a route, may be transient. Returns None if this isn't an error
+/// that is passed to sockets.
+fn classify_error(is_v6: bool, packet_type: u8, code: u8) -> Option<(&'static str, bool)> {
+    if is_v6 {
+        match (packet_type, code) {
+            (ICMPV6_DEST_UNREACHABLE, ICMPV6_CODE_NO_ROUTE) => Some(("Network unreachable", false)),
+            (ICMPV6_DEST_UNREACHABLE, ICMPV6_CODE_ADMIN_PROHIBITED) => {
+                Some(("Administratively prohibited", true))
+            }
+            (ICMPV6_DEST_UNREACHABLE, ICMPV6_CODE_PORT_UNREACHABLE) => {
+                Some(("Connection refused", true))
+            }
+            // Address unreachable, and codes that aren't more specific
+            (ICMPV6_DEST_UNREACHABLE, _) => Some(("Host unreachable", false)),
+            (ICMPV6_TIME_EXCEEDED, _) => Some((ERROR_TIME_EXCEEDED, false)),
+            (ICMPV6_PARAMETER_PROBLEM, ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER) => {
+                Some(("Protocol unreachable", true))
+            }
+            (ICMPV6_PARAMETER_PROBLEM, _) => Some(("Parameter problem", false)),
+            _ => None,
+        }
+    } else {
+        match (packet_type, code) {
+            // Fragmentation needed is handled by path MTU discovery.
+            (ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_FRAG_NEEDED) => None,
+            (ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_NET_UNREACHABLE) => {
+                Some(("Network unreachable", false))
+            }
+            (ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_PROTOCOL_UNREACHABLE) => {
+                Some(("Protocol unreachable", true))
+            }
+            (ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_PORT_UNREACHABLE) => {
+                Some(("Connection refused", true))
+            }
+            (
+                ICMPV4_DEST_UNREACHABLE,
+                ICMPV4_CODE_NET_PROHIBITED
+                | ICMPV4_CODE_HOST_PROHIBITED
+                | ICMPV4_CODE_ADMIN_PROHIBITED,
+            ) => Some(("Administratively prohibited", true)),
+            // Host unreachable, and codes that aren't more specific
+            (ICMPV4_DEST_UNREACHABLE, _) => Some(("Host unreachable", false)),
+            (ICMPV4_TIME_EXCEEDED, _) => Some((ERROR_TIME_EXCEEDED, false)),
+            (ICMPV4_PARAMETER_PROBLEM, _) => Some(("Parameter problem", false)),
+            _ => None,
+        }
+    }
+}
 
 pub fn icmp_input_v4(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
     let header = packet.header();
@@ -50,22 +198,69 @@ pub fn icmp_input_v4(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
     }
 
     let packet_type = header[0];
+    let code = header[1];
     packet.trim_head(ICMP_HEADER_LEN);
     if packet_type == ICMPV4_ECHO_REQUEST {
         // Send a response
         let mut response = buf::NetBuffer::new();
         response.append_from_buffer(&packet, usize::MAX);
-        icmp_output_v4(response, ICMPV4_ECHO_REPLY, source_ip);
+        icmp_output_v4(
+            response,
+            ICMPV4_ECHO_REPLY,
+            source_ip,
+            &ip::IPMetadata::new(),
+        );
+    } else if packet_type == ICMPV4_ECHO_REPLY {
+        ping::echo_reply_input(packet, source_ip);
+    } else if packet_type == ICMPV4_DEST_UNREACHABLE && code == ICMPV4_CODE_FRAG_NEEDED {
+        //    +-------------------------------+-------------------------------+
+        //  4 |            Unused             |         Next-Hop MTU          |
+        //    +-------------------------------+-------------------------------+
+        //  8 |      Internet Header + 64 bits of Original Data Datagram      |
+        //    +---------------------------------------------------------------+
+        let mut data = [0u8; MAX_QUOTED_LEN];
+        let len = packet.copy_to_slice(&mut data);
+        if len < 4 {
+            return;
+        }
+
+        let mtu = util::get_be16(&data[2..4]) as usize;
+        handle_packet_too_big(&data[4..len], mtu);
+    } else if packet_type == ICMPV4_REDIRECT {
+        //    +---------------------------------------------------------------+
+        //  4 |                   Gateway Internet Address                    |
+        //    +---------------------------------------------------------------+
+        //  8 |      Internet Header + 64 bits of Original Data Datagram      |
+        //    +---------------------------------------------------------------+
+        let mut data = [0u8; MAX_QUOTED_LEN];
+        let len = packet.copy_to_slice(&mut data);
+        if len < 4 {
+            return;
+        }
+
+        // Network redirects are treated as host redirects, as they are
+        // ambiguous without the subnet mask (RFC 1122, 3.2.2.2)
+        match parse_quoted_packet(&data[4..len]) {
+            Some(quoted) if netif::is_local_addr(quoted.source_addr) => ip::handle_redirect(
+                quoted.dest_addr,
+                util::IPAddr::new_from(&data[0..4]),
+                source_ip,
+            ),
+            _ => println!("ICMP: Malformed redirect"),
+        }
+    } else if let Some((message, is_hard)) = classify_error(false, packet_type, code) {
+        handle_error(packet, source_ip, message, is_hard);
     }
 }
 
-pub fn icmp_input_v6(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
-    let ph_checksum = util::compute_pseudo_header_checksum(
-        source_ip,
-        netif::get_ipaddr().1,
-        packet.len(),
-        ip::PROTO_ICMPV6,
-    );
+pub fn icmp_input_v6(
+    mut packet: buf::NetBuffer,
+    source_ip: util::IPAddr,
+    dest_ip: util::IPAddr,
+    hop_limit: u8,
+) {
+    let ph_checksum =
+        util::compute_pseudo_header_checksum(source_ip, dest_ip, packet.len(), ip::PROTO_ICMPV6);
 
     let header = packet.header();
     let checksum = util::compute_buffer_ones_comp(ph_checksum, &packet) ^ 0xffff;
@@ -75,33 +270,360 @@ pub fn icmp_input_v6(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
     }
 
     let packet_type = header[0];
+    let code = header[1];
     packet.trim_head(ICMP_HEADER_LEN);
     if packet_type == ICMPV6_ECHO_REQUEST {
         // Send a response
         let mut response = buf::NetBuffer::new();
         response.append_from_buffer(&packet, usize::MAX);
-        icmp_output_v6(response, ICMPV6_ECHO_REPLY, source_ip);
+        icmp_output_v6(
+            response,
+            ICMPV6_ECHO_REPLY,
+            source_ip,
+            &ip::IPMetadata::new(),
+        );
+    } else if packet_type == ICMPV6_ECHO_REPLY {
+        ping::echo_reply_input(packet, source_ip);
+    } else if packet_type == ICMPV6_MLD_QUERY {
+        igmp::mld_query_input(packet, source_ip);
+    } else if (nd::ICMPV6_ROUTER_SOLICITATION..=nd::ICMPV6_REDIRECT).contains(&packet_type) {
+        nd::nd_input(packet_type, packet, source_ip, hop_limit);
+    } else if packet_type == ICMPV6_PACKET_TOO_BIG {
+        //    +---------------------------------------------------------------+
+        //  4 |                             MTU                               |
+        //    +---------------------------------------------------------------+
+        //  8 |                  As much of invoking packet...                |
+        //    +---------------------------------------------------------------+
+        let mut data = [0u8; MAX_QUOTED_LEN];
+        let len = packet.copy_to_slice(&mut data);
+        if len < 4 {
+            return;
+        }
+
+        let mtu = util::get_be32(&data[0..4]) as usize;
+        handle_packet_too_big(&data[4..len], mtu);
+    } else if let Some((message, is_hard)) = classify_error(true, packet_type, code) {
+        handle_error(packet, source_ip, message, is_hard);
     }
 }
 
-pub fn icmp_output_v4(mut packet: buf::NetBuffer, packet_type: u8, dest_addr: util::IPAddr) {
+/// Pass an error to the socket that sent the quoted packet. The first four
+/// bytes of the message body are unused or specific to the type, and are
+/// followed by the quoted packet.
+fn handle_error(
+    packet: buf::NetBuffer,
+    reporter: util::IPAddr,
+    message: &'static str,
+    is_hard: bool,
+) {
+    let mut data = [0u8; MAX_QUOTED_LEN];
+    let len = packet.copy_to_slice(&mut data);
+    if len < 4 {
+        return;
+    }
+
+    let quoted = match parse_quoted_packet(&data[4..len]) {
+        Some(quoted) => quoted,
+        None => {
+            println!("ICMP: Malformed error message");
+            return;
+        }
+    };
+
+    if !netif::is_local_addr(quoted.source_addr) {
+        return;
+    }
+
+    if let Some(id) = quoted.echo_id {
+        ping::echo_error_input(id, quoted.echo_seq, reporter, message);
+        return;
+    }
+
+    if quoted.source_port == 0 {
+        return;
+    }
+
+    println!(
+        "ICMP: {} from {} for packet to {}:{}",
+        message, reporter, quoted.dest_addr, quoted.dest_port
+    );
+
+    match quoted.protocol {
+        ip::PROTO_TCP => tcp::tcp_icmp_error(
+            quoted.dest_addr,
+            quoted.dest_port,
+            quoted.source_port,
+            quoted.tcp_seq_num,
+            reporter,
+            message,
+            is_hard,
+        ),
+        ip::PROTO_UDP => udp::udp_icmp_error(
+            quoted.source_addr,
+            quoted.source_port,
+            quoted.dest_addr,
+            quoted.dest_port,
+            reporter,
+            message,
+        ),
+        _ => {}
+    }
+}
+
+fn handle_packet_too_big(quoted: &[u8], mtu: usize) {
+    let quoted = match parse_quoted_packet(quoted) {
+        Some(quoted) => quoted,
+        None => {
+            println!("ICMP: Malformed packet too big message");
+            return;
+        }
+    };
+
+    // Ignore messages about packets that we didn't send.
+    if !netif::is_local_addr(quoted.source_addr) {
+        return;
+    }
+
+    ip::update_path_mtu(quoted.dest_addr, mtu);
+}
+
+/// Decode the IP header of the original packet that is included in the body
+/// of an ICMP error message.
+fn parse_quoted_packet(data: &[u8]) -> Option<QuotedPacket> {
+    if data.is_empty() {
+        return None;
+    }
+
+    // Extension headers in quoted IPv6 packets are not skipped, but we
+    // don't send any with TCP or UDP.
+    let (source_addr, dest_addr, protocol, header_len) = match data[0] >> 4 {
+        4 => {
+            let header_len = ((data[0] & 0xf) as usize) * 4;
+            if header_len < 20 || data.len() < header_len {
+                return None;
+            }
+
+            (
+                util::IPAddr::new_from(&data[12..16]),
+                util::IPAddr::new_from(&data[16..20]),
+                data[9],
+                header_len,
+            )
+        }
+
+        6 => {
+            if data.len() < 40 {
+                return None;
+            }
+
+            (
+                util::IPAddr::new_from(&data[8..24]),
+                util::IPAddr::new_from(&data[24..40]),
+                data[6],
+                40,
+            )
+        }
+
+        _ => return None,
+    };
+
+    // The source and destination ports are at the same offset for TCP and
+    // UDP, and at least 8 bytes past the IP header are always quoted.
+    let transport = &data[header_len..];
+    let (source_port, dest_port, tcp_seq_num) = if transport.len() >= 8 {
+        (
+            util::get_be16(&transport[0..2]),
+            util::get_be16(&transport[2..4]),
+            util::get_be32(&transport[4..8]),
+        )
+    } else {
+        (0, 0, 0)
+    };
+
+    // For an echo request, the identifier and sequence are where the ports
+    // would be for TCP or UDP.
+    let echo_id = match (protocol, transport.first()) {
+        (ip::PROTO_ICMPV4, Some(&ICMPV4_ECHO_REQUEST))
+        | (ip::PROTO_ICMPV6, Some(&ICMPV6_ECHO_REQUEST))
+            if transport.len() >= 8 =>
+        {
+            Some(util::get_be16(&transport[4..6]))
+        }
+        _ => None,
+    };
+
+    Some(QuotedPacket {
+        source_addr,
+        dest_addr,
+        protocol,
+        source_port,
+        dest_port,
+        tcp_seq_num,
+        echo_id,
+        echo_seq: tcp_seq_num as u16,
+    })
+}
+
+/// Tell the sender of a UDP packet that nothing is listening on the
+/// destination port. original is the received packet, including the IP
+/// header.
+pub fn icmp_port_unreachable(
+    original: buf::NetBuffer,
+    source_addr: util::IPAddr,
+    dest_addr: util::IPAddr,
+) {
+    match source_addr {
+        util::IPAddr::V4(_) => send_error(
+            original,
+            ICMPV4_DEST_UNREACHABLE,
+            ICMPV4_CODE_PORT_UNREACHABLE,
+            0,
+            source_addr,
+            dest_addr,
+        ),
+        util::IPAddr::V6(_) => send_error(
+            original,
+            ICMPV6_DEST_UNREACHABLE,
+            ICMPV6_CODE_PORT_UNREACHABLE,
+            0,
+            source_addr,
+            dest_addr,
+        ),
+    }
+}
+
+/// Tell the sender that the protocol of a packet isn't supported. For IPv6
+/// this is a parameter problem, and pointer is the offset of the next header
+/// field with the unrecognized value.
+pub fn icmp_protocol_unreachable(
+    original: buf::NetBuffer,
+    source_addr: util::IPAddr,
+    dest_addr: util::IPAddr,
+    pointer: usize,
+) {
+    match source_addr {
+        util::IPAddr::V4(_) => send_error(
+            original,
+            ICMPV4_DEST_UNREACHABLE,
+            ICMPV4_CODE_PROTOCOL_UNREACHABLE,
+            0,
+            source_addr,
+            dest_addr,
+        ),
+        util::IPAddr::V6(_) => send_error(
+            original,
+            ICMPV6_PARAMETER_PROBLEM,
+            ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER,
+            pointer as u32,
+            source_addr,
+            dest_addr,
+        ),
+    }
+}
+
+//    +---------------+---------------+-------------------------------+
+//  0 |     Type      |     Code      |          Checksum             |
+//    +---------------+---------------+-------------------------------+
+//  4 |              Unused (or pointer for parameter problem)        |
+//    +---------------------------------------------------------------+
+//  8 |                  As much of invoking packet...                |
+//    +---------------------------------------------------------------+
+fn send_error(
+    mut original: buf::NetBuffer,
+    packet_type: u8,
+    code: u8,
+    param: u32,
+    dest_addr: util::IPAddr,
+    local_addr: util::IPAddr,
+) {
+    if !ERROR_RATE_LIMIT
+        .lock()
+        .unwrap()
+        .take(timer::current_time_ms())
+    {
+        println!("ICMP: Rate limiting error to {}", dest_addr);
+        return;
+    }
+
+    let max_error_len = match dest_addr {
+        util::IPAddr::V4(_) => MAX_ERROR_LEN_V4,
+        util::IPAddr::V6(_) => MAX_ERROR_LEN_V6,
+    };
+
+    let max_quoted = max_error_len - ip::header_len(dest_addr) - ICMP_HEADER_LEN - 4;
+    if original.len() > max_quoted {
+        original.trim_tail(original.len() - max_quoted);
+    }
+
+    original.alloc_header(4);
+    util::set_be32(&mut original.header_mut()[0..4], param);
+
+    // Send from the address the original packet was sent to.
+    let metadata = ip::IPMetadata {
+        source_addr: Some(local_addr),
+        ..ip::IPMetadata::new()
+    };
+
+    match dest_addr {
+        util::IPAddr::V4(_) => {
+            icmp_output_v4_code(original, packet_type, code, dest_addr, &metadata)
+        }
+        util::IPAddr::V6(_) => {
+            icmp_output_v6_code(original, packet_type, code, dest_addr, &metadata)
+        }
+    }
+}
+
+pub fn icmp_output_v4(
+    packet: buf::NetBuffer,
+    packet_type: u8,
+    dest_addr: util::IPAddr,
+    metadata: &ip::IPMetadata,
+) {
+    icmp_output_v4_code(packet, packet_type, 0, dest_addr, metadata);
+}
+
+fn icmp_output_v4_code(
+    mut packet: buf::NetBuffer,
+    packet_type: u8,
+    code: u8,
+    dest_addr: util::IPAddr,
+    metadata: &ip::IPMetadata,
+) {
     packet.alloc_header(ICMP_HEADER_LEN);
     let header = packet.header_mut();
     header[0] = packet_type;
+    header[1] = code;
     let checksum = util::compute_buffer_ones_comp(0, &packet) ^ 0xffff;
 
     let header = packet.header_mut();
     util::set_be16(&mut header[2..4], checksum);
-    ip::ip_output(packet, ip::PROTO_ICMPV4, dest_addr);
+    ip::ip_output(packet, ip::PROTO_ICMPV4, dest_addr, metadata);
+}
+
+pub fn icmp_output_v6(
+    packet: buf::NetBuffer,
+    packet_type: u8,
+    dest_addr: util::IPAddr,
+    metadata: &ip::IPMetadata,
+) {
+    icmp_output_v6_code(packet, packet_type, 0, dest_addr, metadata);
 }
 
-pub fn icmp_output_v6(mut packet: buf::NetBuffer, packet_type: u8, dest_addr: util::IPAddr) {
+fn icmp_output_v6_code(
+    mut packet: buf::NetBuffer,
+    packet_type: u8,
+    code: u8,
+    dest_addr: util::IPAddr,
+    metadata: &ip::IPMetadata,
+) {
     packet.alloc_header(ICMP_HEADER_LEN);
     let header = packet.header_mut();
     header[0] = packet_type;
+    header[1] = code;
 
     let ph_checksum = util::compute_pseudo_header_checksum(
-        netif::get_ipaddr().1,
+        ip::get_source_addr(dest_addr, metadata),
         dest_addr,
         packet.len(),
         ip::PROTO_ICMPV6,
@@ -110,5 +632,146 @@ pub fn icmp_output_v6(mut packet: buf::NetBuffer, packet_type: u8, dest_addr: ut
     let checksum = util::compute_buffer_ones_comp(ph_checksum, &packet) ^ 0xffff;
     let header = packet.header_mut();
     util::set_be16(&mut header[2..4], checksum);
-    ip::ip_output(packet, ip::PROTO_ICMPV6, dest_addr);
+    ip::ip_output(packet, ip::PROTO_ICMPV6, dest_addr, metadata);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quoted_packet_v4() {
+        let mut data = [0u8; 28];
+        data[0] = 0x45;
+        data[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        data[16..20].copy_from_slice(&[10, 0, 0, 1]);
+
+        let quoted = parse_quoted_packet(&data).unwrap();
+        assert_eq!(quoted.source_addr, util::IPAddr::new_from(&[10, 0, 0, 2]));
+        assert_eq!(quoted.dest_addr, util::IPAddr::new_from(&[10, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_parse_quoted_packet_v6() {
+        let mut data = [0u8; 48];
+        data[0] = 0x60;
+        data[8] = 0xfe;
+        data[23] = 2;
+        data[24] = 0xfe;
+        data[39] = 1;
+
+        let quoted = parse_quoted_packet(&data).unwrap();
+        assert_eq!(
+            quoted.source_addr,
+            util::IPAddr::new_from(&[0xfe, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2])
+        );
+        assert_eq!(
+            quoted.dest_addr,
+            util::IPAddr::new_from(&[0xfe, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])
+        );
+    }
+
+    #[test]
+    fn test_parse_quoted_packet_ports() {
+        let mut data = [0u8; 28];
+        data[0] = 0x45;
+        data[9] = ip::PROTO_TCP;
+        data[20..22].copy_from_slice(&[0xc0, 0x01]);
+        data[22..24].copy_from_slice(&[0x00, 0x50]);
+        data[24..28].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+
+        let quoted = parse_quoted_packet(&data).unwrap();
+        assert_eq!(quoted.protocol, ip::PROTO_TCP);
+        assert_eq!(quoted.source_port, 0xc001);
+        assert_eq!(quoted.dest_port, 80);
+        assert_eq!(quoted.tcp_seq_num, 0x12345678);
+
+        // Transport header missing
+        let quoted = parse_quoted_packet(&data[..20]).unwrap();
+        assert_eq!(quoted.source_port, 0);
+        assert_eq!(quoted.dest_port, 0);
+    }
+
+    #[test]
+    fn test_parse_quoted_echo_request() {
+        let mut data = [0u8; 48];
+        data[0] = 0x60;
+        data[6] = ip::PROTO_ICMPV6;
+        data[40] = ICMPV6_ECHO_REQUEST;
+        data[44..46].copy_from_slice(&[0xab, 0xcd]);
+        data[46..48].copy_from_slice(&[0x00, 0x07]);
+
+        let quoted = parse_quoted_packet(&data).unwrap();
+        assert_eq!(quoted.echo_id, Some(0xabcd));
+        assert_eq!(quoted.echo_seq, 7);
+
+        // Not an echo request
+        data[40] = ICMPV6_ECHO_REPLY;
+        assert_eq!(parse_quoted_packet(&data).unwrap().echo_id, None);
+    }
+
+    #[test]
+    fn test_classify_error() {
+        assert_eq!(
+            classify_error(false, ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_PORT_UNREACHABLE),
+            Some(("Connection refused", true))
+        );
+        assert_eq!(
+            classify_error(false, ICMPV4_DEST_UNREACHABLE, 1),
+            Some(("Host unreachable", false))
+        );
+        assert_eq!(
+            classify_error(false, ICMPV4_DEST_UNREACHABLE, ICMPV4_CODE_FRAG_NEEDED),
+            None
+        );
+        assert_eq!(
+            classify_error(false, ICMPV4_TIME_EXCEEDED, 0),
+            Some((ERROR_TIME_EXCEEDED, false))
+        );
+        assert_eq!(classify_error(false, ICMPV4_ECHO_REPLY, 0), None);
+        assert_eq!(
+            classify_error(true, ICMPV6_DEST_UNREACHABLE, 3),
+            Some(("Host unreachable", false))
+        );
+        assert_eq!(
+            classify_error(
+                true,
+                ICMPV6_PARAMETER_PROBLEM,
+                ICMPV6_CODE_UNRECOGNIZED_NEXT_HEADER
+            ),
+            Some(("Protocol unreachable", true))
+        );
+        assert_eq!(classify_error(true, ICMPV6_PACKET_TOO_BIG, 0), None);
+        assert_eq!(classify_error(true, ICMPV6_ECHO_REQUEST, 0), None);
+    }
+
+    #[test]
+    fn test_error_rate_limit() {
+        let mut bucket = TokenBucket::new();
+        let start = 1_000_000;
+        for _ in 0..ERROR_BURST {
+            assert!(bucket.take(start));
+        }
+
+        assert!(!bucket.take(start));
+        assert!(!bucket.take(start + ERROR_INTERVAL - 1));
+        assert!(bucket.take(start + ERROR_INTERVAL));
+        assert!(!bucket.take(start + ERROR_INTERVAL));
+
+        // Tokens don't accumulate past the burst size
+        let later = start + ERROR_INTERVAL * ERROR_BURST * 10;
+        for _ in 0..ERROR_BURST {
+            assert!(bucket.take(later));
+        }
+
+        assert!(!bucket.take(later));
+    }
+
+    #[test]
+    fn test_parse_quoted_packet_truncated() {
+        assert!(parse_quoted_packet(&[]).is_none());
+        assert!(parse_quoted_packet(&[0x45; 12]).is_none());
+        assert!(parse_quoted_packet(&[0x60; 20]).is_none());
+        assert!(parse_quoted_packet(&[0x10; 40]).is_none());
+    }
 }
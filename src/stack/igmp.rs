@@ -0,0 +1,560 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Multicast group membership.
+// IPv4 uses the Internet Group Management Protocol, version 3 (RFC 3376),
+// falling back to version 2 (RFC 2236) when there is an older querier on the
+// network. IPv6 uses Multicast Listener Discovery version 2 (RFC 3810). MLD
+// messages are carried in ICMPv6, but they share the group table with IGMP,
+// so they are handled here.
+//
+// This only implements the host side of these protocols: announcing groups
+// when they are joined or left, and answering queries from multicast routers.
+// Source filtering is not supported. Groups are always joined in EXCLUDE mode
+// with an empty source list (that is, traffic from any source is accepted).
+
+use crate::buf;
+use crate::icmp;
+use crate::ip;
+use crate::timer;
+use crate::util;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+const IGMP_MEMBERSHIP_QUERY: u8 = 0x11;
+const IGMPV2_MEMBERSHIP_REPORT: u8 = 0x16;
+const IGMPV2_LEAVE_GROUP: u8 = 0x17;
+const IGMPV3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+// Group record types, used by both IGMPv3 and MLDv2.
+const MODE_IS_EXCLUDE: u8 = 2;
+const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+const CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+
+const IGMP_HEADER_LEN: usize = 8;
+const IGMPV3_QUERY_MIN_LEN: usize = 12;
+const MLD_QUERY_MIN_LEN: usize = 20; // Not including ICMP header
+
+const ROBUSTNESS: u32 = 2; // Number of times unsolicited reports are sent
+const UNSOLICITED_REPORT_INTERVAL: u32 = 1000; // ms
+const OLDER_VERSION_QUERIER_TIMEOUT: u64 = 400000; // ms
+const DEFAULT_MAX_RESPONSE_TIME: u32 = 10000; // ms
+
+const ALL_HOSTS_V4: [u8; 4] = [224, 0, 0, 1];
+const ALL_ROUTERS_V4: [u8; 4] = [224, 0, 0, 2];
+const IGMPV3_ROUTERS_V4: [u8; 4] = [224, 0, 0, 22];
+const ALL_NODES_V6: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+const MLDV2_ROUTERS_V6: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x16];
+
+// Membership messages are only meaningful on the local link.
+const REPORT_METADATA: ip::IPMetadata = ip::IPMetadata {
+    ttl: 1,
+    router_alert: true,
+    ..ip::IPMetadata::new()
+};
+
+struct GroupTable {
+    // Each group has a count of how many times it has been joined, so
+    // multiple users can share the same group.
+    groups: HashMap<util::IPAddr, u32>,
+
+    // If an IGMPv2 query was seen, respond with version 2 messages until
+    // this time.
+    igmpv2_querier_until: u64,
+}
+
+static GROUP_TABLE: LazyLock<Mutex<GroupTable>> = LazyLock::new(|| {
+    Mutex::new(GroupTable {
+        groups: HashMap::new(),
+        igmpv2_querier_until: 0,
+    })
+});
+
+/// Start receiving packets sent to a multicast group.
+pub fn join_group(group: util::IPAddr) -> Result<(), &'static str> {
+    if !group.is_multicast() {
+        return Err("Not a multicast address");
+    }
+
+    if is_implicit_member(group) {
+        return Ok(());
+    }
+
+    let mut guard = GROUP_TABLE.lock().unwrap();
+    let count = guard.groups.entry(group).or_insert(0);
+    *count += 1;
+    if *count > 1 {
+        return Ok(());
+    }
+
+    drop(guard);
+
+    println!("Joined multicast group {}", group);
+    send_state_change(group, CHANGE_TO_EXCLUDE_MODE);
+
+    Ok(())
+}
+
+/// Stop receiving packets sent to a multicast group. This must be called
+/// once for each call to join_group.
+pub fn leave_group(group: util::IPAddr) -> Result<(), &'static str> {
+    if is_implicit_member(group) {
+        return Ok(());
+    }
+
+    let mut guard = GROUP_TABLE.lock().unwrap();
+    let count = match guard.groups.get_mut(&group) {
+        Some(count) => count,
+        None => return Err("Not a member of group"),
+    };
+
+    *count -= 1;
+    if *count > 0 {
+        return Ok(());
+    }
+
+    guard.groups.remove(&group);
+    drop(guard);
+
+    println!("Left multicast group {}", group);
+    send_state_change(group, CHANGE_TO_INCLUDE_MODE);
+
+    Ok(())
+}
+
+/// Returns true if packets sent to this multicast address should be accepted.
+pub fn is_member(group: util::IPAddr) -> bool {
+    is_implicit_member(group) || GROUP_TABLE.lock().unwrap().groups.contains_key(&group)
+}
+
+// All hosts are members of the all-hosts/all-nodes group and never send
+// reports for it.
+fn is_implicit_member(group: util::IPAddr) -> bool {
+    match group {
+        util::IPAddr::V4(addr) => addr == ALL_HOSTS_V4,
+        util::IPAddr::V6(addr) => addr == ALL_NODES_V6,
+    }
+}
+
+fn is_igmpv2_mode() -> bool {
+    timer::current_time_ms() < GROUP_TABLE.lock().unwrap().igmpv2_querier_until
+}
+
+/// Send unsolicited reports when we join or leave a group. These are
+/// repeated in case one gets lost.
+fn send_state_change(group: util::IPAddr, record_type: u8) {
+    send_change_report(group, record_type);
+    for i in 1..ROBUSTNESS {
+        timer::set_timer(UNSOLICITED_REPORT_INTERVAL * i, move || {
+            send_change_report(group, record_type);
+        });
+    }
+}
+
+fn send_change_report(group: util::IPAddr, record_type: u8) {
+    match group {
+        util::IPAddr::V4(_) => {
+            if is_igmpv2_mode() {
+                if record_type == CHANGE_TO_INCLUDE_MODE {
+                    igmp_output(
+                        build_igmpv2_message(IGMPV2_LEAVE_GROUP, group),
+                        util::IPAddr::V4(ALL_ROUTERS_V4),
+                    );
+                } else {
+                    igmp_output(build_igmpv2_message(IGMPV2_MEMBERSHIP_REPORT, group), group);
+                }
+            } else {
+                igmp_output(
+                    build_igmpv3_report(&[(record_type, group)]),
+                    util::IPAddr::V4(IGMPV3_ROUTERS_V4),
+                );
+            }
+        }
+
+        util::IPAddr::V6(_) => {
+            mld_output(build_mldv2_report(&[(record_type, group)]));
+        }
+    }
+}
+
+/// Return all joined groups of the same address family as the passed
+/// address.
+fn get_groups(v4: bool) -> Vec<util::IPAddr> {
+    GROUP_TABLE
+        .lock()
+        .unwrap()
+        .groups
+        .keys()
+        .filter(|group| matches!(group, util::IPAddr::V4(_)) == v4)
+        .copied()
+        .collect()
+}
+
+/// Respond to a query. If the group is None, this is a general query,
+/// and we report all groups that we belong to.
+fn send_current_state_v4(group: Option<util::IPAddr>) {
+    let groups = match group {
+        Some(group) if is_member(group) => vec![group],
+        Some(_) => return,
+        None => get_groups(true),
+    };
+
+    if groups.is_empty() {
+        return;
+    }
+
+    if is_igmpv2_mode() {
+        for group in groups {
+            igmp_output(build_igmpv2_message(IGMPV2_MEMBERSHIP_REPORT, group), group);
+        }
+    } else {
+        let records: Vec<(u8, util::IPAddr)> = groups
+            .iter()
+            .map(|group| (MODE_IS_EXCLUDE, *group))
+            .collect();
+        igmp_output(
+            build_igmpv3_report(&records),
+            util::IPAddr::V4(IGMPV3_ROUTERS_V4),
+        );
+    }
+}
+
+fn send_current_state_v6(group: Option<util::IPAddr>) {
+    let groups = match group {
+        Some(group) if is_member(group) => vec![group],
+        Some(_) => return,
+        None => get_groups(false),
+    };
+
+    if groups.is_empty() {
+        return;
+    }
+
+    let records: Vec<(u8, util::IPAddr)> = groups
+        .iter()
+        .map(|group| (MODE_IS_EXCLUDE, *group))
+        .collect();
+    mld_output(build_mldv2_report(&records));
+}
+
+// Reports in response to a query are delayed by a random amount, up to the
+// maximum specified in the query. This avoids a burst of responses from
+// all hosts on the network at the same time.
+fn schedule_response<F>(max_response_ms: u32, send: F)
+where
+    F: FnOnce() + Send + Sync + 'static,
+{
+    let delay = rand::random::<u32>() % std::cmp::max(max_response_ms, 1);
+    timer::set_timer(delay, send);
+}
+
+//    0               1               2               3
+//    +---------------+---------------+-------------------------------+
+//  0 |  Type = 0x11  | Max Resp Code |           Checksum            |
+//    +---------------+---------------+-------------------------------+
+//  4 |                         Group Address                         |
+//    +-------+-+-----+---------------+-------------------------------+
+//  8 | Resv  |S| QRV |     QQIC      |     Number of Sources (N)     |
+//    +-------+-+-----+---------------+-------------------------------+
+// 12 |                       Source Address [1]                      |
+//    +---------------------------------------------------------------+
+//
+// IGMPv2 queries are only 8 bytes long.
+
+/// Called by IP layer to handle received packets.
+pub fn igmp_input(packet: buf::NetBuffer, source_addr: util::IPAddr) {
+    if packet.len() < IGMP_HEADER_LEN {
+        return;
+    }
+
+    let checksum = util::compute_buffer_ones_comp(0, &packet) ^ 0xffff;
+    if checksum != 0 {
+        println!("IGMP checksum error");
+        return;
+    }
+
+    let header = packet.header();
+    if header[0] != IGMP_MEMBERSHIP_QUERY {
+        // Reports from other hosts can be ignored. (IGMPv2 hosts can suppress
+        // their own reports when they see one from someone else, but that is
+        // optional)
+        return;
+    }
+
+    let group = util::IPAddr::new_from(&header[4..8]);
+    let group = if group == util::IPAddr::new() {
+        None
+    } else {
+        Some(group)
+    };
+
+    let max_response_ms = if packet.len() >= IGMPV3_QUERY_MIN_LEN {
+        decode_igmp_max_resp_code(header[1])
+    } else {
+        println!("IGMP: Version 2 querier {} present", source_addr);
+        GROUP_TABLE.lock().unwrap().igmpv2_querier_until =
+            timer::current_time_ms() + OLDER_VERSION_QUERIER_TIMEOUT;
+
+        // This will be zero for IGMPv1 queries.
+        if header[1] == 0 {
+            DEFAULT_MAX_RESPONSE_TIME
+        } else {
+            header[1] as u32 * 100
+        }
+    };
+
+    schedule_response(max_response_ms, move || send_current_state_v4(group));
+}
+
+//    0               1               2               3
+//    +---------------+---------------+-------------------------------+
+//  0 |  Type = 130   |      Code     |           Checksum            |
+//    +---------------+---------------+-------------------------------+
+//  4 |    Maximum Response Code      |           Reserved            |
+//    +-------------------------------+-------------------------------+
+//  8 |                                                               |
+//    |                       Multicast Address                       |
+//    |                                                               |
+//    |                                                               |
+//    +-------+-+-----+---------------+-------------------------------+
+// 24 | Resv  |S| QRV |     QQIC      |     Number of Sources (N)     |
+//    +-------+-+-----+---------------+-------------------------------+
+
+/// Called by ICMPv6 when a multicast listener query is received. The ICMP
+/// header has already been removed.
+pub fn mld_query_input(packet: buf::NetBuffer, source_addr: util::IPAddr) {
+    // Queries must come from a link local address (RFC 3810, 5.1.14)
+    match source_addr {
+        util::IPAddr::V6(addr) if addr[0] == 0xfe && (addr[1] & 0xc0) == 0x80 => {}
+        _ => return,
+    }
+
+    let mut data = [0u8; MLD_QUERY_MIN_LEN];
+    if packet.copy_to_slice(&mut data) < MLD_QUERY_MIN_LEN {
+        return;
+    }
+
+    let max_response_ms = decode_mld_max_resp_code(util::get_be16(&data[0..2]));
+    let group = util::IPAddr::new_from(&data[4..20]);
+    let group = if group == util::IPAddr::V6([0; 16]) {
+        None
+    } else {
+        Some(group)
+    };
+
+    schedule_response(max_response_ms, move || send_current_state_v6(group));
+}
+
+// Large values in the max response code are encoded in a floating point
+// format (RFC 3376, 4.1.1). The result is in units of 1/10 second.
+fn decode_igmp_max_resp_code(code: u8) -> u32 {
+    let value = if code < 128 {
+        code as u32
+    } else {
+        let exp = (code >> 4) & 7;
+        let mant = code & 0xf;
+        ((mant as u32) | 0x10) << (exp + 3)
+    };
+
+    value * 100
+}
+
+// Same idea as above, but with a 16 bit value in milliseconds
+// (RFC 3810, 5.1.3).
+fn decode_mld_max_resp_code(code: u16) -> u32 {
+    if code < 32768 {
+        code as u32
+    } else {
+        let exp = (code >> 12) & 7;
+        let mant = code & 0xfff;
+        ((mant as u32) | 0x1000) << (exp + 3)
+    }
+}
+
+//    0               1               2               3
+//    +---------------+---------------+-------------------------------+
+//  0 |     Type      | Max Resp Time |           Checksum            |
+//    +---------------+---------------+-------------------------------+
+//  4 |                         Group Address                         |
+//    +---------------------------------------------------------------+
+
+fn build_igmpv2_message(message_type: u8, group: util::IPAddr) -> buf::NetBuffer {
+    let mut message = [0u8; IGMP_HEADER_LEN];
+    message[0] = message_type;
+    group.copy_to(&mut message[4..8]);
+
+    let mut packet = buf::NetBuffer::new();
+    packet.append_from_slice(&message);
+    packet
+}
+
+//    0               1               2               3
+//    +---------------+---------------+-------------------------------+
+//  0 |  Type = 0x22  |    Reserved   |           Checksum            |
+//    +---------------+---------------+-------------------------------+
+//  4 |           Reserved            |  Number of Group Records (M)  |
+//    +-------------------------------+-------------------------------+
+//  8 |                        Group Record [1]                       |
+//    +---------------------------------------------------------------+
+//
+// Each group record (with no sources) is:
+//    +---------------+---------------+-------------------------------+
+//  0 |  Record Type  |  Aux Data Len |     Number of Sources (N)     |
+//    +---------------+---------------+-------------------------------+
+//  4 |                       Multicast Address                       |
+//    +---------------------------------------------------------------+
+
+fn build_igmpv3_report(records: &[(u8, util::IPAddr)]) -> buf::NetBuffer {
+    let mut header = [0u8; 8];
+    header[0] = IGMPV3_MEMBERSHIP_REPORT;
+    util::set_be16(&mut header[6..8], records.len() as u16);
+
+    let mut packet = buf::NetBuffer::new();
+    packet.append_from_slice(&header);
+    for (record_type, group) in records {
+        let mut record = [0u8; 8];
+        record[0] = *record_type;
+        group.copy_to(&mut record[4..8]);
+        packet.append_from_slice(&record);
+    }
+
+    packet
+}
+
+fn igmp_output(mut packet: buf::NetBuffer, dest_addr: util::IPAddr) {
+    let checksum = util::compute_buffer_ones_comp(0, &packet) ^ 0xffff;
+    util::set_be16(&mut packet.header_mut()[2..4], checksum);
+    ip::ip_output(packet, ip::PROTO_IGMP, dest_addr, &REPORT_METADATA);
+}
+
+// The MLDv2 report is the same as the IGMPv3 report, except the addresses
+// are 16 bytes. The ICMP header (type, code, checksum) is added by
+// icmp_output_v6, so this starts with the reserved field.
+
+fn build_mldv2_report(records: &[(u8, util::IPAddr)]) -> buf::NetBuffer {
+    let mut header = [0u8; 4];
+    util::set_be16(&mut header[2..4], records.len() as u16);
+
+    let mut packet = buf::NetBuffer::new();
+    packet.append_from_slice(&header);
+    for (record_type, group) in records {
+        let mut record = [0u8; 20];
+        record[0] = *record_type;
+        group.copy_to(&mut record[4..20]);
+        packet.append_from_slice(&record);
+    }
+
+    packet
+}
+
+fn mld_output(packet: buf::NetBuffer) {
+    icmp::icmp_output_v6(
+        packet,
+        icmp::ICMPV6_MLDV2_REPORT,
+        util::IPAddr::V6(MLDV2_ROUTERS_V6),
+        &REPORT_METADATA,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_igmpv3_report() {
+        let packet = build_igmpv3_report(&[
+            (
+                CHANGE_TO_EXCLUDE_MODE,
+                util::IPAddr::new_from(&[239, 1, 2, 3]),
+            ),
+            (MODE_IS_EXCLUDE, util::IPAddr::new_from(&[224, 0, 0, 251])),
+        ]);
+
+        let mut data = [0u8; 24];
+        assert_eq!(packet.copy_to_slice(&mut data), 24);
+        assert_eq!(
+            data,
+            [
+                0x22, 0, 0, 0, 0, 0, 0, 2, // Header
+                4, 0, 0, 0, 239, 1, 2, 3, // Record 1
+                2, 0, 0, 0, 224, 0, 0, 251, // Record 2
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_igmpv2_message() {
+        let packet =
+            build_igmpv2_message(IGMPV2_LEAVE_GROUP, util::IPAddr::new_from(&[239, 1, 2, 3]));
+        let mut data = [0u8; 8];
+        assert_eq!(packet.copy_to_slice(&mut data), 8);
+        assert_eq!(data, [0x17, 0, 0, 0, 239, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_build_mldv2_report() {
+        let group =
+            util::IPAddr::new_from(&[0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfb]);
+        let packet = build_mldv2_report(&[(CHANGE_TO_INCLUDE_MODE, group)]);
+        assert_eq!(packet.len(), 24);
+
+        let mut data = [0u8; 24];
+        packet.copy_to_slice(&mut data);
+        assert_eq!(&data[0..4], &[0, 0, 0, 1]);
+        assert_eq!(&data[4..8], &[3, 0, 0, 0]);
+        assert_eq!(data[8], 0xff);
+        assert_eq!(data[23], 0xfb);
+    }
+
+    #[test]
+    fn test_decode_igmp_max_resp_code() {
+        assert_eq!(decode_igmp_max_resp_code(100), 10000);
+        assert_eq!(decode_igmp_max_resp_code(1), 100);
+
+        // exp = 0, mant = 0 -> 0x10 << 3
+        assert_eq!(decode_igmp_max_resp_code(0x80), 128 * 100);
+
+        // exp = 7, mant = 0xf -> 0x1f << 10
+        assert_eq!(decode_igmp_max_resp_code(0xff), 31744 * 100);
+    }
+
+    #[test]
+    fn test_decode_mld_max_resp_code() {
+        assert_eq!(decode_mld_max_resp_code(10000), 10000);
+        assert_eq!(decode_mld_max_resp_code(0x8000), 0x1000 << 3);
+        assert_eq!(decode_mld_max_resp_code(0xffff), 0x1fff << 10);
+    }
+
+    #[test]
+    fn test_group_membership() {
+        // Use a group that other tests won't touch.
+        let group = util::IPAddr::new_from(&[239, 99, 99, 99]);
+        assert!(!is_member(group));
+        {
+            let mut guard = GROUP_TABLE.lock().unwrap();
+            guard.groups.insert(group, 1);
+        }
+
+        assert!(is_member(group));
+        GROUP_TABLE.lock().unwrap().groups.remove(&group);
+        assert!(!is_member(group));
+
+        assert!(is_member(util::IPAddr::new_from(&ALL_HOSTS_V4)));
+        assert!(is_member(util::IPAddr::new_from(&ALL_NODES_V6)));
+        assert!(join_group(util::IPAddr::new_from(&[10, 0, 0, 1])).is_err());
+        assert!(leave_group(group).is_err());
+    }
+}
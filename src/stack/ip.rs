@@ -18,13 +18,20 @@
 
 use crate::buf;
 use crate::icmp;
+use crate::igmp;
+use crate::nd;
 use crate::netif;
 use crate::tcp;
+use crate::timer;
 use crate::udp;
 use crate::util;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{LazyLock, Mutex};
 
 pub const PROTO_ICMPV4: u8 = 1;
+pub const PROTO_IGMP: u8 = 2;
+pub const PROTO_HOP_BY_HOP: u8 = 0;
 pub const PROTO_ICMPV6: u8 = 58;
 pub const PROTO_TCP: u8 = 6;
 pub const PROTO_UDP: u8 = 17;
@@ -32,9 +39,48 @@ pub const PROTO_UDP: u8 = 17;
 const IPV4_BASE_HEADER_LEN: usize = 20;
 const IPV6_HEADER_LEN: usize = 40;
 
+// Router alert option (RFC 2113 for IPv4, RFC 2711 for IPv6)
+const IPV4_ROUTER_ALERT: [u8; 4] = [0x94, 0x04, 0x00, 0x00];
+const IPV6_ROUTER_ALERT_LEN: usize = 8; // Including hop-by-hop header
+
 static NEXT_PACKET_ID: AtomicU16 = AtomicU16::new(0);
 const DEFAULT_TTL: u8 = 64;
 
+// Smallest MTUs each version is required to support (RFC 791 and RFC 8200).
+// Path MTU updates below these are ignored.
+const MIN_MTU_V4: usize = 68;
+pub const MIN_MTU_V6: usize = 1280;
+
+// How long a learned path MTU is remembered before we go back to trying
+// the interface MTU (RFC 1191, section 6.3).
+const PMTU_EXPIRE_MS: u64 = 10 * 60 * 1000;
+
+/// Path MTU discovery (RFC 1191, RFC 8201)
+/// When an ICMP "Fragmentation Needed" or "Packet Too Big" message comes
+/// in, the MTU it reports is recorded here, keyed by destination address.
+/// Entries expire so we'll eventually notice if the path changes to allow
+/// larger packets again.
+struct PathMTUCache {
+    entries: HashMap<util::IPAddr, (usize, u64)>, // (mtu, expire time)
+}
+
+static PMTU_CACHE: LazyLock<Mutex<PathMTUCache>> =
+    LazyLock::new(|| Mutex::new(PathMTUCache::new()));
+
+// Redirects don't have a lifetime, so routes learned from them are
+// forgotten after this long, in case the router's view changes.
+const REDIRECT_EXPIRE_MS: u64 = 10 * 60 * 1000;
+
+/// Host routes learned from ICMP redirects (RFC 1122, 3.2.2.2 and RFC 4861,
+/// section 8). A router uses these to tell us about a better first hop for
+/// a specific destination. These override the default router.
+struct RedirectCache {
+    entries: HashMap<util::IPAddr, (util::IPAddr, u64)>, // (next hop, expire time)
+}
+
+static REDIRECT_CACHE: LazyLock<Mutex<RedirectCache>> =
+    LazyLock::new(|| Mutex::new(RedirectCache::new()));
+
 pub fn ip_input(packet: buf::NetBuffer) {
     let header = packet.header();
     let version = header[0] >> 4;
@@ -62,7 +108,7 @@ pub fn ip_input(packet: buf::NetBuffer) {
 // 20 |                    Options                    |    Padding    |
 //    +-----------------------------------------------+---------------+
 
-fn ip_input_v4(mut packet: buf::NetBuffer) {
+fn ip_input_v4(packet: buf::NetBuffer) {
     // A common way to decode packet headers is to cast the raw byte
     // array to a packed structure. This is a bit more challenging in
     // Rust (it's sketchy in any language, but Rust is more of a stickler).
@@ -86,11 +132,15 @@ fn ip_input_v4(mut packet: buf::NetBuffer) {
         return;
     }
 
+    let ttl = header[8];
     let protocol = header[9];
     let source_addr = util::IPAddr::new_from(&header[12..16]);
+    let dest_addr = util::IPAddr::new_from(&header[16..20]);
+    if !is_local_dest(dest_addr) {
+        return;
+    }
 
-    packet.trim_head(header_len);
-    ip_input_common(packet, protocol, source_addr);
+    ip_input_common(packet, header_len, protocol, ttl, source_addr, dest_addr);
 }
 
 //
@@ -111,38 +161,475 @@ fn ip_input_v4(mut packet: buf::NetBuffer) {
 //    |                                                               |
 //    +---------------------------------------------------------------+
 
-fn ip_input_v6(mut packet: buf::NetBuffer) {
+fn ip_input_v6(packet: buf::NetBuffer) {
     let header = packet.header();
-    let protocol = header[6];
+    let mut protocol = header[6];
+    let hop_limit = header[7];
     let source_addr = util::IPAddr::new_from(&header[8..24]);
+    let dest_addr = util::IPAddr::new_from(&header[24..40]);
+    if !is_local_dest(dest_addr) {
+        return;
+    }
+
+    // Multicast listener queries have a hop-by-hop options header with a
+    // router alert. There isn't anything in it hosts need to act on, so skip
+    // it. Other extension headers are not supported.
+    let mut header_len = IPV6_HEADER_LEN;
+    if protocol == PROTO_HOP_BY_HOP {
+        if packet.header().len() < IPV6_HEADER_LEN + 8 {
+            return;
+        }
+
+        let ext_header = &packet.header()[IPV6_HEADER_LEN..];
+        protocol = ext_header[0];
+        header_len += (ext_header[1] as usize + 1) * 8;
+        if header_len > packet.len() {
+            return;
+        }
+    }
+
+    ip_input_common(
+        packet,
+        header_len,
+        protocol,
+        hop_limit,
+        source_addr,
+        dest_addr,
+    );
+}
+
+/// Check if a packet with this destination address should be accepted by
+/// this host.
+fn is_local_dest(dest_addr: util::IPAddr) -> bool {
+    if dest_addr.is_multicast() {
+        return igmp::is_member(dest_addr);
+    }
+
+    match dest_addr {
+        util::IPAddr::V4(addr) => {
+            netif::is_local_addr(dest_addr)
+                || addr == [255, 255, 255, 255]
+                || is_subnet_broadcast(addr)
+                || netif::get_ipv4_addr() == util::IPAddr::new() // Not configured yet
+        }
+
+        util::IPAddr::V6(_) => netif::is_local_addr(dest_addr),
+    }
+}
+
+/// Check if this is the limited broadcast address (255.255.255.255) or the
+/// directed broadcast address for our subnet. IPv6 has no broadcast.
+pub fn is_broadcast(addr: util::IPAddr) -> bool {
+    match addr {
+        util::IPAddr::V4(addr) => addr == [255, 255, 255, 255] || is_subnet_broadcast(addr),
+        util::IPAddr::V6(_) => false,
+    }
+}
+
+// Directed broadcast to our subnet, e.g. 192.168.1.255 for 192.168.1.0/24
+fn is_subnet_broadcast(addr: [u8; 4]) -> bool {
+    let mut local = [0u8; 4];
+    let mut netmask = [0u8; 4];
+    netif::get_ipv4_addr().copy_to(&mut local);
+    netif::get_ipv4_netmask().copy_to(&mut netmask);
+    let host_mask = !u32::from_be_bytes(netmask);
+    host_mask != 0
+        && (u32::from_be_bytes(addr) & !host_mask) == (u32::from_be_bytes(local) & !host_mask)
+        && (u32::from_be_bytes(addr) & host_mask) == host_mask
+}
+
+/// Return the default router for the same address family as the
+/// destination, if one has been learned (from DHCP for IPv4, or router
+/// advertisements for IPv6).
+pub fn get_default_router(dest_addr: util::IPAddr) -> Option<util::IPAddr> {
+    match dest_addr {
+        util::IPAddr::V4(_) => netif::get_ipv4_router(),
+        util::IPAddr::V6(_) => nd::get_default_router(),
+    }
+}
+
+/// Return the first hop for packets to the given destination: a router
+/// learned from a redirect, the destination itself if it's on the local
+/// link, or otherwise the default router. A TUN device has no link layer, so
+/// this doesn't affect how packets are sent, but it is used to validate
+/// redirects.
+pub fn get_next_hop(dest_addr: util::IPAddr) -> Option<util::IPAddr> {
+    if let Some(next_hop) = REDIRECT_CACHE
+        .lock()
+        .unwrap()
+        .lookup(dest_addr, timer::current_time_ms())
+    {
+        return Some(next_hop);
+    }
+
+    if is_on_link(dest_addr) {
+        Some(dest_addr)
+    } else {
+        get_default_router(dest_addr)
+    }
+}
+
+// Our subnet for IPv4, or link-local for IPv6.
+fn is_on_link(addr: util::IPAddr) -> bool {
+    match addr {
+        util::IPAddr::V4(addr) => {
+            let mut local = [0u8; 4];
+            let mut netmask = [0u8; 4];
+            netif::get_ipv4_addr().copy_to(&mut local);
+            netif::get_ipv4_netmask().copy_to(&mut netmask);
+            let mask = u32::from_be_bytes(netmask);
+            mask != 0 && (u32::from_be_bytes(addr) & mask) == (u32::from_be_bytes(local) & mask)
+        }
 
-    packet.trim_head(IPV6_HEADER_LEN);
-    ip_input_common(packet, protocol, source_addr);
+        util::IPAddr::V6(addr) => addr[0] == 0xfe && (addr[1] & 0xc0) == 0x80,
+    }
+}
+
+/// Called when a redirect message is received from reporter, telling us to
+/// send packets for dest_addr to next_hop instead. This is ignored unless it
+/// came from the router we are currently using for that destination
+/// (otherwise any host could divert our traffic), or if we are a router
+/// ourselves.
+pub fn handle_redirect(dest_addr: util::IPAddr, next_hop: util::IPAddr, reporter: util::IPAddr) {
+    if nd::is_router() {
+        return;
+    }
+
+    if get_next_hop(dest_addr) != Some(reporter) {
+        println!(
+            "IP: Ignoring redirect for {} from {}, which is not the first hop",
+            dest_addr, reporter
+        );
+        return;
+    }
+
+    // The new first hop must be on the local link, unless the redirect is
+    // saying the destination itself is (which IPv6 allows).
+    if dest_addr.is_multicast()
+        || netif::is_local_addr(next_hop)
+        || (next_hop != dest_addr && !is_on_link(next_hop))
+    {
+        println!("IP: Ignoring invalid redirect for {}", dest_addr);
+        return;
+    }
+
+    println!("IP: Redirect {} via {}", dest_addr, next_hop);
+    REDIRECT_CACHE
+        .lock()
+        .unwrap()
+        .insert(dest_addr, next_hop, timer::current_time_ms());
+}
+
+/// Return the address that will be put in the source field of the IP
+/// header for a packet sent with this metadata. Upper layer protocols
+/// need this to compute the pseudo header checksum.
+pub fn get_source_addr(dest_addr: util::IPAddr, metadata: &IPMetadata) -> util::IPAddr {
+    metadata
+        .source_addr
+        .unwrap_or_else(|| netif::get_source_addr(dest_addr))
 }
 
-fn ip_input_common(packet: buf::NetBuffer, protocol: u8, source_addr: util::IPAddr) {
+/// Pass a received packet to the upper layer protocol. The packet still
+/// includes the IP header (header_len bytes) at this point, so it can be
+/// quoted if an ICMP error needs to be sent back. ttl is the TTL or hop
+/// limit the packet arrived with.
+fn ip_input_common(
+    mut packet: buf::NetBuffer,
+    header_len: usize,
+    protocol: u8,
+    ttl: u8,
+    source_addr: util::IPAddr,
+    dest_addr: util::IPAddr,
+) {
+    match protocol {
+        PROTO_ICMPV4 | PROTO_ICMPV6 | PROTO_IGMP | PROTO_TCP => {}
+        PROTO_UDP => {
+            // UDP takes the IP header too, and gives the packet back if
+            // nothing is listening on the port. Corrupted packets are
+            // dropped instead, since errors shouldn't be sent for them.
+            if let Some(packet) = udp::udp_input(packet, header_len, source_addr, dest_addr) {
+                if may_send_icmp_error(source_addr, dest_addr) {
+                    icmp::icmp_port_unreachable(packet, source_addr, dest_addr);
+                }
+            }
+
+            return;
+        }
+
+        _ => {
+            println!("IP: Unknown protocol {}", protocol);
+            if may_send_icmp_error(source_addr, dest_addr) {
+                // For IPv6, the error points at the next header field that
+                // had the unknown value, which is in the last header.
+                let pointer = if header_len == IPV6_HEADER_LEN {
+                    6
+                } else {
+                    IPV6_HEADER_LEN
+                };
+
+                icmp::icmp_protocol_unreachable(packet, source_addr, dest_addr, pointer);
+            }
+
+            return;
+        }
+    }
+
+    packet.trim_head(header_len);
     match protocol {
         PROTO_ICMPV4 => icmp::icmp_input_v4(packet, source_addr),
-        PROTO_ICMPV6 => icmp::icmp_input_v6(packet, source_addr),
-        PROTO_TCP => tcp::tcp_input(packet, source_addr),
-        PROTO_UDP => udp::udp_input(packet, source_addr),
-        _ => println!("IP: Unknown protocol {}", protocol),
+        PROTO_ICMPV6 => icmp::icmp_input_v6(packet, source_addr, dest_addr, ttl),
+        PROTO_IGMP => igmp::igmp_input(packet, source_addr),
+        PROTO_TCP => tcp::tcp_input(packet, source_addr, dest_addr),
+        _ => unreachable!(),
     }
 }
 
-pub fn ip_output(packet: buf::NetBuffer, protocol: u8, dest_addr: util::IPAddr) {
+/// Check whether an ICMP error may be sent about a received packet. Errors
+/// are never sent for packets that weren't addressed to one of our unicast
+/// addresses, or that have a source address that doesn't identify a single
+/// host, to avoid broadcast storms (RFC 1122, 3.2.2 and RFC 4443, 2.4).
+fn may_send_icmp_error(source_addr: util::IPAddr, dest_addr: util::IPAddr) -> bool {
+    if !netif::is_local_addr(dest_addr) || source_addr.is_multicast() {
+        return false;
+    }
+
+    match source_addr {
+        util::IPAddr::V4(addr) => {
+            addr != [0, 0, 0, 0] && addr != [255, 255, 255, 255] && !is_subnet_broadcast(addr)
+        }
+
+        util::IPAddr::V6(addr) => addr != [0; 16],
+    }
+}
+
+/// Parameters that upper layer protocols pass down to control how the IP
+/// header is filled in for outgoing packets. Sockets keep one of these so
+/// applications can adjust them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IPMetadata {
+    /// Time to live (IPv4) or hop limit (IPv6)
+    pub ttl: u8,
+
+    /// Differentiated services code point (6 bits)
+    pub dscp: u8,
+
+    /// Explicit congestion notification (2 bits)
+    pub ecn: u8,
+
+    /// IPv6 flow label (20 bits). Ignored for IPv4.
+    pub flow_label: u32,
+
+    /// Include a router alert option in the header, which is used by
+    /// multicast group management protocols.
+    pub router_alert: bool,
+
+    /// Source address to put in the header. If this is None, the interface
+    /// picks one based on the destination.
+    pub source_addr: Option<util::IPAddr>,
+}
+
+impl IPMetadata {
+    pub const fn new() -> Self {
+        IPMetadata {
+            ttl: DEFAULT_TTL,
+            dscp: 0,
+            ecn: 0,
+            flow_label: 0,
+            router_alert: false,
+            source_addr: None,
+        }
+    }
+
+    /// Check that all fields fit in their respective header fields.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.ttl == 0 {
+            return Err("TTL must be non-zero");
+        }
+
+        if self.dscp > 0x3f {
+            return Err("DSCP out of range");
+        }
+
+        if self.ecn > 3 {
+            return Err("ECN out of range");
+        }
+
+        if self.flow_label > 0xfffff {
+            return Err("Flow label out of range");
+        }
+
+        Ok(())
+    }
+
+    // The DSCP and ECN fields share one octet: the TOS field in IPv4
+    // and the traffic class in IPv6 (RFC 2474, RFC 3168)
+    fn traffic_class(&self) -> u8 {
+        (self.dscp << 2) | (self.ecn & 3)
+    }
+}
+
+impl Default for IPMetadata {
+    fn default() -> Self {
+        IPMetadata::new()
+    }
+}
+
+impl PathMTUCache {
+    fn new() -> PathMTUCache {
+        PathMTUCache {
+            entries: HashMap::new(),
+        }
+    }
+
+    fn lookup(&mut self, dest_addr: util::IPAddr, now: u64) -> Option<usize> {
+        let (mtu, expire) = *self.entries.get(&dest_addr)?;
+        if now >= expire {
+            self.entries.remove(&dest_addr);
+            return None;
+        }
+
+        Some(mtu)
+    }
+
+    // Returns true if this lowered the MTU for the destination.
+    fn update(&mut self, dest_addr: util::IPAddr, mtu: usize, now: u64) -> bool {
+        let current = self.lookup(dest_addr, now).unwrap_or(netif::MTU);
+        if mtu >= current {
+            return false;
+        }
+
+        self.entries.insert(dest_addr, (mtu, now + PMTU_EXPIRE_MS));
+        true
+    }
+}
+
+impl RedirectCache {
+    fn new() -> RedirectCache {
+        RedirectCache {
+            entries: HashMap::new(),
+        }
+    }
+
+    fn lookup(&mut self, dest_addr: util::IPAddr, now: u64) -> Option<util::IPAddr> {
+        let (next_hop, expire) = *self.entries.get(&dest_addr)?;
+        if now >= expire {
+            self.entries.remove(&dest_addr);
+            return None;
+        }
+
+        Some(next_hop)
+    }
+
+    fn insert(&mut self, dest_addr: util::IPAddr, next_hop: util::IPAddr, now: u64) {
+        self.entries
+            .insert(dest_addr, (next_hop, now + REDIRECT_EXPIRE_MS));
+    }
+}
+
+/// Return the largest packet (including the IP header) that can be sent to
+/// the given destination without being dropped along the way.
+pub fn get_path_mtu(dest_addr: util::IPAddr) -> usize {
+    PMTU_CACHE
+        .lock()
+        .unwrap()
+        .lookup(dest_addr, timer::current_time_ms())
+        .unwrap_or(netif::MTU)
+}
+
+/// Size of the IP header that will be prepended to packets sent to this
+/// address.
+pub fn header_len(dest_addr: util::IPAddr) -> usize {
+    match dest_addr {
+        util::IPAddr::V4(_) => IPV4_BASE_HEADER_LEN,
+        util::IPAddr::V6(_) => IPV6_HEADER_LEN,
+    }
+}
+
+/// Called by ICMP when a router reports that a packet we sent was too large.
+/// If this reduces the known MTU for the path, notify the transport
+/// protocols so they can shrink their packets.
+pub fn update_path_mtu(dest_addr: util::IPAddr, mtu: usize) {
+    let min_mtu = match dest_addr {
+        util::IPAddr::V4(_) => MIN_MTU_V4,
+        util::IPAddr::V6(_) => MIN_MTU_V6,
+    };
+
+    if mtu < min_mtu {
+        println!("IP: Ignoring invalid path MTU {} for {}", mtu, dest_addr);
+        return;
+    }
+
+    let lowered = PMTU_CACHE
+        .lock()
+        .unwrap()
+        .update(dest_addr, mtu, timer::current_time_ms());
+    if lowered {
+        println!("IP: Path MTU to {} is now {}", dest_addr, mtu);
+        tcp::tcp_path_mtu_changed(dest_addr, mtu);
+    }
+}
+
+pub fn ip_output(
+    mut packet: buf::NetBuffer,
+    protocol: u8,
+    dest_addr: util::IPAddr,
+    metadata: &IPMetadata,
+) {
+    build_header(&mut packet, protocol, dest_addr, metadata);
+    netif::send_packet(packet);
+}
+
+/// Send several packets of the same protocol, each with its own destination
+/// and header parameters. This builds all of the headers, then writes the
+/// packets to the interface together, which is cheaper than calling
+/// ip_output for each one. Returns how many were sent.
+pub fn ip_output_batch(
+    packets: Vec<(buf::NetBuffer, util::IPAddr, IPMetadata)>,
+    protocol: u8,
+) -> usize {
+    let packets: Vec<buf::NetBuffer> = packets
+        .into_iter()
+        .map(|(mut packet, dest_addr, metadata)| {
+            build_header(&mut packet, protocol, dest_addr, &metadata);
+            packet
+        })
+        .collect();
+
+    netif::send_packets(&packets)
+}
+
+fn build_header(
+    packet: &mut buf::NetBuffer,
+    protocol: u8,
+    dest_addr: util::IPAddr,
+    metadata: &IPMetadata,
+) {
+    let source_addr = get_source_addr(dest_addr, metadata);
     match dest_addr {
-        util::IPAddr::V4(_) => ip_output_v4(packet, protocol, dest_addr),
-        util::IPAddr::V6(_) => ip_output_v6(packet, protocol, dest_addr),
+        util::IPAddr::V4(_) => build_header_v4(packet, protocol, source_addr, dest_addr, metadata),
+        util::IPAddr::V6(_) => build_header_v6(packet, protocol, source_addr, dest_addr, metadata),
     }
 }
 
-fn ip_output_v4(mut packet: buf::NetBuffer, protocol: u8, dest_addr: util::IPAddr) {
-    packet.alloc_header(IPV4_BASE_HEADER_LEN);
+fn build_header_v4(
+    packet: &mut buf::NetBuffer,
+    protocol: u8,
+    source_addr: util::IPAddr,
+    dest_addr: util::IPAddr,
+    metadata: &IPMetadata,
+) {
+    let header_len = if metadata.router_alert {
+        IPV4_BASE_HEADER_LEN + IPV4_ROUTER_ALERT.len()
+    } else {
+        IPV4_BASE_HEADER_LEN
+    };
+
+    packet.alloc_header(header_len);
     let packet_length = packet.len() as u16;
     let header = packet.header_mut();
 
-    header[0] = 0x45; // Version/IHL
+    header[0] = 0x40 | (header_len / 4) as u8; // Version/IHL
+    header[1] = metadata.traffic_class(); // Type of service
     util::set_be16(&mut header[2..4], packet_length); // Total Length
 
     util::set_be16(
@@ -150,28 +637,251 @@ fn ip_output_v4(mut packet: buf::NetBuffer, protocol: u8, dest_addr: util::IPAdd
         NEXT_PACKET_ID.fetch_add(1, Ordering::AcqRel),
     );
 
-    header[8] = DEFAULT_TTL; // TTL
+    // We never fragment, so set Don't Fragment. This allows path MTU
+    // discovery to work.
+    util::set_be16(&mut header[6..8], 0x4000); // Flags/Fragment Offset
+
+    header[8] = metadata.ttl; // TTL
     header[9] = protocol; // Protocol
-    netif::get_ipaddr().0.copy_to(&mut header[12..16]); // Source Address
+    source_addr.copy_to(&mut header[12..16]); // Source Address
     dest_addr.copy_to(&mut header[16..20]); // Destination Address
+    if metadata.router_alert {
+        header[20..24].copy_from_slice(&IPV4_ROUTER_ALERT); // Options
+    }
 
-    let checksum = util::compute_checksum(&header[..IPV4_BASE_HEADER_LEN]);
+    let checksum = util::compute_checksum(&header[..header_len]);
     util::set_be16(&mut header[10..12], checksum);
-
-    netif::send_packet(packet);
 }
 
-fn ip_output_v6(mut packet: buf::NetBuffer, protocol: u8, dest_addr: util::IPAddr) {
+fn build_header_v6(
+    packet: &mut buf::NetBuffer,
+    protocol: u8,
+    source_addr: util::IPAddr,
+    dest_addr: util::IPAddr,
+    metadata: &IPMetadata,
+) {
+    let mut next_header = protocol;
+    if metadata.router_alert {
+        //    +---------------+---------------+---------------+---------------+
+        //  0 |  Next Header  | Hdr Ext Len   | Type (5)      | Opt Len (2)   |
+        //    +---------------+---------------+---------------+---------------+
+        //  4 |             Value             |        PadN (1, 0)            |
+        //    +---------------+---------------+---------------+---------------+
+        packet.alloc_header(IPV6_ROUTER_ALERT_LEN);
+        let ext_header = packet.header_mut();
+        ext_header[0] = protocol;
+        ext_header[2] = 5;
+        ext_header[3] = 2;
+        ext_header[6] = 1;
+        next_header = PROTO_HOP_BY_HOP;
+    }
+
     let payload_length = packet.len() as u16;
     packet.alloc_header(IPV6_HEADER_LEN);
 
     let header = packet.header_mut();
-    header[0] = 0x60; // Version/traffic class/flow label
+    let version_class_flow =
+        (6 << 28) | ((metadata.traffic_class() as u32) << 20) | (metadata.flow_label & 0xfffff);
+    util::set_be32(&mut header[0..4], version_class_flow); // Version/traffic class/flow label
     util::set_be16(&mut header[4..6], payload_length); // Payload length
-    header[6] = protocol; // Next header
-    header[7] = DEFAULT_TTL; // Hop limit
-    netif::get_ipaddr().1.copy_to(&mut header[8..24]); // Source address
+    header[6] = next_header; // Next header
+    header[7] = metadata.ttl; // Hop limit
+    source_addr.copy_to(&mut header[8..24]); // Source address
     dest_addr.copy_to(&mut header[24..40]); // Destination address
+}
 
-    netif::send_packet(packet);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_validate() {
+        assert!(IPMetadata::new().validate().is_ok());
+
+        let mut metadata = IPMetadata::new();
+        metadata.dscp = 0x40;
+        assert!(metadata.validate().is_err());
+
+        let mut metadata = IPMetadata::new();
+        metadata.ecn = 4;
+        assert!(metadata.validate().is_err());
+
+        let mut metadata = IPMetadata::new();
+        metadata.flow_label = 0x100000;
+        assert!(metadata.validate().is_err());
+
+        let mut metadata = IPMetadata::new();
+        metadata.ttl = 0;
+        assert!(metadata.validate().is_err());
+    }
+
+    #[test]
+    fn test_build_header_v4() {
+        let metadata = IPMetadata {
+            ttl: 3,
+            dscp: 46, // Expedited forwarding
+            ecn: 1,
+            flow_label: 0,
+            router_alert: false,
+            source_addr: None,
+        };
+
+        let mut packet = buf::NetBuffer::new();
+        packet.append_from_slice(&[0xaa; 8]);
+        build_header_v4(
+            &mut packet,
+            PROTO_UDP,
+            util::IPAddr::new_from(&[10, 0, 0, 2]),
+            util::IPAddr::new_from(&[10, 0, 0, 1]),
+            &metadata,
+        );
+
+        let header = packet.header();
+        assert_eq!(header[0], 0x45);
+        assert_eq!(header[1], 0xb9);
+        assert_eq!(util::get_be16(&header[2..4]), 28);
+        assert_eq!(util::get_be16(&header[6..8]), 0x4000);
+        assert_eq!(header[8], 3);
+        assert_eq!(header[9], PROTO_UDP);
+        assert_eq!(&header[12..16], &[10, 0, 0, 2]);
+        assert_eq!(&header[16..20], &[10, 0, 0, 1]);
+        assert_eq!(util::compute_checksum(&header[..IPV4_BASE_HEADER_LEN]), 0);
+    }
+
+    #[test]
+    fn test_build_header_v6() {
+        let metadata = IPMetadata {
+            ttl: 7,
+            dscp: 10,
+            ecn: 2,
+            flow_label: 0xabcde,
+            router_alert: false,
+            source_addr: None,
+        };
+
+        let mut packet = buf::NetBuffer::new();
+        packet.append_from_slice(&[0xaa; 8]);
+        build_header_v6(
+            &mut packet,
+            PROTO_TCP,
+            util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]),
+            util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]),
+            &metadata,
+        );
+
+        let header = packet.header();
+        assert_eq!(&header[0..4], &[0x62, 0xaa, 0xbc, 0xde]);
+        assert_eq!(util::get_be16(&header[4..6]), 8);
+        assert_eq!(header[6], PROTO_TCP);
+        assert_eq!(header[7], 7);
+        assert_eq!(header[23], 2);
+        assert_eq!(header[39], 1);
+    }
+
+    #[test]
+    fn test_build_header_v4_router_alert() {
+        let metadata = IPMetadata {
+            ttl: 1,
+            router_alert: true,
+            ..IPMetadata::new()
+        };
+
+        let mut packet = buf::NetBuffer::new();
+        packet.append_from_slice(&[0xaa; 8]);
+        build_header_v4(
+            &mut packet,
+            PROTO_IGMP,
+            util::IPAddr::new_from(&[10, 0, 0, 2]),
+            util::IPAddr::new_from(&[224, 0, 0, 22]),
+            &metadata,
+        );
+
+        let header = packet.header();
+        assert_eq!(header[0], 0x46);
+        assert_eq!(util::get_be16(&header[2..4]), 32);
+        assert_eq!(header[8], 1);
+        assert_eq!(&header[20..24], &IPV4_ROUTER_ALERT);
+        assert_eq!(util::compute_checksum(&header[..24]), 0);
+    }
+
+    #[test]
+    fn test_build_header_v6_router_alert() {
+        let metadata = IPMetadata {
+            ttl: 1,
+            router_alert: true,
+            ..IPMetadata::new()
+        };
+
+        let mut packet = buf::NetBuffer::new();
+        packet.append_from_slice(&[0xaa; 8]);
+        build_header_v6(
+            &mut packet,
+            PROTO_ICMPV6,
+            util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]),
+            util::IPAddr::new_from(&[0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x16]),
+            &metadata,
+        );
+
+        let header = packet.header();
+        assert_eq!(util::get_be16(&header[4..6]), 16);
+        assert_eq!(header[6], PROTO_HOP_BY_HOP);
+        assert_eq!(header[7], 1);
+        assert_eq!(&header[40..48], &[PROTO_ICMPV6, 0, 5, 2, 0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_pmtu_cache_update() {
+        let mut cache = PathMTUCache::new();
+        let addr = util::IPAddr::new_from(&[10, 0, 0, 1]);
+        assert_eq!(cache.lookup(addr, 1000), None);
+
+        assert!(cache.update(addr, 1400, 1000));
+        assert_eq!(cache.lookup(addr, 1000), Some(1400));
+
+        // Larger values don't raise the MTU
+        assert!(!cache.update(addr, 1450, 1000));
+        assert!(!cache.update(addr, 1400, 1000));
+        assert_eq!(cache.lookup(addr, 1000), Some(1400));
+
+        assert!(cache.update(addr, 1200, 1000));
+        assert_eq!(cache.lookup(addr, 1000), Some(1200));
+
+        // Other addresses are not affected
+        let other_addr = util::IPAddr::new_from(&[10, 0, 0, 3]);
+        assert_eq!(cache.lookup(other_addr, 1000), None);
+    }
+
+    #[test]
+    fn test_redirect_cache() {
+        let mut cache = RedirectCache::new();
+        let dest = util::IPAddr::new_from(&[192, 168, 1, 1]);
+        let router1 = util::IPAddr::new_from(&[10, 0, 0, 1]);
+        let router2 = util::IPAddr::new_from(&[10, 0, 0, 3]);
+        assert_eq!(cache.lookup(dest, 1000), None);
+
+        cache.insert(dest, router1, 1000);
+        assert_eq!(cache.lookup(dest, 1000), Some(router1));
+
+        // A later redirect replaces the route and restarts the timeout
+        cache.insert(dest, router2, 2000);
+        assert_eq!(
+            cache.lookup(dest, 2000 + REDIRECT_EXPIRE_MS - 1),
+            Some(router2)
+        );
+        assert_eq!(cache.lookup(dest, 2000 + REDIRECT_EXPIRE_MS), None);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn test_pmtu_cache_expire() {
+        let mut cache = PathMTUCache::new();
+        let addr = util::IPAddr::new_from(&[10, 0, 0, 1]);
+        assert!(cache.update(addr, 1400, 1000));
+        assert_eq!(cache.lookup(addr, 1000 + PMTU_EXPIRE_MS - 1), Some(1400));
+        assert_eq!(cache.lookup(addr, 1000 + PMTU_EXPIRE_MS), None);
+        assert!(cache.entries.is_empty());
+
+        // Can be set again after expiring
+        assert!(cache.update(addr, 1450, 2000 + PMTU_EXPIRE_MS));
+    }
 }
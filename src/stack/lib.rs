@@ -15,9 +15,17 @@
 //
 
 pub mod buf;
+pub mod dhcp;
+pub mod dhcp_server;
+pub mod dhcpv6;
+pub mod dns;
 pub mod icmp;
-mod ip;
+pub mod igmp;
+pub mod ip;
+pub mod mdns;
+pub mod nd;
 mod netif;
+pub mod ping;
 pub mod tcp;
 mod timer;
 pub mod udp;
@@ -33,6 +41,7 @@ fn packet_receive_thread() {
 pub fn init_netstack() {
     netif::init();
     timer::init();
+    nd::init();
     std::thread::spawn(|| {
         packet_receive_thread();
     });
@@ -0,0 +1,592 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Multicast DNS responder (RFC 6762) and DNS-based service discovery
+// (RFC 6763).
+//
+// This answers queries for <hostname>.local with our addresses, and for
+// registered services, so tools such as avahi-browse can find servers
+// running on this stack. Records are announced when the responder starts or
+// a service is registered, and a goodbye (TTL 0) is sent when a service is
+// removed.
+//
+// This doesn't probe for name conflicts before claiming a name, and doesn't
+// do known-answer suppression, so it will sometimes send answers the
+// querier already has.
+
+use crate::dns;
+use crate::ip;
+use crate::netif;
+use crate::timer;
+use crate::udp;
+use crate::util;
+use std::sync::Mutex;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP_V4: [u8; 4] = [224, 0, 0, 251];
+const MDNS_GROUP_V6: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfb];
+
+// Set in the class field of records that only we own, telling other hosts
+// to replace whatever they have cached (RFC 6762, 10.2)
+const CLASS_CACHE_FLUSH: u16 = 0x8000;
+const CLASS_ANY: u16 = 255;
+
+// RFC 6762, 10
+const HOST_RECORD_TTL: u32 = 120;
+const OTHER_RECORD_TTL: u32 = 4500;
+
+const ANNOUNCE_INTERVAL: u32 = 1000; // ms
+const ANNOUNCE_COUNT: u32 = 2;
+
+const SERVICE_ENUMERATION_NAME: &str = "_services._dns-sd._udp.local";
+
+struct Service {
+    instance: String,
+    service_type: String, // e.g. "_http._tcp"
+    port: u16,
+    txt: Vec<String>,
+}
+
+impl Service {
+    fn type_name(&self) -> String {
+        format!("{}.local", self.service_type)
+    }
+
+    fn instance_name(&self) -> String {
+        format!("{}.{}.local", self.instance, self.service_type)
+    }
+}
+
+struct Responder {
+    hostname: String,
+    services: Vec<Service>,
+}
+
+struct MDNSState {
+    responder: Responder,
+    socket: udp::SocketReference,
+}
+
+static MDNS: Mutex<Option<MDNSState>> = Mutex::new(None);
+
+/// Start answering multicast DNS queries for hostname.local (the hostname
+/// is passed without the .local suffix).
+pub fn mdns_start(hostname: &str) -> Result<(), &'static str> {
+    let mut guard = MDNS.lock().unwrap();
+    if guard.is_some() {
+        return Err("mDNS responder already running");
+    }
+
+    if hostname.is_empty() || hostname.contains('.') {
+        return Err("Invalid hostname");
+    }
+
+    let mut socket = udp::udp_open(MDNS_PORT)?;
+
+    // Responses must be sent with a TTL of 255 (RFC 6762, 11)
+    let metadata = ip::IPMetadata {
+        ttl: 255,
+        ..ip::IPMetadata::new()
+    };
+    udp::udp_set_ip_metadata(&mut socket, metadata)?;
+    udp::udp_set_multicast_ttl(&mut socket, 255)?;
+
+    // Don't receive our own announcements.
+    udp::udp_set_multicast_loop(&mut socket, false);
+
+    udp::udp_join_group(&mut socket, util::IPAddr::V4(MDNS_GROUP_V4))?;
+    udp::udp_join_group(&mut socket, util::IPAddr::V6(MDNS_GROUP_V6))?;
+
+    *guard = Some(MDNSState {
+        responder: Responder {
+            hostname: format!("{}.local", hostname),
+            services: Vec::new(),
+        },
+        socket: socket.clone(),
+    });
+
+    drop(guard);
+    std::thread::spawn(move || {
+        mdns_receive_thread(socket);
+    });
+
+    announce(None, ANNOUNCE_COUNT);
+
+    Ok(())
+}
+
+/// Advertise a service with DNS-SD. service_type is the service and
+/// protocol, such as "_http._tcp". Each txt entry is normally a key=value
+/// pair.
+pub fn mdns_register_service(
+    instance: &str,
+    service_type: &str,
+    port: u16,
+    txt: Vec<String>,
+) -> Result<(), &'static str> {
+    let mut guard = MDNS.lock().unwrap();
+    let state = guard.as_mut().ok_or("mDNS responder not running")?;
+    if !service_type.ends_with("._tcp") && !service_type.ends_with("._udp") {
+        return Err("Invalid service type");
+    }
+
+    if txt.iter().any(|entry| entry.len() > 255) {
+        return Err("TXT entry too long");
+    }
+
+    let service = Service {
+        instance: instance.to_string(),
+        service_type: service_type.to_string(),
+        port,
+        txt,
+    };
+
+    let instance_name = service.instance_name();
+    if state.responder.services.iter().any(|existing| {
+        existing
+            .instance_name()
+            .eq_ignore_ascii_case(&instance_name)
+    }) {
+        return Err("Service already registered");
+    }
+
+    state.responder.services.push(service);
+    drop(guard);
+    announce(Some(instance_name), ANNOUNCE_COUNT);
+
+    Ok(())
+}
+
+/// Stop advertising a service. A goodbye message is sent so other hosts
+/// remove it from their caches immediately.
+pub fn mdns_unregister_service(instance: &str, service_type: &str) {
+    let mut guard = MDNS.lock().unwrap();
+    let state = match guard.as_mut() {
+        Some(state) => state,
+        None => return,
+    };
+
+    let index =
+        match state.responder.services.iter().position(|service| {
+            service.instance == instance && service.service_type == service_type
+        }) {
+            Some(index) => index,
+            None => return,
+        };
+
+    let service = state.responder.services.remove(index);
+    let mut message = dns::DNSMessage::new(0, dns::FLAG_RESPONSE | dns::FLAG_AUTHORITATIVE);
+    message.answers = service_records(&service, &state.responder.hostname);
+    for record in &mut message.answers {
+        record.ttl = 0;
+    }
+
+    send_multicast(&state.socket, &message);
+}
+
+// Send unsolicited responses with all records, or just those for one
+// service instance.
+fn announce(instance_name: Option<String>, remaining: u32) {
+    let guard = MDNS.lock().unwrap();
+    let state = match guard.as_ref() {
+        Some(state) => state,
+        None => return,
+    };
+
+    let records = match &instance_name {
+        Some(name) => match state
+            .responder
+            .services
+            .iter()
+            .find(|service| service.instance_name() == *name)
+        {
+            Some(service) => service_records(service, &state.responder.hostname),
+            None => return,
+        },
+        None => state.responder.all_records(&get_local_addrs()),
+    };
+
+    let mut message = dns::DNSMessage::new(0, dns::FLAG_RESPONSE | dns::FLAG_AUTHORITATIVE);
+    message.answers = records;
+    send_multicast(&state.socket, &message);
+
+    if remaining > 1 {
+        timer::set_timer(ANNOUNCE_INTERVAL, move || {
+            announce(instance_name, remaining - 1)
+        });
+    }
+}
+
+fn mdns_receive_thread(mut socket: udp::SocketReference) {
+    let mut data = [0u8; netif::MTU];
+    loop {
+        let mut source_addr = util::IPAddr::new();
+        let mut source_port = 0;
+        let len = udp::udp_recv(&mut socket, &mut data, &mut source_addr, &mut source_port);
+        if len < 0 {
+            if udp::udp_is_closed(&mut socket) {
+                return;
+            }
+
+            continue;
+        }
+
+        let query = match dns::DNSMessage::parse(&data[..len as usize]) {
+            Some(query) if (query.flags & dns::FLAG_RESPONSE) == 0 => query,
+            _ => continue,
+        };
+
+        let guard = MDNS.lock().unwrap();
+        let state = match guard.as_ref() {
+            Some(state) => state,
+            None => continue,
+        };
+
+        let addrs = get_local_addrs();
+        let mut response = dns::DNSMessage::new(0, dns::FLAG_RESPONSE | dns::FLAG_AUTHORITATIVE);
+        for question in &query.questions {
+            let (answers, additional) = state.responder.answer(question, &addrs);
+            response.answers.extend(answers);
+            response.additional.extend(additional);
+        }
+
+        if response.answers.is_empty() {
+            continue;
+        }
+
+        let answers = &response.answers;
+        response
+            .additional
+            .retain(|record| !answers.contains(record));
+        if source_port != MDNS_PORT {
+            // A "legacy" resolver that isn't fully mDNS aware. It expects a
+            // conventional unicast response that echoes the query (RFC 6762,
+            // 6.7)
+            response.id = query.id;
+            response.questions = query.questions.clone();
+            if let Ok(data) = response.encode() {
+                let _ = udp::udp_send(&mut socket, source_addr, source_port, &data);
+            }
+        } else {
+            send_multicast_to(&state.socket, &response, source_addr);
+        }
+    }
+}
+
+impl Responder {
+    fn host_records(&self, addrs: &[util::IPAddr]) -> Vec<dns::DNSRecord> {
+        addrs
+            .iter()
+            .map(|addr| {
+                let (record_type, data) = match addr {
+                    util::IPAddr::V4(bytes) => (dns::TYPE_A, bytes.to_vec()),
+                    util::IPAddr::V6(bytes) => (dns::TYPE_AAAA, bytes.to_vec()),
+                };
+
+                dns::DNSRecord {
+                    name: self.hostname.clone(),
+                    record_type,
+                    class: dns::CLASS_IN | CLASS_CACHE_FLUSH,
+                    ttl: HOST_RECORD_TTL,
+                    data,
+                }
+            })
+            .collect()
+    }
+
+    fn all_records(&self, addrs: &[util::IPAddr]) -> Vec<dns::DNSRecord> {
+        let mut records = self.host_records(addrs);
+        for service in &self.services {
+            records.extend(service_records(service, &self.hostname));
+        }
+
+        records
+    }
+
+    /// Return the answers and additional records for one question.
+    fn answer(
+        &self,
+        question: &dns::DNSQuestion,
+        addrs: &[util::IPAddr],
+    ) -> (Vec<dns::DNSRecord>, Vec<dns::DNSRecord>) {
+        // The top bit of the class in a question asks for a unicast
+        // response. Responses are always multicast here, which is allowed.
+        if (question.class & !CLASS_CACHE_FLUSH) != dns::CLASS_IN
+            && (question.class & !CLASS_CACHE_FLUSH) != CLASS_ANY
+        {
+            return (Vec::new(), Vec::new());
+        }
+
+        let all_records = self.all_records(addrs);
+        let answers: Vec<dns::DNSRecord> = all_records
+            .iter()
+            .filter(|record| {
+                record.name.eq_ignore_ascii_case(&question.name)
+                    && (question.record_type == dns::TYPE_ANY
+                        || question.record_type == record.record_type)
+            })
+            .cloned()
+            .collect();
+
+        // Include records the querier is likely to ask for next (RFC 6763,
+        // 12): the SRV and TXT for a PTR answer, and addresses for SRV.
+        let mut additional = Vec::new();
+        for answer in &answers {
+            let target = match answer.record_type {
+                dns::TYPE_PTR => dns::read_name(&answer.data, 0).map(|(name, _)| name),
+                dns::TYPE_SRV => Some(self.hostname.clone()),
+                _ => None,
+            };
+
+            let target = match target {
+                Some(target) => target,
+                None => continue,
+            };
+
+            for record in &all_records {
+                if !record.name.eq_ignore_ascii_case(&target)
+                    || record.record_type == dns::TYPE_PTR
+                    || additional.contains(record)
+                {
+                    continue;
+                }
+
+                additional.push(record.clone());
+                if record.record_type == dns::TYPE_SRV {
+                    for host_record in self.host_records(addrs) {
+                        if !additional.contains(&host_record) {
+                            additional.push(host_record);
+                        }
+                    }
+                }
+            }
+        }
+
+        (answers, additional)
+    }
+}
+
+fn service_records(service: &Service, hostname: &str) -> Vec<dns::DNSRecord> {
+    let type_name = service.type_name();
+    let instance_name = service.instance_name();
+
+    let mut enumeration_data = Vec::new();
+    let mut ptr_data = Vec::new();
+    let mut srv_data = Vec::new();
+    srv_data.extend_from_slice(&[0, 0, 0, 0]); // Priority and weight
+    srv_data.extend_from_slice(&service.port.to_be_bytes());
+    if dns::encode_name(&mut enumeration_data, &type_name).is_err()
+        || dns::encode_name(&mut ptr_data, &instance_name).is_err()
+        || dns::encode_name(&mut srv_data, hostname).is_err()
+    {
+        return Vec::new();
+    }
+
+    // An empty TXT record must still have a single empty string (RFC 6763,
+    // 6.1)
+    let mut txt_data = Vec::new();
+    for entry in &service.txt {
+        txt_data.push(entry.len() as u8);
+        txt_data.extend_from_slice(entry.as_bytes());
+    }
+
+    if txt_data.is_empty() {
+        txt_data.push(0);
+    }
+
+    vec![
+        dns::DNSRecord {
+            name: SERVICE_ENUMERATION_NAME.to_string(),
+            record_type: dns::TYPE_PTR,
+            class: dns::CLASS_IN,
+            ttl: OTHER_RECORD_TTL,
+            data: enumeration_data,
+        },
+        dns::DNSRecord {
+            name: type_name,
+            record_type: dns::TYPE_PTR,
+            class: dns::CLASS_IN,
+            ttl: OTHER_RECORD_TTL,
+            data: ptr_data,
+        },
+        dns::DNSRecord {
+            name: instance_name.clone(),
+            record_type: dns::TYPE_SRV,
+            class: dns::CLASS_IN | CLASS_CACHE_FLUSH,
+            ttl: HOST_RECORD_TTL,
+            data: srv_data,
+        },
+        dns::DNSRecord {
+            name: instance_name,
+            record_type: dns::TYPE_TXT,
+            class: dns::CLASS_IN | CLASS_CACHE_FLUSH,
+            ttl: OTHER_RECORD_TTL,
+            data: txt_data,
+        },
+    ]
+}
+
+fn get_local_addrs() -> Vec<util::IPAddr> {
+    let mut addrs = Vec::new();
+    let ipv4_addr = netif::get_ipv4_addr();
+    if ipv4_addr != util::IPAddr::new() {
+        addrs.push(ipv4_addr);
+    }
+
+    for (addr, _, state) in netif::get_ipv6_addrs() {
+        if state != netif::AddressState::Tentative {
+            addrs.push(addr);
+        }
+    }
+
+    addrs
+}
+
+fn send_multicast(socket: &udp::SocketReference, message: &dns::DNSMessage) {
+    send_multicast_to(socket, message, util::IPAddr::V4(MDNS_GROUP_V4));
+    send_multicast_to(socket, message, util::IPAddr::V6(MDNS_GROUP_V6));
+}
+
+// Send to the multicast group for the same family as the passed address.
+fn send_multicast_to(
+    socket: &udp::SocketReference,
+    message: &dns::DNSMessage,
+    family_addr: util::IPAddr,
+) {
+    let dest_addr = match family_addr {
+        util::IPAddr::V4(_) => util::IPAddr::V4(MDNS_GROUP_V4),
+        util::IPAddr::V6(_) => util::IPAddr::V6(MDNS_GROUP_V6),
+    };
+
+    // The IPv4 group can't be reached if we don't have an address yet.
+    if dest_addr == util::IPAddr::V4(MDNS_GROUP_V4) && netif::get_ipv4_addr() == util::IPAddr::new()
+    {
+        return;
+    }
+
+    match message.encode() {
+        Ok(data) => {
+            let mut socket = socket.clone();
+            if let Err(msg) = udp::udp_send(&mut socket, dest_addr, MDNS_PORT, &data) {
+                println!("mDNS: error sending response: {}", msg);
+            }
+        }
+
+        Err(msg) => println!("mDNS: error encoding response: {}", msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_responder() -> Responder {
+        Responder {
+            hostname: "netstack.local".to_string(),
+            services: vec![Service {
+                instance: "Test Server".to_string(),
+                service_type: "_http._tcp".to_string(),
+                port: 8080,
+                txt: vec!["path=/".to_string()],
+            }],
+        }
+    }
+
+    fn question(name: &str, record_type: u16) -> dns::DNSQuestion {
+        dns::DNSQuestion {
+            name: name.to_string(),
+            record_type,
+            class: dns::CLASS_IN,
+        }
+    }
+
+    #[test]
+    fn test_answer_host() {
+        let responder = test_responder();
+        let addrs = vec![
+            util::IPAddr::new_from(&[10, 0, 0, 2]),
+            util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]),
+        ];
+
+        let (answers, additional) =
+            responder.answer(&question("NetStack.local", dns::TYPE_A), &addrs);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].data, vec![10, 0, 0, 2]);
+        assert_eq!(answers[0].class, dns::CLASS_IN | CLASS_CACHE_FLUSH);
+        assert!(additional.is_empty());
+
+        let (answers, _) = responder.answer(&question("netstack.local", dns::TYPE_ANY), &addrs);
+        assert_eq!(answers.len(), 2);
+
+        let (answers, _) = responder.answer(&question("other.local", dns::TYPE_A), &addrs);
+        assert!(answers.is_empty());
+    }
+
+    #[test]
+    fn test_answer_service() {
+        let responder = test_responder();
+        let addrs = vec![util::IPAddr::new_from(&[10, 0, 0, 2])];
+
+        let (answers, additional) =
+            responder.answer(&question(SERVICE_ENUMERATION_NAME, dns::TYPE_PTR), &addrs);
+        assert_eq!(answers.len(), 1);
+        assert!(additional.is_empty());
+        assert_eq!(
+            dns::read_name(&answers[0].data, 0).unwrap().0,
+            "_http._tcp.local"
+        );
+
+        let (answers, additional) =
+            responder.answer(&question("_http._tcp.local", dns::TYPE_PTR), &addrs);
+        assert_eq!(answers.len(), 1);
+        assert_eq!(
+            dns::read_name(&answers[0].data, 0).unwrap().0,
+            "Test Server._http._tcp.local"
+        );
+
+        // SRV, TXT, and A
+        assert_eq!(additional.len(), 3);
+        let srv = additional
+            .iter()
+            .find(|record| record.record_type == dns::TYPE_SRV)
+            .unwrap();
+        assert_eq!(&srv.data[0..6], &[0, 0, 0, 0, 0x1f, 0x90]);
+        assert_eq!(dns::read_name(&srv.data, 6).unwrap().0, "netstack.local");
+        let txt = additional
+            .iter()
+            .find(|record| record.record_type == dns::TYPE_TXT)
+            .unwrap();
+        assert_eq!(txt.data, b"\x06path=/".to_vec());
+        assert!(additional
+            .iter()
+            .any(|record| record.record_type == dns::TYPE_A));
+    }
+
+    #[test]
+    fn test_empty_txt() {
+        let service = Service {
+            instance: "x".to_string(),
+            service_type: "_echo._udp".to_string(),
+            port: 7,
+            txt: Vec::new(),
+        };
+
+        let records = service_records(&service, "netstack.local");
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[3].record_type, dns::TYPE_TXT);
+        assert_eq!(records[3].data, vec![0]);
+    }
+}
@@ -0,0 +1,1277 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// IPv6 Neighbor Discovery (RFC 4861) and Stateless Address Autoconfiguration
+// (RFC 4862).
+//
+// TUN devices don't have a link layer, so there's no need for address
+// resolution. This handles:
+// - Soliciting routers and processing their advertisements.
+// - Building global addresses from advertised prefixes. The interface
+//   identifier is either derived from the hardware address (modified EUI-64)
+//   or generated from a hash of the prefix (RFC 7217 stable privacy
+//   addresses).
+// - Duplicate address detection (DAD) for new addresses.
+// - Deprecating and removing addresses when their lifetimes expire.
+// - Answering neighbor solicitations for our addresses, which allows other
+//   hosts to perform duplicate address detection.
+// - Redirects from our router to a better first hop for a destination.
+// - Optionally acting as a router (router mode), sending periodic and
+//   solicited router advertisements so the host on the other side of the
+//   TUN device can autoconfigure itself from us.
+//
+// Messages are discarded if the hop limit is not 255, which proves they
+// came from the local link and weren't forwarded by a router.
+
+use crate::buf;
+use crate::dhcpv6;
+use crate::icmp;
+use crate::igmp;
+use crate::ip;
+use crate::netif;
+use crate::timer;
+use crate::util;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{LazyLock, Mutex};
+
+pub const ICMPV6_ROUTER_SOLICITATION: u8 = 133;
+pub const ICMPV6_ROUTER_ADVERTISEMENT: u8 = 134;
+pub const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+pub const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+pub const ICMPV6_REDIRECT: u8 = 137;
+
+const OPT_PREFIX_INFO: u8 = 3;
+const OPT_MTU: u8 = 5;
+const OPT_RDNSS: u8 = 25;
+
+const NA_FLAG_SOLICITED: u8 = 0x40;
+const NA_FLAG_OVERRIDE: u8 = 0x20;
+const RA_FLAG_MANAGED: u8 = 0x80;
+const RA_FLAG_OTHER_CONFIG: u8 = 0x40;
+const PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+const ND_HOP_LIMIT: u8 = 255;
+const MAX_RTR_SOLICITATIONS: u32 = 3;
+const RTR_SOLICITATION_INTERVAL: u32 = 4000; // ms
+const DAD_RETRANS_TIMER: u32 = 1000; // ms
+const IDGEN_RETRIES: u8 = 3;
+const LIFETIME_CHECK_INTERVAL: u32 = 1000; // ms
+const TWO_HOURS: u64 = 2 * 60 * 60 * 1000; // ms
+const INFINITE_LIFETIME: u32 = 0xffffffff;
+
+// Router mode (RFC 4861, section 10)
+const ADV_CUR_HOP_LIMIT: u8 = 64;
+const MAX_INITIAL_RTR_ADVERTISEMENTS: u32 = 3;
+const MAX_INITIAL_RTR_ADVERT_INTERVAL: u32 = 16; // seconds
+const MIN_DELAY_BETWEEN_RAS: u64 = 3000; // ms
+const MAX_RA_DELAY_TIME: u32 = 500; // ms
+
+// Autoconfigured addresses always use a 64 bit interface identifier.
+const SLAAC_PREFIX_LEN: u8 = 64;
+
+const ALL_NODES: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+const ALL_ROUTERS: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+const UNSPECIFIED: util::IPAddr = util::IPAddr::V6([0; 16]);
+
+/// How the lower 64 bits of autoconfigured addresses are chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterfaceIdMode {
+    /// Derived from the hardware address. This is the same for every prefix,
+    /// which makes the host trackable across networks.
+    Eui64,
+
+    /// A hash of the prefix and a secret key (RFC 7217). This is stable for
+    /// a given network, but differs between networks.
+    StablePrivacy,
+}
+
+struct PrefixInfo {
+    prefix: [u8; 16],
+    prefix_len: u8,
+    flags: u8,
+    valid_lifetime: u32,
+    preferred_lifetime: u32,
+}
+
+struct RouterAdvertisement {
+    router_lifetime: u16, // seconds
+    managed: bool,
+    other_config: bool,
+    prefixes: Vec<PrefixInfo>,
+}
+
+struct AutoconfState {
+    id_mode: InterfaceIdMode,
+    secret_key: [u8; 16],
+
+    // How many times DAD has failed for addresses generated from each
+    // prefix. This is mixed into the stable privacy hash so a new address
+    // will be picked.
+    dad_counters: HashMap<[u8; 8], u8>,
+
+    // Addresses that are undergoing DAD, with the prefix information they
+    // were generated from, so another address can be tried if it fails.
+    tentative: HashMap<util::IPAddr, (u32, u32)>, // (preferred, valid lifetime)
+
+    default_router: Option<(util::IPAddr, u64)>, // (address, expire time)
+    router_solicitations: u32,
+    got_advertisement: bool,
+}
+
+/// A prefix to include in router advertisements when in router mode.
+#[derive(Debug, Clone)]
+pub struct AdvertisedPrefix {
+    pub prefix: util::IPAddr,
+    pub prefix_len: u8,
+
+    /// Hosts can reach other addresses with this prefix directly.
+    pub on_link: bool,
+
+    /// Hosts can use this prefix to autoconfigure addresses. This requires a
+    /// prefix length of 64.
+    pub autonomous: bool,
+
+    /// Lifetimes are in seconds. 0xffffffff is infinite.
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+}
+
+/// Parameters for router mode. Intervals and lifetimes are in seconds.
+#[derive(Debug, Clone)]
+pub struct RouterConfig {
+    pub prefixes: Vec<AdvertisedPrefix>,
+
+    /// Link MTU to advertise, or None to leave it out.
+    pub mtu: Option<u32>,
+
+    /// Recursive DNS servers (RFC 8106).
+    pub dns_servers: Vec<util::IPAddr>,
+    pub dns_lifetime: u32,
+
+    /// How long hosts should use us as a default router. Zero means we
+    /// advertise prefixes, but are not a default router.
+    pub router_lifetime: u16,
+
+    /// Unsolicited advertisements are sent at a random time between these.
+    pub min_interval: u32,
+    pub max_interval: u32,
+}
+
+impl Default for RouterConfig {
+    fn default() -> Self {
+        RouterConfig {
+            prefixes: Vec::new(),
+            mtu: None,
+            dns_servers: Vec::new(),
+            dns_lifetime: 1800,
+            router_lifetime: 1800,
+            min_interval: 200,
+            max_interval: 600,
+        }
+    }
+}
+
+impl RouterConfig {
+    // Check limits from RFC 4861, section 6.2.1
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.max_interval < 4 || self.max_interval > 1800 {
+            return Err("Invalid maximum advertisement interval");
+        }
+
+        if self.min_interval < 3 || self.min_interval as u64 * 4 > self.max_interval as u64 * 3 {
+            return Err("Invalid minimum advertisement interval");
+        }
+
+        if self.router_lifetime > 9000
+            || (self.router_lifetime != 0 && (self.router_lifetime as u32) < self.max_interval)
+        {
+            return Err("Invalid router lifetime");
+        }
+
+        if matches!(self.mtu, Some(mtu) if (mtu as usize) < ip::MIN_MTU_V6) {
+            return Err("MTU too small");
+        }
+
+        for prefix in &self.prefixes {
+            if !matches!(prefix.prefix, util::IPAddr::V6(_)) || prefix.prefix_len > 128 {
+                return Err("Invalid prefix");
+            }
+
+            if prefix.preferred_lifetime > prefix.valid_lifetime {
+                return Err("Preferred lifetime longer than valid lifetime");
+            }
+
+            if prefix.autonomous && prefix.prefix_len != SLAAC_PREFIX_LEN {
+                return Err("Autonomous prefixes must be /64");
+            }
+        }
+
+        if self
+            .dns_servers
+            .iter()
+            .any(|addr| !matches!(addr, util::IPAddr::V6(_)))
+        {
+            return Err("DNS servers must be IPv6 addresses");
+        }
+
+        Ok(())
+    }
+}
+
+struct RouterState {
+    config: RouterConfig,
+    timer_id: i32,
+    initial_advertisements: u32,
+    last_advertisement: u64,
+    solicited_pending: bool,
+}
+
+static ROUTER: Mutex<Option<RouterState>> = Mutex::new(None);
+
+static AUTOCONF: LazyLock<Mutex<AutoconfState>> = LazyLock::new(|| {
+    Mutex::new(AutoconfState {
+        id_mode: InterfaceIdMode::StablePrivacy,
+        secret_key: rand::random::<[u8; 16]>(),
+        dad_counters: HashMap::new(),
+        tentative: HashMap::new(),
+        default_router: None,
+        router_solicitations: 0,
+        got_advertisement: false,
+    })
+});
+
+/// Start autoconfiguration. This is called when the network stack is
+/// initialized.
+pub fn init() {
+    let _ = igmp::join_group(solicited_node_addr(netif::get_link_local_addr()));
+    timer::set_timer(LIFETIME_CHECK_INTERVAL, check_lifetimes);
+    send_router_solicitation();
+}
+
+/// Select how interface identifiers for new addresses are generated. This
+/// does not affect addresses that have already been configured.
+pub fn set_interface_id_mode(mode: InterfaceIdMode) {
+    AUTOCONF.lock().unwrap().id_mode = mode;
+}
+
+/// Set the secret used to generate stable privacy addresses. By default,
+/// this is random, which means addresses will change each time the program
+/// is run. Setting it to a fixed value keeps them the same.
+pub fn set_stable_secret(key: [u8; 16]) {
+    AUTOCONF.lock().unwrap().secret_key = key;
+}
+
+/// Return the address of the router that most recently advertised itself
+/// as a default router, if its lifetime hasn't expired.
+pub fn get_default_router() -> Option<util::IPAddr> {
+    let guard = AUTOCONF.lock().unwrap();
+    match guard.default_router {
+        Some((addr, expire)) if timer::current_time_ms() < expire => Some(addr),
+        _ => None,
+    }
+}
+
+/// Return all IPv6 addresses that are currently usable.
+pub fn get_addresses() -> Vec<util::IPAddr> {
+    netif::get_ipv6_addrs()
+        .iter()
+        .filter(|(_, _, state)| *state != netif::AddressState::Tentative)
+        .map(|(addr, _, _)| *addr)
+        .collect()
+}
+
+/// Add an address to the interface. If it is new, it starts out tentative
+/// and becomes usable once duplicate address detection completes. If it
+/// already exists, only the lifetimes are updated. Lifetimes are in
+/// seconds, and 0xffffffff is infinite.
+pub fn configure_address(
+    addr: util::IPAddr,
+    prefix_len: u8,
+    preferred_lifetime: u32,
+    valid_lifetime: u32,
+) {
+    let now = timer::current_time_ms();
+    let preferred_until = lifetime_to_deadline(preferred_lifetime, now);
+    let valid_until = lifetime_to_deadline(valid_lifetime, now);
+    let added = netif::add_ipv6_addr(
+        addr,
+        prefix_len,
+        netif::AddressState::Tentative,
+        preferred_until,
+        valid_until,
+    );
+
+    if added {
+        println!("Starting duplicate address detection for {}", addr);
+        AUTOCONF
+            .lock()
+            .unwrap()
+            .tentative
+            .insert(addr, (preferred_lifetime, valid_lifetime));
+        start_dad(addr);
+    }
+}
+
+/// Start acting as a router on this link. This periodically sends router
+/// advertisements and answers router solicitations. If router mode is
+/// already active, the new configuration replaces the old one.
+pub fn start_router(config: RouterConfig) -> Result<(), &'static str> {
+    config.validate()?;
+
+    let mut guard = ROUTER.lock().unwrap();
+    match guard.take() {
+        Some(state) => {
+            // Already a member of all-routers from the first call
+            timer::cancel_timer(state.timer_id);
+        }
+
+        None => {
+            let _ = igmp::join_group(util::IPAddr::V6(ALL_ROUTERS));
+        }
+    }
+
+    println!("Starting router mode");
+    *guard = Some(RouterState {
+        config,
+        timer_id: timer::set_timer(0, send_periodic_advertisement),
+        initial_advertisements: 0,
+        last_advertisement: 0,
+        solicited_pending: false,
+    });
+
+    Ok(())
+}
+
+/// Stop router mode. A final advertisement with a router lifetime of zero
+/// is sent so hosts stop using us as their default router.
+pub fn stop_router() {
+    let mut guard = ROUTER.lock().unwrap();
+    let state = match guard.take() {
+        Some(state) => state,
+        None => return,
+    };
+
+    drop(guard);
+    timer::cancel_timer(state.timer_id);
+    let _ = igmp::leave_group(util::IPAddr::V6(ALL_ROUTERS));
+    send_router_advertisement(&state.config, 0);
+}
+
+pub fn is_router() -> bool {
+    ROUTER.lock().unwrap().is_some()
+}
+
+fn send_periodic_advertisement() {
+    let mut guard = ROUTER.lock().unwrap();
+    let state = match guard.as_mut() {
+        Some(state) => state,
+        None => return,
+    };
+
+    send_router_advertisement(&state.config, state.config.router_lifetime);
+    state.last_advertisement = timer::current_time_ms();
+
+    // The first few advertisements are sent more quickly, so hosts that
+    // missed the first one configure themselves promptly (RFC 4861 6.2.4)
+    let mut interval = rand::random::<u32>()
+        % (state.config.max_interval - state.config.min_interval + 1)
+        + state.config.min_interval;
+    if state.initial_advertisements < MAX_INITIAL_RTR_ADVERTISEMENTS {
+        state.initial_advertisements += 1;
+        interval = interval.min(MAX_INITIAL_RTR_ADVERT_INTERVAL);
+    }
+
+    state.timer_id = timer::set_timer(interval * 1000, send_periodic_advertisement);
+}
+
+// RFC 4861 6.2.6. Responses are multicast to all nodes, so they are rate
+// limited and delayed randomly to avoid synchronizing with other routers.
+fn handle_router_solicitation() {
+    let mut guard = ROUTER.lock().unwrap();
+    let state = match guard.as_mut() {
+        Some(state) => state,
+        None => return,
+    };
+
+    if state.solicited_pending {
+        return;
+    }
+
+    let now = timer::current_time_ms();
+    let earliest = state.last_advertisement + MIN_DELAY_BETWEEN_RAS;
+    let delay = rand::random::<u32>() % MAX_RA_DELAY_TIME;
+    let delay = delay.max(earliest.saturating_sub(now) as u32);
+    state.solicited_pending = true;
+    timer::set_timer(delay, send_solicited_advertisement);
+}
+
+fn send_solicited_advertisement() {
+    let mut guard = ROUTER.lock().unwrap();
+    if let Some(state) = guard.as_mut() {
+        send_router_advertisement(&state.config, state.config.router_lifetime);
+        state.last_advertisement = timer::current_time_ms();
+        state.solicited_pending = false;
+    }
+}
+
+fn send_router_advertisement(config: &RouterConfig, router_lifetime: u16) {
+    let mut packet = buf::NetBuffer::new();
+    packet.append_from_slice(&build_router_advertisement(config, router_lifetime));
+    nd_output(
+        packet,
+        ICMPV6_ROUTER_ADVERTISEMENT,
+        util::IPAddr::V6(ALL_NODES),
+        Some(netif::get_link_local_addr()),
+    );
+}
+
+fn lifetime_to_deadline(lifetime: u32, now: u64) -> Option<u64> {
+    if lifetime == INFINITE_LIFETIME {
+        None
+    } else {
+        Some(now + lifetime as u64 * 1000)
+    }
+}
+
+fn check_lifetimes() {
+    for addr in netif::expire_ipv6_addrs(timer::current_time_ms()) {
+        println!("Address {} has expired", addr);
+        let _ = igmp::leave_group(solicited_node_addr(addr));
+    }
+
+    timer::set_timer(LIFETIME_CHECK_INTERVAL, check_lifetimes);
+}
+
+fn send_router_solicitation() {
+    if is_router() {
+        return;
+    }
+
+    let mut guard = AUTOCONF.lock().unwrap();
+    if guard.got_advertisement || guard.router_solicitations >= MAX_RTR_SOLICITATIONS {
+        return;
+    }
+
+    guard.router_solicitations += 1;
+    drop(guard);
+
+    //    +---------------------------------------------------------------+
+    //  4 |                            Reserved                           |
+    //    +---------------------------------------------------------------+
+    let mut packet = buf::NetBuffer::new();
+    packet.append_from_slice(&[0; 4]);
+    nd_output(
+        packet,
+        ICMPV6_ROUTER_SOLICITATION,
+        util::IPAddr::V6(ALL_ROUTERS),
+        None,
+    );
+
+    timer::set_timer(RTR_SOLICITATION_INTERVAL, send_router_solicitation);
+}
+
+/// Called by ICMPv6 to handle neighbor discovery messages. The ICMP header
+/// has already been removed. hop_limit is from the IPv6 header.
+pub fn nd_input(packet_type: u8, packet: buf::NetBuffer, source_addr: util::IPAddr, hop_limit: u8) {
+    // RFC 4861, 6.1.1, 6.1.2, 7.1.1, 7.1.2 and 8.1
+    if hop_limit != ND_HOP_LIMIT {
+        println!(
+            "ND: Dropping message with hop limit {} from {}",
+            hop_limit, source_addr
+        );
+        return;
+    }
+
+    let mut data = [0u8; netif::MTU];
+    let len = packet.copy_to_slice(&mut data);
+    let data = &data[..len];
+    match packet_type {
+        ICMPV6_NEIGHBOR_SOLICITATION => handle_neighbor_solicitation(data, source_addr),
+        ICMPV6_NEIGHBOR_ADVERTISEMENT => handle_neighbor_advertisement(data),
+        ICMPV6_ROUTER_SOLICITATION => handle_router_solicitation(),
+        ICMPV6_ROUTER_ADVERTISEMENT => handle_router_advertisement(data, source_addr),
+        ICMPV6_REDIRECT => handle_redirect(data, source_addr),
+        _ => {}
+    }
+}
+
+//    +---------------------------------------------------------------+
+//  4 |                            Reserved                           |
+//    +---------------------------------------------------------------+
+//  8 |                                                               |
+//    |                       Target Address                          |
+//    |                                                               |
+//    |                                                               |
+//    +---------------------------------------------------------------+
+// 24 |                                                               |
+//    |                     Destination Address                       |
+//    |                                                               |
+//    |                                                               |
+//    +---------------------------------------------------------------+
+// 40 |   Options ...
+//    +-----------------------
+
+fn handle_redirect(data: &[u8], source_addr: util::IPAddr) {
+    match parse_redirect(data, source_addr) {
+        Some((target, dest)) => ip::handle_redirect(dest, target, source_addr),
+        None => println!("ND: Invalid redirect from {}", source_addr),
+    }
+}
+
+/// Return the target and destination addresses from a redirect message, if
+/// it passes the checks in RFC 4861, 8.1 that don't depend on our routes.
+fn parse_redirect(data: &[u8], source_addr: util::IPAddr) -> Option<(util::IPAddr, util::IPAddr)> {
+    if data.len() < 36 || !is_link_local(source_addr) {
+        return None;
+    }
+
+    let target = util::IPAddr::new_from(&data[4..20]);
+    let dest = util::IPAddr::new_from(&data[20..36]);
+
+    // The target is either a router on the link, or the destination itself
+    // if it is on the link.
+    if dest.is_multicast() || (!is_link_local(target) && target != dest) {
+        return None;
+    }
+
+    Some((target, dest))
+}
+
+//    +---------------------------------------------------------------+
+//  4 |                            Reserved                           |
+//    +---------------------------------------------------------------+
+//  8 |                                                               |
+//    |                         Target Address                        |
+//    |                                                               |
+//    |                                                               |
+//    +---------------------------------------------------------------+
+// 24 |   Options ...
+//    +-----------------------
+
+fn handle_neighbor_solicitation(data: &[u8], source_addr: util::IPAddr) {
+    if data.len() < 20 {
+        return;
+    }
+
+    let target = util::IPAddr::new_from(&data[4..20]);
+    match netif::get_ipv6_addr_info(target) {
+        // If the source is unspecified, another host is doing DAD for the
+        // same address. Otherwise, tentative addresses are not answered.
+        Some((netif::AddressState::Tentative, _)) if source_addr == UNSPECIFIED => {
+            dad_failed(target)
+        }
+
+        None | Some((netif::AddressState::Tentative, _)) => {}
+
+        // If this was from another host doing DAD, it doesn't have an
+        // address yet, so the reply needs to be multicast.
+        Some(_) if source_addr == UNSPECIFIED => {
+            send_neighbor_advertisement(target, util::IPAddr::V6(ALL_NODES), NA_FLAG_OVERRIDE)
+        }
+
+        Some(_) => {
+            send_neighbor_advertisement(target, source_addr, NA_FLAG_SOLICITED | NA_FLAG_OVERRIDE)
+        }
+    }
+}
+
+//    +-+-+-+---------------------------------------------------------+
+//  4 |R|S|O|                     Reserved                            |
+//    +-+-+-+---------------------------------------------------------+
+//  8 |                                                               |
+//    |                         Target Address                        |
+//    |                                                               |
+//    |                                                               |
+//    +---------------------------------------------------------------+
+// 24 |   Options ...
+//    +-----------------------
+
+fn handle_neighbor_advertisement(data: &[u8]) {
+    if data.len() < 20 {
+        return;
+    }
+
+    let target = util::IPAddr::new_from(&data[4..20]);
+    match netif::get_ipv6_addr_info(target) {
+        None => {}
+        Some((netif::AddressState::Tentative, _)) => dad_failed(target),
+        Some(_) => println!("ND: Another host is using our address {}", target),
+    }
+}
+
+fn send_neighbor_advertisement(target: util::IPAddr, dest_addr: util::IPAddr, flags: u8) {
+    let mut message = [0u8; 20];
+    message[0] = flags;
+    target.copy_to(&mut message[4..20]);
+
+    let mut packet = buf::NetBuffer::new();
+    packet.append_from_slice(&message);
+    nd_output(
+        packet,
+        ICMPV6_NEIGHBOR_ADVERTISEMENT,
+        dest_addr,
+        Some(target),
+    );
+}
+
+fn handle_router_advertisement(data: &[u8], source_addr: util::IPAddr) {
+    // Router advertisements must come from a link local address. Routers
+    // don't configure themselves from other routers' advertisements.
+    if !is_link_local(source_addr) || is_router() {
+        return;
+    }
+
+    let advertisement = match parse_router_advertisement(data) {
+        Some(advertisement) => advertisement,
+        None => {
+            println!("ND: Malformed router advertisement");
+            return;
+        }
+    };
+
+    let now = timer::current_time_ms();
+    let mut guard = AUTOCONF.lock().unwrap();
+    guard.got_advertisement = true;
+    if advertisement.router_lifetime == 0 {
+        if matches!(guard.default_router, Some((addr, _)) if addr == source_addr) {
+            guard.default_router = None;
+        }
+    } else {
+        guard.default_router = Some((
+            source_addr,
+            now + advertisement.router_lifetime as u64 * 1000,
+        ));
+    }
+
+    drop(guard);
+
+    for prefix in &advertisement.prefixes {
+        process_prefix(prefix, now);
+    }
+
+    // The managed flag means addresses are available from DHCPv6. The other
+    // configuration flag means only other information (such as DNS servers)
+    // is.
+    if advertisement.managed {
+        dhcpv6::start_from_advertisement(true);
+    } else if advertisement.other_config {
+        dhcpv6::start_from_advertisement(false);
+    }
+}
+
+// RFC 4862, 5.5.3
+fn process_prefix(info: &PrefixInfo, now: u64) {
+    if (info.flags & PREFIX_FLAG_AUTONOMOUS) == 0
+        || is_link_local(util::IPAddr::V6(info.prefix))
+        || info.preferred_lifetime > info.valid_lifetime
+    {
+        return;
+    }
+
+    if info.prefix_len != SLAAC_PREFIX_LEN {
+        println!(
+            "ND: Can't autoconfigure address with prefix length {}",
+            info.prefix_len
+        );
+        return;
+    }
+
+    let guard = AUTOCONF.lock().unwrap();
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&info.prefix[..8]);
+    let dad_counter = *guard.dad_counters.get(&prefix).unwrap_or(&0);
+    let addr = make_address(
+        &prefix,
+        &generate_interface_id(guard.id_mode, &guard.secret_key, &prefix, dad_counter),
+    );
+    drop(guard);
+
+    let mut valid_lifetime = info.valid_lifetime;
+    if let Some((_, valid_until)) = netif::get_ipv6_addr_info(addr) {
+        // To prevent denial of service by spoofed advertisements with short
+        // lifetimes, an existing address's lifetime can't be reduced below
+        // two hours.
+        let remaining = valid_until.map(|time| time.saturating_sub(now));
+        valid_lifetime = limit_valid_lifetime(remaining, info.valid_lifetime);
+    }
+
+    configure_address(
+        addr,
+        SLAAC_PREFIX_LEN,
+        info.preferred_lifetime,
+        valid_lifetime,
+    );
+}
+
+// Apply the "two hour rule" from RFC 4862 5.5.3 (e). The remaining time is
+// None if the current lifetime is infinite.
+fn limit_valid_lifetime(remaining_ms: Option<u64>, advertised: u32) -> u32 {
+    let remaining_ms = remaining_ms.unwrap_or(u64::MAX);
+    let advertised_ms = advertised as u64 * 1000;
+    if advertised == INFINITE_LIFETIME || advertised_ms > TWO_HOURS || advertised_ms > remaining_ms
+    {
+        advertised
+    } else if remaining_ms <= TWO_HOURS {
+        (remaining_ms / 1000) as u32
+    } else {
+        (TWO_HOURS / 1000) as u32
+    }
+}
+
+fn start_dad(addr: util::IPAddr) {
+    let solicited_node = solicited_node_addr(addr);
+    let _ = igmp::join_group(solicited_node);
+
+    let mut message = [0u8; 20];
+    addr.copy_to(&mut message[4..20]);
+    let mut packet = buf::NetBuffer::new();
+    packet.append_from_slice(&message);
+    nd_output(
+        packet,
+        ICMPV6_NEIGHBOR_SOLICITATION,
+        solicited_node,
+        Some(UNSPECIFIED),
+    );
+
+    timer::set_timer(DAD_RETRANS_TIMER, move || dad_complete(addr));
+}
+
+fn dad_complete(addr: util::IPAddr) {
+    if let Some((netif::AddressState::Tentative, _)) = netif::get_ipv6_addr_info(addr) {
+        println!("Address {} is now preferred", addr);
+        AUTOCONF.lock().unwrap().tentative.remove(&addr);
+        netif::set_ipv6_addr_state(addr, netif::AddressState::Preferred);
+    }
+}
+
+fn dad_failed(addr: util::IPAddr) {
+    println!("ND: Duplicate address detected: {}", addr);
+    netif::remove_ipv6_addr(addr);
+    let _ = igmp::leave_group(solicited_node_addr(addr));
+
+    let mut guard = AUTOCONF.lock().unwrap();
+    let lifetimes = guard.tentative.remove(&addr);
+    if guard.id_mode != InterfaceIdMode::StablePrivacy {
+        return;
+    }
+
+    // With stable privacy addresses, we can pick a different address and try
+    // again (RFC 7217, section 6).
+    let mut prefix = [0u8; 8];
+    if let util::IPAddr::V6(bytes) = addr {
+        prefix.copy_from_slice(&bytes[..8]);
+    }
+
+    let counter = guard.dad_counters.entry(prefix).or_insert(0);
+    *counter += 1;
+    let counter = *counter;
+    if counter > IDGEN_RETRIES {
+        println!("ND: Giving up on autoconfiguration for prefix");
+        return;
+    }
+
+    let new_addr = make_address(
+        &prefix,
+        &generate_interface_id(guard.id_mode, &guard.secret_key, &prefix, counter),
+    );
+    drop(guard);
+
+    if let Some((preferred_lifetime, valid_lifetime)) = lifetimes {
+        configure_address(
+            new_addr,
+            SLAAC_PREFIX_LEN,
+            preferred_lifetime,
+            valid_lifetime,
+        );
+    }
+}
+
+//    +---------------+---------------+-------------------------------+
+//  4 | Cur Hop Limit |M|O|  Reserved |       Router Lifetime         |
+//    +---------------+---------------+-------------------------------+
+//  8 |                         Reachable Time                        |
+//    +---------------------------------------------------------------+
+// 12 |                          Retrans Timer                        |
+//    +---------------------------------------------------------------+
+// 16 |   Options ...
+//    +-----------------------
+//
+// Prefix information option:
+//    +---------------+---------------+---------------+-+-+-----------+
+//  0 |     Type      |    Length     | Prefix Length |L|A| Reserved1 |
+//    +---------------+---------------+---------------+-+-+-----------+
+//  4 |                         Valid Lifetime                        |
+//    +---------------------------------------------------------------+
+//  8 |                       Preferred Lifetime                      |
+//    +---------------------------------------------------------------+
+// 12 |                           Reserved2                           |
+//    +---------------------------------------------------------------+
+// 16 |                                                               |
+//    |                            Prefix                             |
+//    |                                                               |
+//    |                                                               |
+//    +---------------------------------------------------------------+
+
+fn parse_router_advertisement(data: &[u8]) -> Option<RouterAdvertisement> {
+    if data.len() < 12 {
+        return None;
+    }
+
+    let mut advertisement = RouterAdvertisement {
+        router_lifetime: util::get_be16(&data[2..4]),
+        managed: (data[1] & RA_FLAG_MANAGED) != 0,
+        other_config: (data[1] & RA_FLAG_OTHER_CONFIG) != 0,
+        prefixes: Vec::new(),
+    };
+
+    let mut offset = 12;
+    while offset + 2 <= data.len() {
+        let option_type = data[offset];
+        let option_len = data[offset + 1] as usize * 8;
+        if option_len == 0 || offset + option_len > data.len() {
+            return None;
+        }
+
+        let option = &data[offset..offset + option_len];
+        if option_type == OPT_PREFIX_INFO && option_len == 32 {
+            let mut prefix = [0u8; 16];
+            prefix.copy_from_slice(&option[16..32]);
+            advertisement.prefixes.push(PrefixInfo {
+                prefix,
+                prefix_len: option[2],
+                flags: option[3],
+                valid_lifetime: util::get_be32(&option[4..8]),
+                preferred_lifetime: util::get_be32(&option[8..12]),
+            });
+        }
+
+        offset += option_len;
+    }
+
+    Some(advertisement)
+}
+
+//    +---------------+---------------+-------------------------------+
+//  0 |     Type      |    Length     |           Reserved            |
+//    +---------------+---------------+-------------------------------+
+//  4 |                              MTU                              |
+//    +---------------------------------------------------------------+
+//
+// Recursive DNS server option:
+//    +---------------+---------------+-------------------------------+
+//  0 |     Type      |    Length     |           Reserved            |
+//    +---------------+---------------+-------------------------------+
+//  4 |                           Lifetime                            |
+//    +---------------------------------------------------------------+
+//  8 |            Addresses of IPv6 Recursive DNS Servers            |
+//    +---------------------------------------------------------------+
+
+fn build_router_advertisement(config: &RouterConfig, router_lifetime: u16) -> Vec<u8> {
+    let mut message = vec![0u8; 12];
+    message[0] = ADV_CUR_HOP_LIMIT;
+    util::set_be16(&mut message[2..4], router_lifetime);
+
+    for prefix in &config.prefixes {
+        let mut option = [0u8; 32];
+        option[0] = OPT_PREFIX_INFO;
+        option[1] = 4;
+        option[2] = prefix.prefix_len;
+        if prefix.on_link {
+            option[3] |= PREFIX_FLAG_ON_LINK;
+        }
+
+        if prefix.autonomous {
+            option[3] |= PREFIX_FLAG_AUTONOMOUS;
+        }
+
+        util::set_be32(&mut option[4..8], prefix.valid_lifetime);
+        util::set_be32(&mut option[8..12], prefix.preferred_lifetime);
+        prefix.prefix.copy_to(&mut option[16..32]);
+        message.extend_from_slice(&option);
+    }
+
+    if let Some(mtu) = config.mtu {
+        let mut option = [0u8; 8];
+        option[0] = OPT_MTU;
+        option[1] = 1;
+        util::set_be32(&mut option[4..8], mtu);
+        message.extend_from_slice(&option);
+    }
+
+    if !config.dns_servers.is_empty() {
+        let mut option = vec![0u8; 8 + config.dns_servers.len() * 16];
+        option[0] = OPT_RDNSS;
+        option[1] = (1 + config.dns_servers.len() * 2) as u8;
+        util::set_be32(&mut option[4..8], config.dns_lifetime);
+        for (i, server) in config.dns_servers.iter().enumerate() {
+            server.copy_to(&mut option[8 + i * 16..24 + i * 16]);
+        }
+
+        message.extend_from_slice(&option);
+    }
+
+    message
+}
+
+fn nd_output(
+    packet: buf::NetBuffer,
+    packet_type: u8,
+    dest_addr: util::IPAddr,
+    source_addr: Option<util::IPAddr>,
+) {
+    let metadata = ip::IPMetadata {
+        ttl: ND_HOP_LIMIT,
+        source_addr,
+        ..ip::IPMetadata::new()
+    };
+
+    icmp::icmp_output_v6(packet, packet_type, dest_addr, &metadata);
+}
+
+fn is_link_local(addr: util::IPAddr) -> bool {
+    matches!(addr, util::IPAddr::V6(bytes) if bytes[0] == 0xfe && (bytes[1] & 0xc0) == 0x80)
+}
+
+/// Nodes join this multicast group for each of their addresses, so neighbor
+/// solicitations can be sent without bothering every host on the link.
+pub fn solicited_node_addr(addr: util::IPAddr) -> util::IPAddr {
+    let mut group = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0, 0, 0];
+    if let util::IPAddr::V6(bytes) = addr {
+        group[13..16].copy_from_slice(&bytes[13..16]);
+    }
+
+    util::IPAddr::V6(group)
+}
+
+fn make_address(prefix: &[u8; 8], interface_id: &[u8; 8]) -> util::IPAddr {
+    let mut addr = [0u8; 16];
+    addr[..8].copy_from_slice(prefix);
+    addr[8..].copy_from_slice(interface_id);
+    util::IPAddr::V6(addr)
+}
+
+fn generate_interface_id(
+    mode: InterfaceIdMode,
+    secret_key: &[u8; 16],
+    prefix: &[u8; 8],
+    dad_counter: u8,
+) -> [u8; 8] {
+    match mode {
+        InterfaceIdMode::Eui64 => eui64_interface_id(&netif::HW_ADDR),
+        InterfaceIdMode::StablePrivacy => {
+            stable_privacy_interface_id(secret_key, prefix, dad_counter)
+        }
+    }
+}
+
+// Modified EUI-64 (RFC 4291, appendix A): insert ff:fe in the middle of the
+// MAC address and flip the universal/local bit.
+fn eui64_interface_id(hw_addr: &[u8; 6]) -> [u8; 8] {
+    [
+        hw_addr[0] ^ 0x02,
+        hw_addr[1],
+        hw_addr[2],
+        0xff,
+        0xfe,
+        hw_addr[3],
+        hw_addr[4],
+        hw_addr[5],
+    ]
+}
+
+// RFC 7217 calls for a cryptographic hash, but any pseudorandom function
+// works as long as the output can't be predicted without the secret key.
+// The standard library's SipHash is used here to avoid a dependency.
+fn stable_privacy_interface_id(
+    secret_key: &[u8; 16],
+    prefix: &[u8; 8],
+    dad_counter: u8,
+) -> [u8; 8] {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    secret_key.hash(&mut hasher);
+    prefix.hash(&mut hasher);
+    netif::HW_ADDR.hash(&mut hasher);
+    dad_counter.hash(&mut hasher);
+    hasher.finish().to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solicited_node_addr() {
+        let addr = util::IPAddr::new_from(&[
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0,
+        ]);
+        assert_eq!(
+            solicited_node_addr(addr),
+            util::IPAddr::new_from(&[
+                0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0xbc, 0xde, 0xf0
+            ])
+        );
+    }
+
+    #[test]
+    fn test_eui64_interface_id() {
+        assert_eq!(
+            eui64_interface_id(&[0x00, 0x1b, 0x21, 0x3c, 0x4d, 0x5e]),
+            [0x02, 0x1b, 0x21, 0xff, 0xfe, 0x3c, 0x4d, 0x5e]
+        );
+    }
+
+    #[test]
+    fn test_stable_privacy_interface_id() {
+        let key = [7u8; 16];
+        let prefix1 = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1];
+        let prefix2 = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 2];
+
+        // Same inputs always produce the same address
+        assert_eq!(
+            stable_privacy_interface_id(&key, &prefix1, 0),
+            stable_privacy_interface_id(&key, &prefix1, 0)
+        );
+
+        // But changing any of them produces a different one
+        assert_ne!(
+            stable_privacy_interface_id(&key, &prefix1, 0),
+            stable_privacy_interface_id(&key, &prefix2, 0)
+        );
+        assert_ne!(
+            stable_privacy_interface_id(&key, &prefix1, 0),
+            stable_privacy_interface_id(&key, &prefix1, 1)
+        );
+        assert_ne!(
+            stable_privacy_interface_id(&key, &prefix1, 0),
+            stable_privacy_interface_id(&[8u8; 16], &prefix1, 0)
+        );
+    }
+
+    #[test]
+    fn test_parse_router_advertisement() {
+        let mut data = [0u8; 44];
+        data[0] = 64; // Hop limit
+        util::set_be16(&mut data[2..4], 1800);
+
+        // Prefix information
+        data[12] = OPT_PREFIX_INFO;
+        data[13] = 4;
+        data[14] = 64;
+        data[15] = 0xc0;
+        util::set_be32(&mut data[16..20], 86400);
+        util::set_be32(&mut data[20..24], 14400);
+        data[28..32].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+
+        let advertisement = parse_router_advertisement(&data).unwrap();
+        assert_eq!(advertisement.router_lifetime, 1800);
+        assert!(!advertisement.managed);
+        assert!(!advertisement.other_config);
+        assert_eq!(advertisement.prefixes.len(), 1);
+        let prefix = &advertisement.prefixes[0];
+        assert_eq!(prefix.prefix_len, 64);
+        assert_eq!(prefix.flags, 0xc0);
+        assert_eq!(prefix.valid_lifetime, 86400);
+        assert_eq!(prefix.preferred_lifetime, 14400);
+        assert_eq!(&prefix.prefix[0..4], &[0x20, 0x01, 0x0d, 0xb8]);
+    }
+
+    #[test]
+    fn test_parse_router_advertisement_bad_option() {
+        let mut data = [0u8; 20];
+        data[12] = OPT_PREFIX_INFO;
+        data[13] = 0; // Zero length is invalid
+        assert!(parse_router_advertisement(&data).is_none());
+
+        data[13] = 4; // Runs past end
+        assert!(parse_router_advertisement(&data).is_none());
+
+        assert!(parse_router_advertisement(&data[..8]).is_none());
+    }
+
+    #[test]
+    fn test_parse_router_advertisement_flags() {
+        let mut data = [0u8; 12];
+        data[1] = RA_FLAG_MANAGED;
+        let advertisement = parse_router_advertisement(&data).unwrap();
+        assert!(advertisement.managed);
+        assert!(!advertisement.other_config);
+
+        data[1] = RA_FLAG_OTHER_CONFIG;
+        let advertisement = parse_router_advertisement(&data).unwrap();
+        assert!(!advertisement.managed);
+        assert!(advertisement.other_config);
+    }
+
+    #[test]
+    fn test_build_router_advertisement() {
+        let config = RouterConfig {
+            prefixes: vec![AdvertisedPrefix {
+                prefix: util::IPAddr::new_from(&[
+                    0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                ]),
+                prefix_len: 64,
+                on_link: true,
+                autonomous: true,
+                valid_lifetime: 86400,
+                preferred_lifetime: 14400,
+            }],
+            mtu: Some(1400),
+            dns_servers: vec![util::IPAddr::new_from(&[
+                0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x53,
+            ])],
+            dns_lifetime: 1200,
+            ..RouterConfig::default()
+        };
+
+        let message = build_router_advertisement(&config, 1800);
+        assert_eq!(message.len(), 12 + 32 + 8 + 24);
+        assert_eq!(message[0], 64);
+
+        // Can be read back by the host side parser
+        let advertisement = parse_router_advertisement(&message).unwrap();
+        assert_eq!(advertisement.router_lifetime, 1800);
+        assert_eq!(advertisement.prefixes.len(), 1);
+        let prefix = &advertisement.prefixes[0];
+        assert_eq!(prefix.prefix_len, 64);
+        assert_eq!(prefix.flags, PREFIX_FLAG_ON_LINK | PREFIX_FLAG_AUTONOMOUS);
+        assert_eq!(prefix.valid_lifetime, 86400);
+        assert_eq!(prefix.preferred_lifetime, 14400);
+        assert_eq!(&prefix.prefix[0..6], &[0x20, 0x01, 0x0d, 0xb8, 0, 1]);
+
+        // MTU option
+        assert_eq!(&message[44..48], &[OPT_MTU, 1, 0, 0]);
+        assert_eq!(util::get_be32(&message[48..52]), 1400);
+
+        // RDNSS option
+        assert_eq!(&message[52..56], &[OPT_RDNSS, 3, 0, 0]);
+        assert_eq!(util::get_be32(&message[56..60]), 1200);
+        assert_eq!(message[75], 0x53);
+    }
+
+    #[test]
+    fn test_router_config_validate() {
+        assert!(RouterConfig::default().validate().is_ok());
+
+        let config = RouterConfig {
+            min_interval: 500,
+            ..RouterConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = RouterConfig {
+            router_lifetime: 10,
+            ..RouterConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = RouterConfig {
+            router_lifetime: 0,
+            ..RouterConfig::default()
+        };
+        assert!(config.validate().is_ok());
+
+        let config = RouterConfig {
+            mtu: Some(1000),
+            ..RouterConfig::default()
+        };
+        assert!(config.validate().is_err());
+
+        let config = RouterConfig {
+            prefixes: vec![AdvertisedPrefix {
+                prefix: util::IPAddr::new_from(&[
+                    0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                ]),
+                prefix_len: 48,
+                on_link: true,
+                autonomous: true,
+                valid_lifetime: 100,
+                preferred_lifetime: 100,
+            }],
+            ..RouterConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_limit_valid_lifetime() {
+        const HOUR: u32 = 3600;
+
+        // Longer than two hours is always accepted
+        assert_eq!(limit_valid_lifetime(Some(1000), 3 * HOUR), 3 * HOUR);
+
+        // Longer than remaining is accepted
+        assert_eq!(limit_valid_lifetime(Some(1000), 60), 60);
+
+        // Can't shorten below two hours
+        assert_eq!(
+            limit_valid_lifetime(Some(10 * HOUR as u64 * 1000), 60),
+            2 * HOUR
+        );
+
+        // If less than two hours remain, keep the remaining time
+        assert_eq!(limit_valid_lifetime(Some(HOUR as u64 * 1000), 60), HOUR);
+
+        assert_eq!(
+            limit_valid_lifetime(Some(1000), INFINITE_LIFETIME),
+            INFINITE_LIFETIME
+        );
+        assert_eq!(
+            limit_valid_lifetime(None, INFINITE_LIFETIME),
+            INFINITE_LIFETIME
+        );
+        assert_eq!(limit_valid_lifetime(None, 60), 2 * HOUR);
+    }
+
+    #[test]
+    fn test_parse_redirect() {
+        let router =
+            util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let other_router =
+            util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3]);
+        let dest =
+            util::IPAddr::new_from(&[0x20, 0x01, 0xd, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 9]);
+
+        let mut data = [0u8; 36];
+        other_router.copy_to(&mut data[4..20]);
+        dest.copy_to(&mut data[20..36]);
+        assert_eq!(parse_redirect(&data, router), Some((other_router, dest)));
+
+        // Must come from a link-local address
+        assert_eq!(parse_redirect(&data, dest), None);
+
+        // Truncated
+        assert_eq!(parse_redirect(&data[..35], router), None);
+
+        // Destination is on link
+        dest.copy_to(&mut data[4..20]);
+        assert_eq!(parse_redirect(&data, router), Some((dest, dest)));
+
+        // Target that is neither link-local nor the destination
+        let global_router =
+            util::IPAddr::new_from(&[0x20, 0x01, 0xd, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        global_router.copy_to(&mut data[4..20]);
+        assert_eq!(parse_redirect(&data, router), None);
+
+        // Multicast destination
+        let multicast =
+            util::IPAddr::new_from(&[0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        other_router.copy_to(&mut data[4..20]);
+        multicast.copy_to(&mut data[20..36]);
+        assert_eq!(parse_redirect(&data, router), None);
+    }
+}
@@ -18,9 +18,13 @@
 
 use crate::buf;
 use crate::util;
+use std::sync::{LazyLock, Mutex};
 
 const MAX_VECS: usize = 8;
 
+/// Largest packet that can be sent on the interface.
+pub const MTU: usize = 1500;
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 struct IOVec {
@@ -34,17 +38,67 @@ extern "C" {
     // We eschew all type checking and just pass the iovecs as raw byte pointers.
     fn tun_recv(vecs: *const u8, length: usize) -> i32;
     fn tun_send(vecs: *const u8, length: usize) -> i32;
+    fn tun_send_batch(vecs: *const u8, counts: *const usize, num_packets: usize) -> i32;
+}
+
+/// Lifecycle of an IPv6 address (RFC 4862).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressState {
+    /// Duplicate address detection is in progress. The address can't be
+    /// used yet.
+    Tentative,
+
+    /// Can be used for new and existing connections.
+    Preferred,
+
+    /// The preferred lifetime has expired. Existing connections can still
+    /// use it, but it should not be chosen for new ones.
+    Deprecated,
+}
+
+struct InterfaceAddress {
+    addr: util::IPAddr,
+    prefix_len: u8,
+    state: AddressState,
+
+    // Absolute times (ms) when the address becomes deprecated and invalid.
+    // None means infinite.
+    preferred_until: Option<u64>,
+    valid_until: Option<u64>,
 }
 
-static mut LOCAL_IPV4: util::IPAddr = util::IPAddr::new();
-static mut LOCAL_IPV6: util::IPAddr = util::IPAddr::new();
+struct InterfaceConfig {
+    ipv4_addr: util::IPAddr,
+    ipv4_netmask: util::IPAddr,
+    ipv4_router: Option<util::IPAddr>,
+
+    // The first entry is always the link local address.
+    ipv6_addrs: Vec<InterfaceAddress>,
+}
+
+static CONFIG: LazyLock<Mutex<InterfaceConfig>> = LazyLock::new(|| {
+    Mutex::new(InterfaceConfig {
+        ipv4_addr: util::IPAddr::new_from(&[10, 0, 0, 2]),
+        ipv4_netmask: util::IPAddr::new_from(&[255, 255, 255, 0]),
+        ipv4_router: None,
+        ipv6_addrs: vec![InterfaceAddress {
+            addr: util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x2]),
+            prefix_len: 64,
+            state: AddressState::Preferred,
+            preferred_until: None,
+            valid_until: None,
+        }],
+    })
+});
+
+/// Hardware address used to derive IPv6 interface identifiers. TUN devices
+/// don't have a link layer, so this is made up (it has the locally
+/// administered bit set).
+pub const HW_ADDR: [u8; 6] = [0x02, 0x00, 0x5e, 0x10, 0x00, 0x02];
 
 pub fn init() {
     unsafe {
         tun_init();
-        LOCAL_IPV4 = util::IPAddr::new_from(&[10, 0, 0, 2]);
-        LOCAL_IPV6 =
-            util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x2]);
     }
 }
 
@@ -97,6 +151,212 @@ pub fn send_packet(packet: buf::NetBuffer) {
     util::METRICS.packets_sent.inc();
 }
 
-pub fn get_ipaddr() -> (util::IPAddr, util::IPAddr) {
-    unsafe { (LOCAL_IPV4, LOCAL_IPV6) }
+/// Send several packets with a single call into the TUN wrapper. The kernel
+/// still gets one write per packet, since a TUN device only takes one at a
+/// time. Returns how many were sent, which is less than the number of
+/// packets if a write failed partway through.
+pub fn send_packets(packets: &[buf::NetBuffer]) -> usize {
+    if packets.is_empty() {
+        return 0;
+    }
+
+    let mut iovec = vec![
+        IOVec {
+            base: std::ptr::null::<u8>(),
+            len: 0,
+        };
+        packets.len() * MAX_VECS
+    ];
+    let mut counts = Vec::with_capacity(packets.len());
+    let mut num_vecs = 0;
+    for packet in packets {
+        let count = to_iovec(packet, &mut iovec[num_vecs..num_vecs + MAX_VECS]);
+        counts.push(count);
+        num_vecs += count;
+    }
+
+    let result =
+        unsafe { tun_send_batch(iovec.as_ptr() as *const u8, counts.as_ptr(), packets.len()) };
+    if result <= 0 {
+        println!("Error {} writing to TUN interface", result);
+        std::process::exit(1);
+    }
+
+    let sent = result as usize;
+    if sent < packets.len() {
+        println!(
+            "Error writing to TUN interface, sent {} of {} packets",
+            sent,
+            packets.len()
+        );
+    }
+
+    util::METRICS.packets_sent.add(sent as u32);
+    sent
+}
+
+pub fn get_ipv4_addr() -> util::IPAddr {
+    CONFIG.lock().unwrap().ipv4_addr
+}
+
+pub fn get_ipv4_netmask() -> util::IPAddr {
+    CONFIG.lock().unwrap().ipv4_netmask
+}
+
+pub fn get_ipv4_router() -> Option<util::IPAddr> {
+    CONFIG.lock().unwrap().ipv4_router
+}
+
+/// Replace the IPv4 configuration. An address of 0.0.0.0 means the
+/// interface is unconfigured, in which case packets to any IPv4 address
+/// will be accepted (which is necessary for DHCP).
+pub fn set_ipv4_config(addr: util::IPAddr, netmask: util::IPAddr, router: Option<util::IPAddr>) {
+    let mut guard = CONFIG.lock().unwrap();
+    guard.ipv4_addr = addr;
+    guard.ipv4_netmask = netmask;
+    guard.ipv4_router = router;
+}
+
+pub fn get_link_local_addr() -> util::IPAddr {
+    CONFIG.lock().unwrap().ipv6_addrs[0].addr
+}
+
+/// Returns true if this is one of the addresses assigned to this interface.
+/// Tentative addresses are not included, since they are not usable yet.
+pub fn is_local_addr(addr: util::IPAddr) -> bool {
+    let guard = CONFIG.lock().unwrap();
+    match addr {
+        util::IPAddr::V4(_) => addr == guard.ipv4_addr,
+        util::IPAddr::V6(_) => guard
+            .ipv6_addrs
+            .iter()
+            .any(|entry| entry.addr == addr && entry.state != AddressState::Tentative),
+    }
+}
+
+/// Choose which local address to send from when communicating with the
+/// given destination. This is a simplified version of RFC 6724: link local
+/// and link scoped multicast destinations use the link local address, and
+/// everything else uses a preferred global address if we have one.
+pub fn get_source_addr(dest_addr: util::IPAddr) -> util::IPAddr {
+    let guard = CONFIG.lock().unwrap();
+    match dest_addr {
+        util::IPAddr::V4(_) => guard.ipv4_addr,
+        util::IPAddr::V6(addr) => {
+            let link_local = guard.ipv6_addrs[0].addr;
+            let is_link_scope = (addr[0] == 0xfe && (addr[1] & 0xc0) == 0x80)
+                || (addr[0] == 0xff && (addr[1] & 0xf) <= 2);
+            if is_link_scope {
+                return link_local;
+            }
+
+            for state in [AddressState::Preferred, AddressState::Deprecated] {
+                if let Some(entry) = guard.ipv6_addrs[1..]
+                    .iter()
+                    .find(|entry| entry.state == state)
+                {
+                    return entry.addr;
+                }
+            }
+
+            link_local
+        }
+    }
+}
+
+/// Add an IPv6 address to the interface, or update the lifetimes if it is
+/// already present. Returns false if the address was already present.
+pub fn add_ipv6_addr(
+    addr: util::IPAddr,
+    prefix_len: u8,
+    state: AddressState,
+    preferred_until: Option<u64>,
+    valid_until: Option<u64>,
+) -> bool {
+    let mut guard = CONFIG.lock().unwrap();
+    if let Some(entry) = guard.ipv6_addrs.iter_mut().find(|entry| entry.addr == addr) {
+        entry.preferred_until = preferred_until;
+        entry.valid_until = valid_until;
+        if entry.state == AddressState::Deprecated && state == AddressState::Preferred {
+            entry.state = AddressState::Preferred;
+        }
+
+        return false;
+    }
+
+    guard.ipv6_addrs.push(InterfaceAddress {
+        addr,
+        prefix_len,
+        state,
+        preferred_until,
+        valid_until,
+    });
+
+    true
+}
+
+pub fn remove_ipv6_addr(addr: util::IPAddr) {
+    let mut guard = CONFIG.lock().unwrap();
+
+    // The link local address is never removed.
+    if let Some(index) = guard.ipv6_addrs[1..]
+        .iter()
+        .position(|entry| entry.addr == addr)
+    {
+        guard.ipv6_addrs.remove(index + 1);
+    }
+}
+
+/// Return the current state and valid lifetime (absolute time in ms) of an
+/// address, or None if it isn't assigned to this interface.
+pub fn get_ipv6_addr_info(addr: util::IPAddr) -> Option<(AddressState, Option<u64>)> {
+    let guard = CONFIG.lock().unwrap();
+    guard
+        .ipv6_addrs
+        .iter()
+        .find(|entry| entry.addr == addr)
+        .map(|entry| (entry.state, entry.valid_until))
+}
+
+pub fn set_ipv6_addr_state(addr: util::IPAddr, state: AddressState) {
+    let mut guard = CONFIG.lock().unwrap();
+    if let Some(entry) = guard.ipv6_addrs.iter_mut().find(|entry| entry.addr == addr) {
+        entry.state = state;
+    }
+}
+
+/// Deprecate or remove addresses whose lifetimes have expired. Returns the
+/// addresses that were removed.
+pub fn expire_ipv6_addrs(now: u64) -> Vec<util::IPAddr> {
+    let mut guard = CONFIG.lock().unwrap();
+    let mut removed = Vec::new();
+    guard.ipv6_addrs.retain(|entry| {
+        if matches!(entry.valid_until, Some(time) if now >= time) {
+            removed.push(entry.addr);
+            false
+        } else {
+            true
+        }
+    });
+
+    for entry in guard.ipv6_addrs.iter_mut() {
+        if entry.state == AddressState::Preferred
+            && matches!(entry.preferred_until, Some(time) if now >= time)
+        {
+            println!("Address {} is deprecated", entry.addr);
+            entry.state = AddressState::Deprecated;
+        }
+    }
+
+    removed
+}
+
+/// Return a list of all IPv6 addresses and their current state.
+pub fn get_ipv6_addrs() -> Vec<(util::IPAddr, u8, AddressState)> {
+    let guard = CONFIG.lock().unwrap();
+    guard
+        .ipv6_addrs
+        .iter()
+        .map(|entry| (entry.addr, entry.prefix_len, entry.state))
+        .collect()
 }
@@ -0,0 +1,397 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Echo client ("ping") for ICMP and ICMPv6.
+//
+// Each call to ping picks a random identifier, which is put in the echo
+// requests it sends. The remote host copies it into the reply, and the ICMP
+// layer uses it to find the waiting caller. Requests are sent one at a
+// time: the next one goes out after the previous reply arrives or times
+// out, and the interval has elapsed.
+//
+// If a router or the destination sends an ICMP error about a request, such
+// as Time Exceeded when the TTL is too small, the ICMP layer finds the
+// caller using the identifier in the quoted request. This is how
+// traceroute's ICMP mode works.
+
+use crate::buf;
+use crate::icmp;
+use crate::ip;
+use crate::util;
+use std::collections::HashMap;
+use std::sync::{mpsc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+//    0               1               2               3
+//    +---------------+---------------+-------------------------------+
+//  0 |     Type      |     Code      |          Checksum             |
+//    +---------------+---------------+-------------------------------+
+//  4 |          Identifier           |        Sequence Number        |
+//    +-------------------------------+-------------------------------+
+//  8 |                          Data...                              |
+//    +---------------------------------------------------------------+
+
+const ECHO_HEADER_LEN: usize = 4; // After the ICMP header
+
+/// Parameters for a series of echo requests.
+pub struct PingOptions {
+    /// Number of requests to send
+    pub count: u32,
+
+    /// Bytes of data in each request, not including headers
+    pub payload_size: usize,
+
+    /// Minimum time between sending requests (ms)
+    pub interval: u32,
+
+    /// How long to wait for each reply (ms)
+    pub timeout: u32,
+
+    /// TTL and other IP header fields for the requests
+    pub ip_metadata: ip::IPMetadata,
+}
+
+/// What happened to one echo request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingResult {
+    Reply {
+        seq: u16,
+        source_addr: util::IPAddr,
+        len: usize,
+        rtt: Duration,
+    },
+    Error {
+        seq: u16,
+        source_addr: util::IPAddr,
+        message: &'static str,
+        rtt: Duration,
+    },
+    Timeout {
+        seq: u16,
+    },
+}
+
+/// Summary of all requests sent by a call to ping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PingStatistics {
+    pub transmitted: u32,
+    pub received: u32,
+    pub min_rtt: Duration,
+    pub max_rtt: Duration,
+    total_rtt: Duration,
+}
+
+// A reply, or an error if message is set.
+struct EchoReply {
+    seq: u16,
+    source_addr: util::IPAddr,
+    len: usize,
+    message: Option<&'static str>,
+    time: Instant,
+}
+
+// Callers waiting for replies, keyed by identifier.
+type SessionMap = HashMap<u16, mpsc::Sender<EchoReply>>;
+
+static SESSIONS: LazyLock<Mutex<SessionMap>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+impl Default for PingOptions {
+    fn default() -> Self {
+        PingOptions {
+            count: 4,
+            payload_size: 56,
+            interval: 1000,
+            timeout: 1000,
+            ip_metadata: ip::IPMetadata::new(),
+        }
+    }
+}
+
+impl PingOptions {
+    fn validate(&self, dest_addr: util::IPAddr) -> Result<(), &'static str> {
+        if self.count == 0 {
+            return Err("Count must be non-zero");
+        }
+
+        if self.timeout == 0 {
+            return Err("Timeout must be non-zero");
+        }
+
+        // Requests are sent with the don't fragment flag, like other packets.
+        let max_payload = ip::get_path_mtu(dest_addr)
+            - ip::header_len(dest_addr)
+            - icmp::ICMP_HEADER_LEN
+            - ECHO_HEADER_LEN;
+        if self.payload_size > max_payload {
+            return Err("Payload too large");
+        }
+
+        self.ip_metadata.validate()
+    }
+}
+
+impl PingStatistics {
+    fn add(&mut self, result: &PingResult) {
+        self.transmitted += 1;
+        if let PingResult::Reply { rtt, .. } = *result {
+            if self.received == 0 || rtt < self.min_rtt {
+                self.min_rtt = rtt;
+            }
+
+            if rtt > self.max_rtt {
+                self.max_rtt = rtt;
+            }
+
+            self.received += 1;
+            self.total_rtt += rtt;
+        }
+    }
+
+    /// Mean round trip time of requests that got a reply.
+    pub fn average_rtt(&self) -> Option<Duration> {
+        if self.received == 0 {
+            None
+        } else {
+            Some(self.total_rtt / self.received)
+        }
+    }
+
+    /// Percentage of requests that did not get a reply.
+    pub fn loss_percent(&self) -> f64 {
+        if self.transmitted == 0 {
+            0.0
+        } else {
+            100.0 * (self.transmitted - self.received) as f64 / self.transmitted as f64
+        }
+    }
+}
+
+/// Send echo requests to dest_addr and wait for replies. The callback is
+/// invoked as each reply arrives or each request times out. This blocks
+/// until all requests have been sent and returns a summary.
+pub fn ping<F>(
+    dest_addr: util::IPAddr,
+    options: &PingOptions,
+    mut callback: F,
+) -> Result<PingStatistics, &'static str>
+where
+    F: FnMut(&PingResult),
+{
+    options.validate(dest_addr)?;
+
+    let (sender, receiver) = mpsc::channel();
+    let id = register_session(sender);
+    let timeout = Duration::from_millis(options.timeout as u64);
+    let interval = Duration::from_millis(options.interval as u64);
+    let mut statistics = PingStatistics::default();
+
+    for i in 0..options.count {
+        let seq = i as u16;
+        let send_time = Instant::now();
+        send_echo_request(
+            dest_addr,
+            id,
+            seq,
+            options.payload_size,
+            &options.ip_metadata,
+        );
+
+        let deadline = send_time + timeout;
+        let result = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(remaining) {
+                // Ignore late replies to earlier requests.
+                Ok(reply) if reply.seq == seq => {
+                    let rtt = reply.time - send_time;
+                    break match reply.message {
+                        None => PingResult::Reply {
+                            seq,
+                            source_addr: reply.source_addr,
+                            len: reply.len,
+                            rtt,
+                        },
+                        Some(message) => PingResult::Error {
+                            seq,
+                            source_addr: reply.source_addr,
+                            message,
+                            rtt,
+                        },
+                    };
+                }
+                Ok(_) => {}
+                Err(_) => break PingResult::Timeout { seq },
+            }
+        };
+
+        statistics.add(&result);
+        callback(&result);
+
+        if i + 1 < options.count {
+            std::thread::sleep(interval.saturating_sub(send_time.elapsed()));
+        }
+    }
+
+    SESSIONS.lock().unwrap().remove(&id);
+
+    Ok(statistics)
+}
+
+/// Pick an identifier that isn't in use by another call to ping.
+fn register_session(sender: mpsc::Sender<EchoReply>) -> u16 {
+    let mut sessions = SESSIONS.lock().unwrap();
+    loop {
+        let id = rand::random::<u16>();
+        if let std::collections::hash_map::Entry::Vacant(entry) = sessions.entry(id) {
+            entry.insert(sender);
+            return id;
+        }
+    }
+}
+
+fn send_echo_request(
+    dest_addr: util::IPAddr,
+    id: u16,
+    seq: u16,
+    payload_size: usize,
+    metadata: &ip::IPMetadata,
+) {
+    let mut packet = buf::NetBuffer::new();
+    let mut header = [0u8; ECHO_HEADER_LEN];
+    util::set_be16(&mut header[0..2], id);
+    util::set_be16(&mut header[2..4], seq);
+    packet.append_from_slice(&header);
+
+    let payload: Vec<u8> = (0..payload_size).map(|i| i as u8).collect();
+    packet.append_from_slice(&payload);
+
+    match dest_addr {
+        util::IPAddr::V4(_) => {
+            icmp::icmp_output_v4(packet, icmp::ICMPV4_ECHO_REQUEST, dest_addr, metadata)
+        }
+        util::IPAddr::V6(_) => {
+            icmp::icmp_output_v6(packet, icmp::ICMPV6_ECHO_REQUEST, dest_addr, metadata)
+        }
+    }
+}
+
+/// Called by the ICMP layer when an echo reply is received. The ICMP header
+/// has already been removed.
+pub fn echo_reply_input(packet: buf::NetBuffer, source_addr: util::IPAddr) {
+    let time = Instant::now();
+    if packet.len() < ECHO_HEADER_LEN {
+        return;
+    }
+
+    let header = packet.header();
+    let id = util::get_be16(&header[0..2]);
+    let seq = util::get_be16(&header[2..4]);
+    if let Some(sender) = SESSIONS.lock().unwrap().get(&id) {
+        let _ = sender.send(EchoReply {
+            seq,
+            source_addr,
+            len: packet.len() - ECHO_HEADER_LEN,
+            message: None,
+            time,
+        });
+    }
+}
+
+/// Called by the ICMP layer when an error is received about one of our echo
+/// requests.
+pub fn echo_error_input(id: u16, seq: u16, reporter: util::IPAddr, message: &'static str) {
+    let time = Instant::now();
+    if let Some(sender) = SESSIONS.lock().unwrap().get(&id) {
+        let _ = sender.send(EchoReply {
+            seq,
+            source_addr: reporter,
+            len: 0,
+            message: Some(message),
+            time,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(seq: u16, rtt_ms: u64) -> PingResult {
+        PingResult::Reply {
+            seq,
+            source_addr: util::IPAddr::new(),
+            len: 56,
+            rtt: Duration::from_millis(rtt_ms),
+        }
+    }
+
+    #[test]
+    fn test_statistics() {
+        let mut statistics = PingStatistics::default();
+        assert_eq!(statistics.average_rtt(), None);
+        assert_eq!(statistics.loss_percent(), 0.0);
+
+        statistics.add(&reply(0, 20));
+        statistics.add(&PingResult::Timeout { seq: 1 });
+        statistics.add(&reply(2, 10));
+        statistics.add(&reply(3, 30));
+
+        assert_eq!(statistics.transmitted, 4);
+        assert_eq!(statistics.received, 3);
+        assert_eq!(statistics.min_rtt, Duration::from_millis(10));
+        assert_eq!(statistics.max_rtt, Duration::from_millis(30));
+        assert_eq!(statistics.average_rtt(), Some(Duration::from_millis(20)));
+        assert_eq!(statistics.loss_percent(), 25.0);
+    }
+
+    #[test]
+    fn test_all_lost() {
+        let mut statistics = PingStatistics::default();
+        statistics.add(&PingResult::Timeout { seq: 0 });
+        statistics.add(&PingResult::Error {
+            seq: 1,
+            source_addr: util::IPAddr::new(),
+            message: "Host unreachable",
+            rtt: Duration::from_millis(5),
+        });
+        assert_eq!(statistics.received, 0);
+        assert_eq!(statistics.average_rtt(), None);
+        assert_eq!(statistics.loss_percent(), 100.0);
+    }
+
+    #[test]
+    fn test_validate_options() {
+        let dest = util::IPAddr::new_from(&[10, 0, 0, 1]);
+        assert!(PingOptions::default().validate(dest).is_ok());
+
+        let options = PingOptions {
+            count: 0,
+            ..PingOptions::default()
+        };
+        assert!(options.validate(dest).is_err());
+
+        let options = PingOptions {
+            timeout: 0,
+            ..PingOptions::default()
+        };
+        assert!(options.validate(dest).is_err());
+
+        let options = PingOptions {
+            payload_size: 100000,
+            ..PingOptions::default()
+        };
+        assert!(options.validate(dest).is_err());
+    }
+}
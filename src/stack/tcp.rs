@@ -19,6 +19,7 @@
 // avoidance, Nagle's algorithm, and RTT time estimation.
 
 use crate::buf;
+use crate::dns;
 use crate::ip;
 use crate::netif;
 use crate::timer;
@@ -26,7 +27,8 @@ use crate::util;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Display;
-use std::sync::{Arc, Condvar, LazyLock, Mutex, MutexGuard};
+use std::sync::{mpsc, Arc, Condvar, LazyLock, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 const EPHEMERAL_PORT_BASE: u16 = 49152;
 const RETRANSMIT_INTERVAL: u32 = 1000; // HACK: this should back off
@@ -39,6 +41,15 @@ const DEFAULT_TCP_MSS: usize = 536;
 const MAX_RECEIVE_WINDOW: u16 = 0xffff;
 const MAX_RETRIES: u32 = 5; // For connection management
 
+// Packetization layer path MTU discovery parameters
+const BLACK_HOLE_TIMEOUTS: u32 = 2; // Consecutive retransmit timeouts
+const MTU_PROBE_INTERVAL: u64 = 10000; // ms
+const MTU_PROBE_GRANULARITY: usize = 32; // Stop searching when this close
+
+// How long to wait for a connection attempt before starting one to the next
+// address (RFC 8305, section 5)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
 #[derive(Debug)]
 enum TCPState {
     Closed,
@@ -97,13 +108,21 @@ struct TCPSocketState {
     send_last_win_seq: u32, // SND.WL1
     send_last_win_ack: u32, // SND.WL2
     send_mss: usize,
+    mtu_prober: MTUProber,
+    ip_metadata: ip::IPMetadata,
     retransmit_queue: buf::NetBuffer,
     retransmit_timer_id: i32,
     response_timer_id: i32,
     request_retry_count: u32,
 
+    // Most recent ICMP error reported for this connection, and the address
+    // of the host or router that sent it. This is returned from tcp_open if
+    // the connection attempt fails.
+    soft_error: Option<(&'static str, util::IPAddr)>,
+
     // Listen
     socket_queue: Vec<SocketReference>,
+    bind_addr: util::BindAddr,
 }
 
 struct TCPReassembler {
@@ -111,6 +130,23 @@ struct TCPReassembler {
     out_of_order: Vec<(u32, buf::NetBuffer)>,
 }
 
+/// Packetization layer path MTU discovery (RFC 4821)
+/// Classic path MTU discovery depends on routers sending ICMP messages back
+/// when a packet is too large. If those are filtered, large segments just
+/// vanish and the connection would keep retransmitting them forever. This
+/// detects that case by counting consecutive retransmit timeouts and falls
+/// back to a smaller MSS. It then periodically sends a larger probe segment,
+/// doing a binary search for the largest size that gets through.
+struct MTUProber {
+    base_mss: usize,    // Fallback that should work on any path
+    search_low: usize,  // Largest MSS known to get through
+    search_high: usize, // Largest MSS that might get through
+    timeout_count: u32,
+    probe_end_seq: Option<u32>,
+    probe_size: usize,
+    next_probe_time: u64,
+}
+
 struct TCPSendParams<'a> {
     source_port: u16,
     dest_ip: util::IPAddr,
@@ -120,6 +156,7 @@ struct TCPSendParams<'a> {
     flags: u8,
     window: u16,
     options: &'a [u8],
+    metadata: &'a ip::IPMetadata,
 }
 
 impl TCPSocket {
@@ -130,7 +167,7 @@ impl TCPSocket {
         )
     }
 
-    fn lock(&self) -> (MutexGuard<TCPSocketState>, &Condvar) {
+    fn lock(&self) -> (MutexGuard<'_, TCPSocketState>, &Condvar) {
         (self.0.lock().unwrap(), &self.1)
     }
 }
@@ -141,6 +178,12 @@ type PortMap = HashMap<SocketKey, SocketReference>;
 
 static PORT_MAP: LazyLock<Mutex<PortMap>> = LazyLock::new(|| Mutex::new(HashMap::new()));
 
+// Listening sockets are identified by their local binding and port.
+type ListenKey = (util::BindAddr, u16);
+type ListenMap = HashMap<ListenKey, SocketReference>;
+
+static LISTEN_MAP: LazyLock<Mutex<ListenMap>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
 /// Generate a random ephemeral port that doesn't conflict with any open sockets.
 fn find_ephemeral_port(
     guard: &mut MutexGuard<PortMap>,
@@ -163,6 +206,22 @@ pub fn tcp_open(
     remote_ip: util::IPAddr,
     remote_port: u16,
 ) -> Result<SocketReference, &'static str> {
+    let mut socket_ref = tcp_connect_start(remote_ip, remote_port, ip::IPMetadata::new())?;
+    tcp_connect_wait(&mut socket_ref, None)?;
+
+    Ok(socket_ref)
+}
+
+/// Start connecting to a host, sending the SYN with the given IP header
+/// parameters, but don't wait for the connection to be established. Use
+/// tcp_connect_wait to find out if it succeeded.
+pub fn tcp_connect_start(
+    remote_ip: util::IPAddr,
+    remote_port: u16,
+    metadata: ip::IPMetadata,
+) -> Result<SocketReference, &'static str> {
+    metadata.validate()?;
+
     let mut portmap_guard = PORT_MAP.lock().unwrap();
     let local_port = find_ephemeral_port(&mut portmap_guard, remote_ip, remote_port);
     let socket_ref = Arc::new(TCPSocket::new(remote_ip, remote_port, local_port));
@@ -170,41 +229,147 @@ pub fn tcp_open(
     portmap_guard.insert((remote_ip, remote_port, local_port), socket_ref.clone());
     drop(portmap_guard);
 
-    let (mut guard, cond) = (*socket_ref).lock();
+    // Pick the source address now, so it can't change partway through the
+    // connection if addresses or routes do.
+    let (mut guard, _) = (*socket_ref).lock();
+    guard.ip_metadata = ip::IPMetadata {
+        source_addr: Some(ip::get_source_addr(remote_ip, &metadata)),
+        ..metadata
+    };
     guard.set_state(TCPState::SynSent);
 
     guard.send_packet(buf::NetBuffer::new(), FLAG_SYN);
     set_response_timer(&mut guard, socket_ref.clone());
+    drop(guard);
 
-    // Wait until this is connected
-    while !matches!(guard.state, TCPState::Established) {
-        guard = cond.wait(guard).unwrap();
-        if matches!(guard.state, TCPState::Closed) {
-            return Err("Connection failed");
+    Ok(socket_ref)
+}
+
+/// Wait for a connection started by tcp_connect_start to be established.
+/// If timeout (in ms) is given and expires first, this returns an error,
+/// but the connection attempt continues until the socket is closed.
+pub fn tcp_connect_wait(
+    socket_ref: &mut SocketReference,
+    timeout: Option<u32>,
+) -> Result<(), &'static str> {
+    let deadline = timeout.map(|ms| Instant::now() + Duration::from_millis(ms as u64));
+    let (mut guard, cond) = (*socket_ref).lock();
+    loop {
+        match guard.state {
+            TCPState::SynSent => {}
+            TCPState::Closed => {
+                return Err(guard
+                    .soft_error
+                    .map(|(message, _)| message)
+                    .unwrap_or("Connection failed"));
+            }
+            _ => return Ok(()),
         }
+
+        guard = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err("Connection timed out");
+                }
+
+                cond.wait_timeout(guard, remaining).unwrap().0
+            }
+            None => cond.wait(guard).unwrap(),
+        };
     }
+}
 
-    std::mem::drop(guard);
+/// Return the most recent ICMP error reported for this connection, along
+/// with the address of the host or router that sent it. If a connection
+/// attempt fails because of an error, tcp_open returns its description,
+/// and this can be used to find out where it came from.
+pub fn tcp_get_error(socket_ref: &mut SocketReference) -> Option<(&'static str, util::IPAddr)> {
+    let (guard, _) = (*socket_ref).lock();
+    guard.soft_error
+}
 
-    Ok(socket_ref)
+/// Look up a host name and connect to it. If it has several addresses,
+/// connection attempts are started to each in turn, alternating between
+/// IPv6 and IPv4, without waiting for the previous one to fail ("Happy
+/// Eyeballs", RFC 8305). The first connection to succeed is returned, and
+/// the others are closed.
+pub fn tcp_connect_host(host: &str, port: u16) -> Result<SocketReference, &'static str> {
+    let addrs = interleave_families(dns::dns_resolve(host)?);
+    let (sender, receiver) = mpsc::channel();
+    let mut pending = 0;
+    for addr in addrs {
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            let result = tcp_open(addr, port);
+
+            // If another attempt already won, nobody is listening anymore.
+            if let Err(mpsc::SendError(Ok(mut socket))) = sender.send(result) {
+                tcp_close(&mut socket);
+            }
+        });
+
+        pending += 1;
+        match receiver.recv_timeout(CONNECTION_ATTEMPT_DELAY) {
+            Ok(Ok(socket)) => return Ok(socket),
+            Ok(Err(_)) => pending -= 1,
+            Err(_) => {}
+        }
+    }
+
+    while pending > 0 {
+        match receiver.recv() {
+            Ok(Ok(socket)) => return Ok(socket),
+            _ => pending -= 1,
+        }
+    }
+
+    Err("Connection failed")
+}
+
+// Reorder so address families alternate, starting with the family of the
+// first address.
+fn interleave_families(addrs: Vec<util::IPAddr>) -> Vec<util::IPAddr> {
+    let first_v6 = matches!(addrs.first(), Some(util::IPAddr::V6(_)));
+    let (mut preferred, mut other): (Vec<util::IPAddr>, Vec<util::IPAddr>) = addrs
+        .into_iter()
+        .partition(|addr| matches!(addr, util::IPAddr::V6(_)) == first_v6);
+    preferred.reverse();
+    other.reverse();
+
+    let mut result = Vec::new();
+    while !preferred.is_empty() || !other.is_empty() {
+        result.extend(preferred.pop());
+        result.extend(other.pop());
+    }
+
+    result
 }
 
 /// Disconnect the passed socket. This technically only disconnects
 /// the send direction and it is still possible to read from it (which
 /// is how the spec is defined).
 pub fn tcp_close(socket_ref: &mut SocketReference) {
-    let (mut guard, _) = (*socket_ref).lock();
+    let (mut guard, cond) = (*socket_ref).lock();
 
     println!("{} tcp_close: state {:?}", guard, guard.state);
     match guard.state {
         TCPState::Listen => {
-            let local_port = guard.local_port;
+            let key = (guard.bind_addr, guard.local_port);
             guard.set_state(TCPState::Closed);
             drop(guard); // Unlock to avoid deadlock
-            PORT_MAP
-                .lock()
-                .unwrap()
-                .remove(&(util::IPAddr::new(), 0, local_port));
+            LISTEN_MAP.lock().unwrap().remove(&key);
+        }
+
+        TCPState::SynSent => {
+            // Abandon the connection attempt (RFC 9293, 3.10.4)
+            if guard.response_timer_id != -1 {
+                timer::cancel_timer(guard.response_timer_id);
+                guard.response_timer_id = -1;
+            }
+
+            guard.set_state(TCPState::Closed);
+            cond.notify_all();
         }
 
         TCPState::Established => {
@@ -228,6 +393,17 @@ pub fn tcp_close(socket_ref: &mut SocketReference) {
 /// This will not necessarily read the full size of the slice. It will return how
 /// much data is available, or a negative number if the socket is closed.
 pub fn tcp_read(socket_ref: &mut SocketReference, data: &mut [u8]) -> i32 {
+    tcp_read_timeout(socket_ref, data, None)
+}
+
+/// Like tcp_read, but give up and return 0 if no data arrives within
+/// timeout ms (or wait forever if it is None).
+pub fn tcp_read_timeout(
+    socket_ref: &mut SocketReference,
+    data: &mut [u8],
+    timeout: Option<u32>,
+) -> i32 {
+    let deadline = timeout.map(|ms| Instant::now() + Duration::from_millis(ms as u64));
     let (mut guard, cond) = (*socket_ref).lock();
 
     loop {
@@ -241,7 +417,17 @@ pub fn tcp_read(socket_ref: &mut SocketReference, data: &mut [u8]) -> i32 {
             return got as i32;
         }
 
-        guard = cond.wait(guard).unwrap();
+        guard = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return 0;
+                }
+
+                cond.wait_timeout(guard, remaining).unwrap().0
+            }
+            None => cond.wait(guard).unwrap(),
+        };
     }
 }
 
@@ -255,7 +441,13 @@ pub fn tcp_write(socket_ref: &mut SocketReference, data: &[u8]) -> i32 {
 
     let mut offset = 0;
     while offset < data.len() {
-        let packet_length = std::cmp::min(data.len() - offset, guard.send_mss);
+        let mut packet_length = std::cmp::min(data.len() - offset, guard.send_mss);
+        let probe_size = guard.mtu_prober.get_probe_size(timer::current_time_ms());
+        let is_probe = matches!(probe_size, Some(size) if data.len() - offset >= size);
+        if is_probe {
+            packet_length = probe_size.unwrap();
+        }
+
         let max_segment = guard.send_unacked.wrapping_add(guard.send_window);
         if util::seq_gt(
             guard.send_next_seq.wrapping_add(packet_length as u32),
@@ -280,6 +472,12 @@ pub fn tcp_write(socket_ref: &mut SocketReference, data: &[u8]) -> i32 {
         packet.append_from_slice(packet_slice);
         guard.send_packet(packet, FLAG_ACK | FLAG_PSH);
         guard.send_next_seq = guard.send_next_seq.wrapping_add(packet_length as u32);
+        if is_probe {
+            println!("{}: Sending MTU probe, size {}", guard, packet_length);
+            let end_seq = guard.send_next_seq;
+            guard.mtu_prober.start_probe(end_seq, packet_length);
+        }
+
         guard.retransmit_queue.append_from_slice(packet_slice);
         offset += packet_length;
 
@@ -296,25 +494,52 @@ pub fn tcp_write(socket_ref: &mut SocketReference, data: &[u8]) -> i32 {
     data.len() as i32
 }
 
-/// Open a socket and listen for incoming connections on the specified port.
+/// Open a socket and listen for incoming connections on the specified port,
+/// to any local IPv4 or IPv6 address.
 pub fn tcp_listen(port: u16) -> Result<SocketReference, &'static str> {
+    tcp_listen_bind(util::BindAddr::Any, port)
+}
+
+/// Listen for incoming connections to the given local address or address
+/// family. Sockets with different bindings can listen on the same port, in
+/// which case connections go to the most specific match.
+pub fn tcp_listen_bind(
+    bind_addr: util::BindAddr,
+    port: u16,
+) -> Result<SocketReference, &'static str> {
+    if let util::BindAddr::Addr(addr) = bind_addr {
+        if !netif::is_local_addr(addr) {
+            return Err("Address not available");
+        }
+    }
+
     let socket_ref = Arc::new(TCPSocket::new(util::IPAddr::new(), 0, port));
 
     let (mut guard, _cond) = (*socket_ref).lock();
     guard.set_state(TCPState::Listen);
+    guard.bind_addr = bind_addr;
     drop(guard);
 
-    let mut portmap_guard = PORT_MAP.lock().unwrap();
-    if portmap_guard.contains_key(&(util::IPAddr::new(), 0, port)) {
+    let mut listen_map_guard = LISTEN_MAP.lock().unwrap();
+    if listen_map_guard.contains_key(&(bind_addr, port)) {
         return Err("Port already in use");
     }
 
-    portmap_guard.insert((util::IPAddr::new(), 0, port), socket_ref.clone());
-    drop(portmap_guard);
+    listen_map_guard.insert((bind_addr, port), socket_ref.clone());
+    drop(listen_map_guard);
 
     Ok(socket_ref)
 }
 
+// Find the listening socket for a connection to the given local address
+// and port, preferring one bound to that specific address.
+fn find_listen_socket(local_addr: util::IPAddr, local_port: u16) -> Option<SocketReference> {
+    let listen_map_guard = LISTEN_MAP.lock().unwrap();
+    util::BindAddr::lookup_order(local_addr)
+        .iter()
+        .find_map(|bind_addr| listen_map_guard.get(&(*bind_addr, local_port)).cloned())
+}
+
 /// Wait for an incoming connection on a listening socket, then return a new socket.
 pub fn tcp_accept(socket_ref: &mut SocketReference) -> Result<SocketReference, &'static str> {
     let (mut guard, cond) = (*socket_ref).lock();
@@ -326,6 +551,125 @@ pub fn tcp_accept(socket_ref: &mut SocketReference) -> Result<SocketReference, &
     Ok(guard.socket_queue.remove(0))
 }
 
+/// Set the IP header parameters (TTL/hop limit, DSCP, ECN, and flow label)
+/// that will be used for packets sent from this socket. If this is a listening
+/// socket, new connections accepted from it will inherit these. A connection
+/// keeps the source address it started with.
+pub fn tcp_set_ip_metadata(
+    socket_ref: &mut SocketReference,
+    metadata: ip::IPMetadata,
+) -> Result<(), &'static str> {
+    metadata.validate()?;
+    let (mut guard, _) = (*socket_ref).lock();
+    guard.ip_metadata = match guard.state {
+        TCPState::Listen => metadata,
+        _ => ip::IPMetadata {
+            source_addr: guard.ip_metadata.source_addr,
+            ..metadata
+        },
+    };
+
+    Ok(())
+}
+
+/// Return the IP header parameters currently used by this socket.
+pub fn tcp_get_ip_metadata(socket_ref: &mut SocketReference) -> ip::IPMetadata {
+    let (guard, _) = (*socket_ref).lock();
+    guard.ip_metadata
+}
+
+/// Called by the IP layer when it learns that the path MTU to a remote host
+/// has been lowered. Shrink the segment size of connections to that host
+/// and immediately resend unacknowledged data, since the packets that were
+/// too large were dropped.
+pub fn tcp_path_mtu_changed(remote_ip: util::IPAddr, mtu: usize) {
+    let sockets: Vec<SocketReference> = PORT_MAP
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(key, _)| key.0 == remote_ip)
+        .map(|(_, socket_ref)| socket_ref.clone())
+        .collect();
+
+    let mss = mtu - ip::header_len(remote_ip) - TCP_HEADER_LEN;
+    for socket_ref in sockets {
+        let (mut guard, _cond) = (*socket_ref).lock();
+        guard.mtu_prober.set_max_mss(mss);
+        if guard.send_mss <= mss {
+            continue;
+        }
+
+        println!("{}: Reducing MSS from {} to {}", guard, guard.send_mss, mss);
+        guard.send_mss = mss;
+        if guard.is_established() && !guard.retransmit_queue.is_empty() {
+            guard.resend_unacked(&socket_ref);
+        }
+    }
+}
+
+/// Called by the ICMP layer when an error is received for a packet this
+/// connection sent. Hard errors abort the connection (RFC 1122, 4.2.3.9).
+/// Soft errors are only recorded, unless the connection is still being
+/// opened, in which case the attempt fails immediately rather than waiting
+/// for all retries to time out (RFC 5461, 4.1).
+pub fn tcp_icmp_error(
+    remote_ip: util::IPAddr,
+    remote_port: u16,
+    local_port: u16,
+    seq_num: u32,
+    reporter: util::IPAddr,
+    message: &'static str,
+    is_hard: bool,
+) {
+    let socket_ref = match PORT_MAP
+        .lock()
+        .unwrap()
+        .get(&(remote_ip, remote_port, local_port))
+    {
+        Some(socket_ref) => socket_ref.clone(),
+        None => return,
+    };
+
+    let (mut guard, cond) = (*socket_ref).lock();
+
+    // Ignore errors for sequence numbers that aren't in flight, which
+    // makes it harder to reset connections with forged messages
+    // (RFC 5927, 4.1)
+    let in_flight = if matches!(guard.state, TCPState::SynSent | TCPState::SynReceived) {
+        seq_num == guard.send_next_seq
+    } else {
+        util::seq_ge(seq_num, guard.send_unacked) && util::seq_le(seq_num, guard.send_next_seq)
+    };
+
+    if !in_flight {
+        println!("{}: Ignoring ICMP error for sequence {}", guard, seq_num);
+        return;
+    }
+
+    guard.soft_error = Some((message, reporter));
+    if is_hard || matches!(guard.state, TCPState::SynSent | TCPState::SynReceived) {
+        println!("{}: Aborting connection: {}", guard, message);
+        if guard.response_timer_id != -1 {
+            timer::cancel_timer(guard.response_timer_id);
+            guard.response_timer_id = -1;
+        }
+
+        guard.set_state(TCPState::Closed);
+        cond.notify_all();
+    }
+}
+
+/// Limit the MSS the remote host advertised so segments will fit in
+/// the path MTU.
+fn clamp_mss(remote_ip: util::IPAddr, advertised_mss: usize) -> usize {
+    let path_mss = ip::get_path_mtu(remote_ip) - ip::header_len(remote_ip) - TCP_HEADER_LEN;
+    if advertised_mss == 0 {
+        std::cmp::min(DEFAULT_TCP_MSS, path_mss)
+    } else {
+        std::cmp::min(advertised_mss, path_mss)
+    }
+}
+
 fn retransmit(socket_ref: SocketReference) {
     let (mut guard, _cond) = (*socket_ref).lock();
 
@@ -335,15 +679,32 @@ fn retransmit(socket_ref: SocketReference) {
 
     util::METRICS.packets_retransmitted.inc();
 
+    // If the segment size changes, everything that was in flight was sent
+    // at a size that won't get through, so resend it at the new size.
+    let current_mss = guard.send_mss;
+    let probe_lost = guard.mtu_prober.probe_end_seq.is_some();
+    let new_mss = guard
+        .mtu_prober
+        .retransmit_timeout(current_mss, timer::current_time_ms());
+    if let Some(new_mss) = new_mss {
+        println!(
+            "{}: Possible MTU black hole, reducing MSS from {} to {}",
+            guard, current_mss, new_mss
+        );
+        guard.send_mss = new_mss;
+    }
+
     if !guard.retransmit_queue.is_empty() {
-        println!("Retransmitting sequence {}", guard.send_next_seq);
-        let mut packet = buf::NetBuffer::new();
-        packet.append_from_buffer(&guard.retransmit_queue, guard.send_mss);
-        guard.send_packet(packet, FLAG_ACK | FLAG_PSH);
-        let socket_clone = socket_ref.clone();
-        guard.retransmit_timer_id = timer::set_timer(RETRANSMIT_INTERVAL, move || {
-            retransmit(socket_clone);
-        });
+        println!("Retransmitting sequence {}", guard.send_unacked);
+        if probe_lost || new_mss.is_some() {
+            guard.resend_unacked(&socket_ref);
+        } else {
+            let mut packet = buf::NetBuffer::new();
+            packet.append_from_buffer(&guard.retransmit_queue, guard.send_mss);
+            let seq_num = guard.send_unacked;
+            guard.send_packet_at(packet, FLAG_ACK | FLAG_PSH, seq_num);
+            guard.restart_retransmit_timer(&socket_ref);
+        }
     }
 }
 
@@ -391,15 +752,25 @@ impl TCPSocketState {
             send_last_win_seq: 0,
             send_last_win_ack: 0,
             send_mss: DEFAULT_TCP_MSS,
+            mtu_prober: MTUProber::new(DEFAULT_TCP_MSS),
+            ip_metadata: ip::IPMetadata::new(),
             retransmit_queue: buf::NetBuffer::new(),
             retransmit_timer_id: -1,
             response_timer_id: -1,
             request_retry_count: 0,
+            soft_error: None,
+            bind_addr: util::BindAddr::Any,
             socket_queue: Vec::new(),
         }
     }
 
     fn send_packet(&mut self, packet: buf::NetBuffer, flags: u8) {
+        self.send_packet_at(packet, flags, self.send_next_seq);
+    }
+
+    /// Same as send_packet, but with an explicit sequence number. This is
+    /// used to retransmit data that has already been sent.
+    fn send_packet_at(&mut self, packet: buf::NetBuffer, flags: u8, seq_num: u32) {
         let receive_window = MAX_RECEIVE_WINDOW - self.receive_queue.len() as u16;
 
         // We need to acknowledge the FIN packet, which consumes a sequence
@@ -420,7 +791,7 @@ impl TCPSocketState {
             "{}: send_packet: flags {} seq {} ack {} window {} (length {})",
             self,
             flags_to_str(flags),
-            self.send_next_seq,
+            seq_num,
             ack_seq,
             receive_window,
             packet.len(),
@@ -436,11 +807,12 @@ impl TCPSocketState {
             source_port: self.local_port,
             dest_ip: self.remote_ip,
             dest_port: self.remote_port,
-            seq_num: self.send_next_seq,
+            seq_num,
             ack_num: ack_seq,
             flags,
             window: receive_window,
             options,
+            metadata: &self.ip_metadata,
         };
 
         tcp_output(packet, &params);
@@ -461,6 +833,37 @@ impl TCPSocketState {
             TCPState::Closed | TCPState::SynSent | TCPState::TimeWait
         )
     }
+
+    /// Send unacknowledged data again, split into segments of the current
+    /// MSS, as much as the peer's window allows. Anything past that will
+    /// be sent by later retransmits once acks come in.
+    fn resend_unacked(&mut self, socket_ref: &SocketReference) {
+        let limit = std::cmp::min(self.retransmit_queue.len(), self.send_window as usize);
+        let mut remaining = buf::NetBuffer::new();
+        remaining.append_from_buffer(&self.retransmit_queue, limit);
+        let mut seq_num = self.send_unacked;
+        while !remaining.is_empty() {
+            let length = std::cmp::min(self.send_mss, remaining.len());
+            let mut packet = buf::NetBuffer::new();
+            packet.append_from_buffer(&remaining, length);
+            remaining.trim_head(length);
+            self.send_packet_at(packet, FLAG_ACK | FLAG_PSH, seq_num);
+            seq_num = seq_num.wrapping_add(length as u32);
+        }
+
+        self.restart_retransmit_timer(socket_ref);
+    }
+
+    fn restart_retransmit_timer(&mut self, socket_ref: &SocketReference) {
+        if self.retransmit_timer_id != -1 {
+            timer::cancel_timer(self.retransmit_timer_id);
+        }
+
+        let socket_clone = socket_ref.clone();
+        self.retransmit_timer_id = timer::set_timer(RETRANSMIT_INTERVAL, move || {
+            retransmit(socket_clone);
+        });
+    }
 }
 
 impl Display for TCPSocketState {
@@ -473,6 +876,89 @@ impl Display for TCPSocketState {
     }
 }
 
+impl MTUProber {
+    fn new(max_mss: usize) -> MTUProber {
+        // Start by assuming the full size works. There's no need to search
+        // unless we detect a black hole.
+        MTUProber {
+            base_mss: std::cmp::min(DEFAULT_TCP_MSS, max_mss),
+            search_low: max_mss,
+            search_high: max_mss,
+            timeout_count: 0,
+            probe_end_seq: None,
+            probe_size: 0,
+            next_probe_time: 0,
+        }
+    }
+
+    /// Lower the upper bound of the search, for example because an ICMP
+    /// message reported the path MTU. A probe in flight is abandoned, since
+    /// the data will be resent at the smaller size and the ack won't show
+    /// whether the probe size works.
+    fn set_max_mss(&mut self, max_mss: usize) {
+        self.probe_end_seq = None;
+        self.search_high = std::cmp::min(self.search_high, max_mss);
+        self.search_low = std::cmp::min(self.search_low, max_mss);
+        self.base_mss = std::cmp::min(self.base_mss, max_mss);
+    }
+
+    /// If it's time to send a probe, return the size it should be.
+    fn get_probe_size(&self, now: u64) -> Option<usize> {
+        if self.probe_end_seq.is_some()
+            || now < self.next_probe_time
+            || self.search_high.saturating_sub(self.search_low) < MTU_PROBE_GRANULARITY
+        {
+            return None;
+        }
+
+        Some((self.search_low + self.search_high).div_ceil(2))
+    }
+
+    fn start_probe(&mut self, end_seq: u32, size: usize) {
+        self.probe_end_seq = Some(end_seq);
+        self.probe_size = size;
+    }
+
+    /// Called when new data is acknowledged. Returns a new MSS if this
+    /// confirmed a probe got through.
+    fn ack_received(&mut self, ack_num: u32, now: u64) -> Option<usize> {
+        self.timeout_count = 0;
+        let probe_end_seq = self.probe_end_seq?;
+        if !util::seq_ge(ack_num, probe_end_seq) {
+            return None;
+        }
+
+        self.probe_end_seq = None;
+        self.search_low = std::cmp::min(self.probe_size, self.search_high);
+        self.next_probe_time = now + MTU_PROBE_INTERVAL;
+        Some(self.search_low)
+    }
+
+    /// Called when the retransmit timer expires. Returns a new MSS if this
+    /// looks like a black hole, that is, segments of the current size are
+    /// being dropped.
+    fn retransmit_timeout(&mut self, current_mss: usize, now: u64) -> Option<usize> {
+        if self.probe_end_seq.is_some() {
+            // The probe was lost. This doesn't affect the current MSS.
+            self.probe_end_seq = None;
+            self.search_high = std::cmp::min(self.search_high, self.probe_size - 1);
+            self.next_probe_time = now + MTU_PROBE_INTERVAL;
+            return None;
+        }
+
+        self.timeout_count += 1;
+        if self.timeout_count < BLACK_HOLE_TIMEOUTS || current_mss <= self.base_mss {
+            return None;
+        }
+
+        self.timeout_count = 0;
+        self.search_low = self.base_mss;
+        self.search_high = current_mss - 1;
+        self.next_probe_time = now + MTU_PROBE_INTERVAL;
+        Some(self.base_mss)
+    }
+}
+
 impl TCPReassembler {
     const fn new() -> TCPReassembler {
         TCPReassembler {
@@ -540,8 +1026,8 @@ const TCP_HEADER_LEN: usize = 20;
 //
 
 /// Called by IP layer to handle received packets.
-pub fn tcp_input(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
-    if !validate_checksum(&packet, source_ip) {
+pub fn tcp_input(mut packet: buf::NetBuffer, source_ip: util::IPAddr, dest_ip: util::IPAddr) {
+    if !validate_checksum(&packet, source_ip, dest_ip) {
         println!("TCP checksum error");
         return;
     }
@@ -578,7 +1064,7 @@ pub fn tcp_input(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
     let pm_entry = port_map_guard.get_mut(&(source_ip, source_port, dest_port));
     if pm_entry.is_none() {
         // This might be a new socket, check for a listen socket
-        let listen_entry = port_map_guard.get_mut(&(util::IPAddr::new(), 0, dest_port));
+        let listen_entry = find_listen_socket(dest_ip, dest_port);
         if listen_entry.is_none() || (flags & FLAG_SYN) == 0 {
             let response = buf::NetBuffer::new();
             let params = TCPSendParams {
@@ -590,19 +1076,19 @@ pub fn tcp_input(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
                 flags: FLAG_RST | FLAG_ACK,
                 window: 0,
                 options: &[],
+                metadata: &ip::IPMetadata::new(),
             };
 
             tcp_output(response, &params);
             return;
         }
 
-        let listen_socket = listen_entry
-            .expect("just checked if listen_entry is none above")
-            .clone();
+        let listen_socket = listen_entry.expect("just checked if listen_entry is none above");
         let new_socket = handle_new_connection(
             listen_socket,
             source_ip,
             source_port,
+            dest_ip,
             dest_port,
             seq_num,
             ack_num,
@@ -620,8 +1106,9 @@ pub fn tcp_input(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
     let (mut guard, cond) = (*socket_ref).lock();
 
     if options.max_segment_size != 0 {
-        guard.send_mss = options.max_segment_size;
-        println!("Set max segment size {}", options.max_segment_size);
+        guard.send_mss = clamp_mss(source_ip, options.max_segment_size);
+        guard.mtu_prober = MTUProber::new(guard.send_mss);
+        println!("Set max segment size {}", guard.send_mss);
     }
 
     // XXX hack: this should be reset inside the state transitions for
@@ -720,6 +1207,13 @@ pub fn tcp_input(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
             }
 
             guard.send_unacked = ack_num;
+            if let Some(new_mss) = guard
+                .mtu_prober
+                .ack_received(ack_num, timer::current_time_ms())
+            {
+                println!("{}: MTU probe succeeded, MSS is now {}", guard, new_mss);
+                guard.send_mss = new_mss;
+            }
         }
 
         // We record the acknowledgement and sequence number of
@@ -835,13 +1329,11 @@ pub fn tcp_input(mut packet: buf::NetBuffer, source_ip: util::IPAddr) {
     }
 }
 
-fn validate_checksum(packet: &buf::NetBuffer, source_ip: util::IPAddr) -> bool {
-    let dest_ip = if matches!(source_ip, util::IPAddr::V4(_)) {
-        netif::get_ipaddr().0
-    } else {
-        netif::get_ipaddr().1
-    };
-
+fn validate_checksum(
+    packet: &buf::NetBuffer,
+    source_ip: util::IPAddr,
+    dest_ip: util::IPAddr,
+) -> bool {
     let ph_checksum =
         util::compute_pseudo_header_checksum(source_ip, dest_ip, packet.len(), ip::PROTO_TCP);
 
@@ -891,10 +1383,12 @@ fn parse_options(header: &[u8]) -> TCPHeaderOptions {
     options
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handle_new_connection(
     listen_socket_ref: SocketReference,
     source_ip: util::IPAddr,
     source_port: u16,
+    dest_ip: util::IPAddr,
     dest_port: u16,
     seq_num: u32,
     ack_num: u32,
@@ -907,11 +1401,21 @@ fn handle_new_connection(
     );
     let new_socket_ref = Arc::new(TCPSocket::new(source_ip, source_port, dest_port));
 
+    // Accepted sockets inherit IP options from the listening socket. Replies
+    // must come from the address the peer connected to, even if the listener
+    // is bound to any address and the routes change later.
+    let (listen_guard, _cond) = (*listen_socket_ref).lock();
+    let mut ip_metadata = listen_guard.ip_metadata;
+    ip_metadata.source_addr = ip_metadata.source_addr.or(Some(dest_ip));
+    drop(listen_guard);
+
     let (mut guard, _cond) = (*new_socket_ref).lock();
+    guard.ip_metadata = ip_metadata;
     guard.remote_ip = source_ip;
     guard.remote_port = source_port;
     guard.set_state(TCPState::SynReceived);
-    guard.send_mss = max_segment_size;
+    guard.send_mss = clamp_mss(source_ip, max_segment_size);
+    guard.mtu_prober = MTUProber::new(guard.send_mss);
     guard.receive_next_seq = seq_num.wrapping_add(1);
     guard.reassembler.set_next_expect(seq_num.wrapping_add(1));
 
@@ -936,7 +1440,7 @@ fn handle_new_connection(
 }
 
 fn tcp_output(mut packet: buf::NetBuffer, params: &TCPSendParams) {
-    assert!(params.options.len() % 4 == 0); // Must be pre-padded
+    assert!(params.options.len().is_multiple_of(4)); // Must be pre-padded
     let header_length = TCP_HEADER_LEN + params.options.len();
     packet.alloc_header(header_length);
     let packet_length = packet.len() as u16;
@@ -957,11 +1461,7 @@ fn tcp_output(mut packet: buf::NetBuffer, params: &TCPSendParams) {
     // Compute checksum
     // First need to create a pseudo header
     let ph_checksum = util::compute_pseudo_header_checksum(
-        if matches!(params.dest_ip, util::IPAddr::V4(_)) {
-            netif::get_ipaddr().0
-        } else {
-            netif::get_ipaddr().1
-        },
+        ip::get_source_addr(params.dest_ip, params.metadata),
         params.dest_ip,
         packet_length as usize,
         ip::PROTO_TCP,
@@ -972,7 +1472,7 @@ fn tcp_output(mut packet: buf::NetBuffer, params: &TCPSendParams) {
     let header = packet.header_mut();
     util::set_be16(&mut header[16..18], checksum);
 
-    ip::ip_output(packet, ip::PROTO_TCP, params.dest_ip);
+    ip::ip_output(packet, ip::PROTO_TCP, params.dest_ip, params.metadata);
 }
 
 fn set_response_timer(guard: &mut MutexGuard<TCPSocketState>, socket_ref: SocketReference) {
@@ -1322,4 +1822,130 @@ mod tests {
         // Ensure the previous one was removed.
         assert_eq!(reassembler.out_of_order.len(), 1);
     }
+
+    #[test]
+    fn test_mtu_prober_black_hole() {
+        let mut prober = MTUProber::new(1460);
+        assert_eq!(prober.get_probe_size(0), None);
+
+        // A single timeout isn't enough to conclude there's a black hole.
+        assert_eq!(prober.retransmit_timeout(1460, 1000), None);
+        assert_eq!(prober.retransmit_timeout(1460, 2000), Some(DEFAULT_TCP_MSS));
+        assert_eq!(prober.search_high, 1459);
+
+        // An ack resets the timeout count
+        assert_eq!(prober.retransmit_timeout(DEFAULT_TCP_MSS, 3000), None);
+        assert_eq!(prober.ack_received(1234, 3000), None);
+        assert_eq!(prober.retransmit_timeout(DEFAULT_TCP_MSS, 4000), None);
+
+        // Already at the lowest size
+        assert_eq!(prober.retransmit_timeout(DEFAULT_TCP_MSS, 5000), None);
+    }
+
+    #[test]
+    fn test_mtu_prober_search() {
+        let mut prober = MTUProber::new(1460);
+        assert_eq!(prober.retransmit_timeout(1460, 0), None);
+        assert_eq!(prober.retransmit_timeout(1460, 0), Some(536));
+
+        // Wait before probing
+        assert_eq!(prober.get_probe_size(MTU_PROBE_INTERVAL - 1), None);
+        assert_eq!(prober.get_probe_size(MTU_PROBE_INTERVAL), Some(998));
+
+        // Probe gets through
+        prober.start_probe(10000, 998);
+        assert_eq!(prober.get_probe_size(MTU_PROBE_INTERVAL), None);
+        assert_eq!(prober.ack_received(9000, MTU_PROBE_INTERVAL), None);
+        assert_eq!(prober.ack_received(10000, MTU_PROBE_INTERVAL), Some(998));
+        assert_eq!(prober.search_low, 998);
+
+        // Next probe is lost. The MSS is not changed.
+        assert_eq!(prober.get_probe_size(MTU_PROBE_INTERVAL * 2), Some(1229));
+        prober.start_probe(20000, 1229);
+        assert_eq!(prober.retransmit_timeout(998, MTU_PROBE_INTERVAL * 2), None);
+        assert_eq!(prober.search_high, 1228);
+        assert_eq!(prober.get_probe_size(MTU_PROBE_INTERVAL * 3), Some(1113));
+
+        // A lost probe doesn't raise a limit that was lowered while it was
+        // in flight.
+        prober.start_probe(30000, 1113);
+        prober.search_high = 1050;
+        assert_eq!(prober.retransmit_timeout(998, MTU_PROBE_INTERVAL * 3), None);
+        assert_eq!(prober.search_high, 1050);
+    }
+
+    #[test]
+    fn test_mtu_prober_converge() {
+        let mut prober = MTUProber::new(1460);
+        prober.set_max_mss(1000);
+        assert_eq!(prober.search_high, 1000);
+        prober.search_low = 980;
+        assert_eq!(prober.get_probe_size(0), None);
+    }
+
+    #[test]
+    fn test_mtu_prober_path_mtu_during_probe() {
+        let mut prober = MTUProber::new(1460);
+        assert_eq!(prober.retransmit_timeout(1460, 0), None);
+        assert_eq!(prober.retransmit_timeout(1460, 0), Some(536));
+        assert_eq!(prober.get_probe_size(MTU_PROBE_INTERVAL), Some(998));
+        prober.start_probe(10000, 998);
+
+        // An ICMP message lowers the path MTU while the probe is in flight.
+        // The data is resent at the smaller size, so the ack doesn't mean
+        // the probe got through.
+        prober.set_max_mss(800);
+        assert_eq!(prober.ack_received(10000, MTU_PROBE_INTERVAL), None);
+        assert_eq!(prober.get_probe_size(MTU_PROBE_INTERVAL), Some(668));
+
+        // Even if the bounds cross, the MSS never goes above the limit
+        prober.start_probe(20000, 668);
+        prober.probe_size = 900;
+        assert_eq!(prober.ack_received(20000, MTU_PROBE_INTERVAL), Some(800));
+        prober.search_low = 900;
+        assert_eq!(prober.get_probe_size(MTU_PROBE_INTERVAL * 2), None);
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let v6_1 = util::IPAddr::new_from(&[0x20, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let v6_2 = util::IPAddr::new_from(&[0x20, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        let v4_1 = util::IPAddr::new_from(&[10, 0, 0, 1]);
+        let v4_2 = util::IPAddr::new_from(&[10, 0, 0, 2]);
+        let v4_3 = util::IPAddr::new_from(&[10, 0, 0, 3]);
+
+        assert_eq!(
+            interleave_families(vec![v6_1, v6_2, v4_1, v4_2, v4_3]),
+            vec![v6_1, v4_1, v6_2, v4_2, v4_3]
+        );
+        assert_eq!(
+            interleave_families(vec![v4_1, v4_2, v6_1]),
+            vec![v4_1, v6_1, v4_2]
+        );
+        assert_eq!(interleave_families(vec![v4_1]), vec![v4_1]);
+        assert!(interleave_families(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_listen_bind() {
+        let v4_addr = util::IPAddr::V4([10, 0, 0, 2]);
+        let v6_addr = util::IPAddr::parse("fe80::2").unwrap();
+        let mut socket_v4 = tcp_listen_bind(util::BindAddr::AnyV4, 61100).unwrap();
+        let socket_v6 = tcp_listen_bind(util::BindAddr::AnyV6, 61100).unwrap();
+        assert!(tcp_listen_bind(util::BindAddr::AnyV4, 61100).is_err());
+        assert!(find_listen_socket(v4_addr, 61101).is_none());
+
+        let found = find_listen_socket(v4_addr, 61100).unwrap();
+        assert!(Arc::ptr_eq(&found, &socket_v4));
+        let found = find_listen_socket(v6_addr, 61100).unwrap();
+        assert!(Arc::ptr_eq(&found, &socket_v6));
+
+        tcp_close(&mut socket_v4);
+        assert!(find_listen_socket(v4_addr, 61100).is_none());
+        let socket_any = tcp_listen(61100).unwrap();
+        let found = find_listen_socket(v4_addr, 61100).unwrap();
+        assert!(Arc::ptr_eq(&found, &socket_any));
+        let found = find_listen_socket(v6_addr, 61100).unwrap();
+        assert!(Arc::ptr_eq(&found, &socket_v6));
+    }
 }
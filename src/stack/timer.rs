@@ -51,7 +51,8 @@ static PENDING_TIMERS: LazyLock<Mutex<Vec<Timer>>> = LazyLock::new(|| Mutex::new
 
 static NEXT_TIMER_ID: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(1);
 
-fn current_time_ms() -> u64 {
+/// Milliseconds since the epoch. This is the same time base used for timers.
+pub fn current_time_ms() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
@@ -125,6 +126,7 @@ pub fn init() {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     use std::sync::{Arc, Mutex, Once};
@@ -17,13 +17,16 @@
 // User Datagram Protcol, as described in RFC 768
 
 use crate::buf;
+use crate::igmp;
 use crate::ip;
 use crate::netif;
 use crate::util;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::sync::Condvar;
-use std::sync::{Arc, LazyLock, Mutex, MutexGuard};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
 
 pub type SocketReference = Arc<UDPSocket>;
 
@@ -31,88 +34,899 @@ pub struct UDPSocket(Mutex<UDPSocketState>, Condvar);
 
 pub struct UDPSocketState {
     receive_queue: VecDeque<(util::IPAddr, u16, buf::NetBuffer)>,
-    port: u16,
+
+    // Payload bytes currently in receive_queue, the most that can be, and
+    // how many datagrams were dropped because it was full.
+    receive_queue_bytes: usize,
+    receive_buffer_size: usize,
+    receive_drops: u64,
+
+    bind_addr: util::BindAddr,
+    local_port: u16,
+
+    // Set by udp_connect. Otherwise the address is unspecified and the port
+    // is zero, and packets from any peer are accepted.
+    remote_addr: util::IPAddr,
+    remote_port: u16,
+
+    ip_metadata: ip::IPMetadata,
+
+    // Broadcast and multicast options
+    broadcast: bool,
+    multicast_ttl: u8,
+    multicast_loop: bool,
+    groups: Vec<util::IPAddr>,
+
+    // ICMP error most recently reported for a packet sent from this socket.
+    last_error: Option<ICMPError>,
+    error_pending: bool,
+    closed: bool,
 }
 
-type PortMap = HashMap<u16, SocketReference>;
+/// An ICMP error reported for a packet sent from a socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ICMPError {
+    pub message: &'static str,
+
+    /// Host or router that sent the error.
+    pub reporter: util::IPAddr,
+
+    /// Destination of the packet that caused the error, taken from the
+    /// headers quoted in the ICMP message. This tells errors for different
+    /// packets apart on an unconnected socket.
+    pub dest_addr: util::IPAddr,
+    pub dest_port: u16,
+}
+
+const EPHEMERAL_PORT_BASE: u16 = 49152;
+const DEFAULT_RECEIVE_BUFFER_SIZE: usize = 65536;
+
+// Multicast packets stay on the local network unless the application asks
+// otherwise (RFC 1112, 6.1).
+const DEFAULT_MULTICAST_TTL: u8 = 1;
+
+// Local binding, remote address, remote port, local port. Sockets that are
+// not connected use an unspecified remote address and a remote port of zero.
+// This doesn't hold a strong reference, so the port is released when the
+// last handle to the socket is dropped.
+type SocketKey = (util::BindAddr, util::IPAddr, u16, u16);
+type PortMap = HashMap<SocketKey, Weak<UDPSocket>>;
 
 static PORT_MAP: LazyLock<Mutex<PortMap>> = LazyLock::new(|| Mutex::new(HashMap::new()));
 
 impl UDPSocket {
-    fn new(port: u16) -> UDPSocket {
-        UDPSocket(Mutex::new(UDPSocketState::new(port)), Condvar::new())
+    fn new(bind_addr: util::BindAddr, local_port: u16) -> UDPSocket {
+        UDPSocket(
+            Mutex::new(UDPSocketState::new(bind_addr, local_port)),
+            Condvar::new(),
+        )
     }
 
-    fn lock(&self) -> (MutexGuard<UDPSocketState>, &Condvar) {
+    fn lock(&self) -> (MutexGuard<'_, UDPSocketState>, &Condvar) {
         (self.0.lock().unwrap(), &self.1)
     }
 }
 
+impl Drop for UDPSocket {
+    fn drop(&mut self) {
+        // Still release the port if another thread panicked while holding
+        // the lock.
+        let state = self.0.get_mut().unwrap_or_else(|err| err.into_inner());
+        let key = state.key();
+        let groups = std::mem::take(&mut state.groups);
+        let mut port_map_guard = PORT_MAP.lock().unwrap();
+
+        // The port may have already been closed and reused by another socket.
+        if port_map_guard
+            .get(&key)
+            .is_some_and(|entry| entry.strong_count() == 0)
+        {
+            port_map_guard.remove(&key);
+        }
+
+        drop(port_map_guard);
+        leave_groups(groups);
+    }
+}
+
 impl UDPSocketState {
-    fn new(port: u16) -> UDPSocketState {
+    fn new(bind_addr: util::BindAddr, local_port: u16) -> UDPSocketState {
         UDPSocketState {
             receive_queue: VecDeque::new(),
-            port,
+            receive_queue_bytes: 0,
+            receive_buffer_size: DEFAULT_RECEIVE_BUFFER_SIZE,
+            receive_drops: 0,
+            bind_addr,
+            local_port,
+            remote_addr: util::IPAddr::new(),
+            remote_port: 0,
+            ip_metadata: ip::IPMetadata::new(),
+            broadcast: false,
+            multicast_ttl: DEFAULT_MULTICAST_TTL,
+            multicast_loop: true,
+            groups: Vec::new(),
+            last_error: None,
+            error_pending: false,
+            closed: false,
         }
     }
+
+    fn key(&self) -> SocketKey {
+        (
+            self.bind_addr,
+            self.remote_addr,
+            self.remote_port,
+            self.local_port,
+        )
+    }
+
+    fn is_connected(&self) -> bool {
+        self.remote_port != 0
+    }
+
+    // Check that a payload of length bytes can be sent to dest_addr. If so,
+    // return the IP header parameters to use and whether a copy should be
+    // delivered locally.
+    fn prepare_send(
+        &self,
+        dest_addr: util::IPAddr,
+        length: usize,
+    ) -> Result<(ip::IPMetadata, bool), &'static str> {
+        if self.closed {
+            return Err("Socket closed");
+        }
+
+        if !self.bind_addr.allows_family(dest_addr) {
+            return Err("Address family mismatch");
+        }
+
+        if !self.broadcast && ip::is_broadcast(dest_addr) {
+            return Err("Broadcast not enabled");
+        }
+
+        // Packets are sent with the don't fragment flag set, so anything larger
+        // than the path MTU would be dropped.
+        if length > udp_max_payload(dest_addr) {
+            return Err("Message too long");
+        }
+
+        // A socket bound to a specific address sends from it, unless the
+        // application has chosen a different one.
+        let mut metadata = self.ip_metadata;
+        if let util::BindAddr::Addr(local_addr) = self.bind_addr {
+            metadata.source_addr = metadata.source_addr.or(Some(local_addr));
+        }
+
+        let mut loop_back = false;
+        if dest_addr.is_multicast() {
+            metadata.ttl = self.multicast_ttl;
+            loop_back = self.multicast_loop && igmp::is_member(dest_addr);
+        }
+
+        Ok((metadata, loop_back))
+    }
+
+    // Return None if there is nothing to report yet and the caller should
+    // wait.
+    fn try_recv(&mut self, max_len: usize) -> Option<Result<Datagram, RecvResult>> {
+        if self.closed {
+            return Some(Err(RecvResult::Closed));
+        }
+
+        if self.error_pending {
+            self.error_pending = false;
+            return Some(Err(RecvResult::Error));
+        }
+
+        let (source_addr, source_port, mut payload) = self.receive_queue.pop_front()?;
+        self.receive_queue_bytes -= payload.len();
+        let truncated = payload.len() > max_len;
+        if truncated {
+            payload.trim_tail(payload.len() - max_len);
+        }
+
+        Some(Ok(Datagram {
+            payload,
+            source_addr,
+            source_port,
+            truncated,
+        }))
+    }
+}
+
+// If the entry is dead, the socket is being dropped and just hasn't removed
+// it yet.
+fn is_in_use(guard: &MutexGuard<PortMap>, key: &SocketKey) -> bool {
+    guard.get(key).is_some_and(|entry| entry.strong_count() > 0)
+}
+
+// Remove the entry for key only if it belongs to this socket.
+fn remove_entry(guard: &mut MutexGuard<PortMap>, key: &SocketKey, socket_ref: &SocketReference) {
+    if guard
+        .get(key)
+        .is_some_and(|entry| std::ptr::eq(entry.as_ptr(), Arc::as_ptr(socket_ref)))
+    {
+        guard.remove(key);
+    }
 }
 
-/// Open a new UDP socket with the specified local port.
+// Start at a random port and check each one in the range in turn, so this
+// fails instead of looping forever if they are all taken.
+fn find_ephemeral_port(guard: &MutexGuard<PortMap>) -> Result<u16, &'static str> {
+    const RANGE: u32 = 0x10000 - EPHEMERAL_PORT_BASE as u32;
+    let in_use: HashSet<u16> = guard
+        .iter()
+        .filter(|(_, entry)| entry.strong_count() > 0)
+        .map(|(key, _)| key.3)
+        .collect();
+    let start = rand::random::<u32>() % RANGE;
+    (0..RANGE)
+        .map(|offset| EPHEMERAL_PORT_BASE + ((start + offset) % RANGE) as u16)
+        .find(|port| !in_use.contains(port))
+        .ok_or("No ephemeral ports available")
+}
+
+fn leave_groups(groups: Vec<util::IPAddr>) {
+    for group in groups {
+        let _ = igmp::leave_group(group);
+    }
+}
+
+// Find the socket that should receive a packet. A socket connected to the
+// remote address and port takes precedence over one that isn't connected,
+// then one bound to the destination address takes precedence over one bound
+// to any address.
+fn find_socket(
+    remote_addr: util::IPAddr,
+    remote_port: u16,
+    local_addr: util::IPAddr,
+    local_port: u16,
+) -> Option<SocketReference> {
+    let port_map_guard = PORT_MAP.lock().unwrap();
+    let bind_order = util::BindAddr::lookup_order(local_addr);
+    let connected_keys =
+        bind_order.map(|bind_addr| (bind_addr, remote_addr, remote_port, local_port));
+    let unconnected_keys =
+        bind_order.map(|bind_addr| (bind_addr, util::IPAddr::new(), 0, local_port));
+    connected_keys
+        .iter()
+        .chain(unconnected_keys.iter())
+        .find_map(|key| port_map_guard.get(key).and_then(|entry| entry.upgrade()))
+}
+
+// Find all sockets that should receive a copy of a broadcast or multicast
+// packet. That is every socket on the port, except those bound to a
+// different address family or connected to a different peer.
+fn find_all_sockets(
+    remote_addr: util::IPAddr,
+    remote_port: u16,
+    local_addr: util::IPAddr,
+    local_port: u16,
+) -> Vec<SocketReference> {
+    let bind_order = util::BindAddr::lookup_order(local_addr);
+    PORT_MAP
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(key, _)| {
+            key.3 == local_port
+                && bind_order.contains(&key.0)
+                && (key.2 == 0 || (key.1 == remote_addr && key.2 == remote_port))
+        })
+        .filter_map(|(_, entry)| entry.upgrade())
+        .collect()
+}
+
+/// Open a new UDP socket with the specified local port, which will receive
+/// packets sent to any local IPv4 or IPv6 address. If the port is zero, an
+/// unused one is picked from the ephemeral range (use udp_get_local_port to
+/// find out which).
 pub fn udp_open(port: u16) -> Result<SocketReference, &'static str> {
+    udp_open_bind(util::BindAddr::Any, port)
+}
+
+/// Open a new UDP socket that only receives packets sent to the given local
+/// address or address family. Sockets with different bindings can share a
+/// port, in which case packets go to the most specific match. For example,
+/// separate services can use the same port on IPv4 and IPv6.
+pub fn udp_open_bind(
+    bind_addr: util::BindAddr,
+    port: u16,
+) -> Result<SocketReference, &'static str> {
+    if let util::BindAddr::Addr(addr) = bind_addr {
+        if !netif::is_local_addr(addr) {
+            return Err("Address not available");
+        }
+    }
+
     let mut port_map_guard = PORT_MAP.lock().unwrap();
-    if port_map_guard.contains_key(&port) {
+    let port = if port == 0 {
+        find_ephemeral_port(&port_map_guard)?
+    } else if is_in_use(&port_map_guard, &(bind_addr, util::IPAddr::new(), 0, port)) {
         return Err("Port already in use");
-    }
+    } else {
+        port
+    };
 
-    let socket_ref = Arc::new(UDPSocket::new(port));
-    port_map_guard.insert(port, socket_ref.clone());
+    let socket_ref = Arc::new(UDPSocket::new(bind_addr, port));
+    port_map_guard.insert(
+        (bind_addr, util::IPAddr::new(), 0, port),
+        Arc::downgrade(&socket_ref),
+    );
 
     Ok(socket_ref)
 }
 
+/// Set the remote address and port for this socket. After this, only
+/// packets from that peer are received on it, and udp_send_connected can be
+/// used to send without specifying the destination. Once the socket is
+/// connected, another socket can be opened on the same local port to
+/// receive packets from other peers. Calling this again changes the peer.
+pub fn udp_connect(
+    socket_ref: &mut SocketReference,
+    remote_addr: util::IPAddr,
+    remote_port: u16,
+) -> Result<(), &'static str> {
+    if remote_addr.is_unspecified() || remote_port == 0 {
+        return Err("Invalid address");
+    }
+
+    let (mut guard, _) = (*socket_ref).lock();
+    if guard.closed {
+        return Err("Socket closed");
+    }
+
+    if !guard.bind_addr.allows_family(remote_addr) {
+        return Err("Address family mismatch");
+    }
+
+    let old_key = guard.key();
+    let new_key = (guard.bind_addr, remote_addr, remote_port, guard.local_port);
+    if new_key == old_key {
+        return Ok(());
+    }
+
+    let mut port_map_guard = PORT_MAP.lock().unwrap();
+    if is_in_use(&port_map_guard, &new_key) {
+        return Err("Address in use");
+    }
+
+    remove_entry(&mut port_map_guard, &old_key, socket_ref);
+    port_map_guard.insert(new_key, Arc::downgrade(socket_ref));
+    drop(port_map_guard);
+
+    guard.remote_addr = remote_addr;
+    guard.remote_port = remote_port;
+
+    // Discard anything that arrived from other peers before this call.
+    guard
+        .receive_queue
+        .retain(|(addr, port, _)| *addr == remote_addr && *port == remote_port);
+    guard.receive_queue_bytes = guard
+        .receive_queue
+        .iter()
+        .map(|(_, _, packet)| packet.len())
+        .sum();
+
+    Ok(())
+}
+
+/// Return the local port this socket is bound to.
+pub fn udp_get_local_port(socket_ref: &mut SocketReference) -> u16 {
+    let (guard, _) = (*socket_ref).lock();
+    guard.local_port
+}
+
+/// Close the socket and release its port so it can be opened again. Any
+/// thread waiting in udp_recv wakes up and gets an error. This happens
+/// automatically when the last reference to the socket is dropped, but
+/// receive threads usually hold one, so services must call this explicitly.
+pub fn udp_close(socket_ref: &mut SocketReference) {
+    let (mut guard, cond) = (*socket_ref).lock();
+    if guard.closed {
+        return;
+    }
+
+    guard.closed = true;
+    guard.receive_queue.clear();
+    guard.receive_queue_bytes = 0;
+    let key = guard.key();
+    let groups = std::mem::take(&mut guard.groups);
+    cond.notify_all();
+    drop(guard); // Unlock to avoid deadlock
+
+    remove_entry(&mut PORT_MAP.lock().unwrap(), &key, socket_ref);
+    leave_groups(groups);
+}
+
+/// Return true if udp_close has been called on this socket. udp_recv
+/// returns -1 immediately on a closed socket, so receive loops should check
+/// this and exit.
+pub fn udp_is_closed(socket_ref: &mut SocketReference) -> bool {
+    let (guard, _) = (*socket_ref).lock();
+    guard.closed
+}
+
+/// Result of udp_recv_timeout and udp_try_recv.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvResult {
+    /// A datagram was received, and this many bytes of its payload were
+    /// copied into the passed slice.
+    Data(usize),
+
+    /// An ICMP error was reported for a packet sent from this socket.
+    /// udp_get_error describes it.
+    Error,
+
+    /// The socket has been closed with udp_close.
+    Closed,
+
+    /// Nothing arrived before the timeout expired.
+    TimedOut,
+
+    /// Nothing was waiting (udp_try_recv only).
+    WouldBlock,
+}
+
+/// A received datagram, as returned by udp_recv_buffer.
+pub struct Datagram {
+    pub payload: buf::NetBuffer,
+    pub source_addr: util::IPAddr,
+    pub source_port: u16,
+
+    /// The end of the payload was removed because it was longer than the
+    /// caller asked for.
+    pub truncated: bool,
+}
+
 /// Wait for a UDP packet to arrive on the specified socket, copy its payload
 /// into the passed slice and return the number of bytes copied.
+/// If an ICMP error was received for a packet sent from this socket since
+/// the last call, or the socket has been closed, this returns -1 instead,
+/// and udp_get_error describes it.
 pub fn udp_recv(
     socket_ref: &mut SocketReference,
     data: &mut [u8],
     out_addr: &mut util::IPAddr,
     out_port: &mut u16,
 ) -> i32 {
+    match udp_recv_timeout(socket_ref, data, out_addr, out_port, None) {
+        RecvResult::Data(len) => len as i32,
+        _ => -1,
+    }
+}
+
+/// Like udp_recv, but give up if nothing arrives within timeout ms (or wait
+/// forever if it is None).
+pub fn udp_recv_timeout(
+    socket_ref: &mut SocketReference,
+    data: &mut [u8],
+    out_addr: &mut util::IPAddr,
+    out_port: &mut u16,
+    timeout: Option<u32>,
+) -> RecvResult {
+    let result = udp_recv_buffer(socket_ref, data.len(), timeout);
+    copy_datagram(result, data, out_addr, out_port)
+}
+
+/// Receive a packet if one is waiting, otherwise return WouldBlock
+/// immediately.
+pub fn udp_try_recv(
+    socket_ref: &mut SocketReference,
+    data: &mut [u8],
+    out_addr: &mut util::IPAddr,
+    out_port: &mut u16,
+) -> RecvResult {
+    let (mut guard, _) = (*socket_ref).lock();
+    let result = guard
+        .try_recv(data.len())
+        .unwrap_or(Err(RecvResult::WouldBlock));
+    drop(guard);
+
+    copy_datagram(result, data, out_addr, out_port)
+}
+
+fn copy_datagram(
+    result: Result<Datagram, RecvResult>,
+    data: &mut [u8],
+    out_addr: &mut util::IPAddr,
+    out_port: &mut u16,
+) -> RecvResult {
+    match result {
+        Ok(datagram) => {
+            let len = datagram.payload.len();
+            datagram.payload.copy_to_slice(&mut data[..len]);
+            *out_addr = datagram.source_addr;
+            *out_port = datagram.source_port;
+            RecvResult::Data(len)
+        }
+
+        Err(result) => result,
+    }
+}
+
+/// Wait for a UDP packet and return its payload buffer directly, without
+/// copying it. If the payload is longer than max_len, the end is removed
+/// and the truncated flag is set (pass usize::MAX to always get all of it).
+/// This gives up after timeout ms, or waits forever if it is None. The
+/// error is any result other than Data.
+pub fn udp_recv_buffer(
+    socket_ref: &mut SocketReference,
+    max_len: usize,
+    timeout: Option<u32>,
+) -> Result<Datagram, RecvResult> {
+    wait_for_datagram(socket_ref, max_len, timeout).1
+}
+
+/// Wait for UDP packets like udp_recv_buffer, then return up to max_count of
+/// them, all removed from the queue while holding the socket lock once. This
+/// only waits for the first; the rest are whatever is already queued. An
+/// error or close that happens after the first packet is reported by the
+/// next call.
+pub fn udp_recv_batch(
+    socket_ref: &mut SocketReference,
+    max_count: usize,
+    max_len: usize,
+    timeout: Option<u32>,
+) -> Result<Vec<Datagram>, RecvResult> {
+    if max_count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let (mut guard, first) = wait_for_datagram(socket_ref, max_len, timeout);
+    let mut datagrams = vec![first?];
+    while datagrams.len() < max_count && !guard.closed && !guard.error_pending {
+        match guard.try_recv(max_len) {
+            Some(Ok(datagram)) => datagrams.push(datagram),
+            _ => break,
+        }
+    }
+
+    Ok(datagrams)
+}
+
+// The lock is still held when this returns, so the caller can take more
+// packets from the queue.
+fn wait_for_datagram(
+    socket_ref: &SocketReference,
+    max_len: usize,
+    timeout: Option<u32>,
+) -> (MutexGuard<'_, UDPSocketState>, Result<Datagram, RecvResult>) {
+    let deadline = timeout.map(|ms| Instant::now() + Duration::from_millis(ms as u64));
     let (mut guard, cond) = (*socket_ref).lock();
 
     loop {
-        let entry = guard.receive_queue.pop_front();
-        if entry.is_some() {
-            let (source_addr, source_port, buf) = entry.unwrap();
-            *out_addr = source_addr;
-            *out_port = source_port;
-            let len = buf.len();
-            let copy_len = std::cmp::min(len, data.len());
-            buf.copy_to_slice(&mut data[..copy_len]);
-            return copy_len as i32;
+        if let Some(result) = guard.try_recv(max_len) {
+            return (guard, result);
         }
 
         // Need to wait for data
-        guard = cond.wait(guard).unwrap();
+        guard = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return (guard, Err(RecvResult::TimedOut));
+                }
+
+                cond.wait_timeout(guard, remaining).unwrap().0
+            }
+
+            None => cond.wait(guard).unwrap(),
+        };
     }
 }
 
+/// Check if a socket would receive a packet from the given remote address
+/// and port to the given local address and port.
+pub fn udp_has_socket(
+    remote_addr: util::IPAddr,
+    remote_port: u16,
+    local_addr: util::IPAddr,
+    local_port: u16,
+) -> bool {
+    find_socket(remote_addr, remote_port, local_addr, local_port).is_some()
+}
+
 /// Send a UDP packet to the specified destination address and port.
+/// Sending to a broadcast address requires enabling it with
+/// udp_set_broadcast first.
 pub fn udp_send(
     socket_ref: &mut SocketReference,
     dest_addr: util::IPAddr,
     dest_port: u16,
     data: &[u8],
 ) -> Result<(), &'static str> {
-    let (guard, _) = (*socket_ref).lock();
-
     let mut packet = buf::NetBuffer::new();
     packet.append_from_slice(data);
-    udp_output(packet, dest_addr, guard.port, dest_port);
+    udp_send_buffer(socket_ref, dest_addr, dest_port, packet)
+}
+
+/// Like udp_send, but takes ownership of a buffer containing the payload.
+/// The UDP and IP headers are added to the front of it, so the data isn't
+/// copied again.
+pub fn udp_send_buffer(
+    socket_ref: &mut SocketReference,
+    dest_addr: util::IPAddr,
+    dest_port: u16,
+    mut packet: buf::NetBuffer,
+) -> Result<(), &'static str> {
+    let (guard, _) = (*socket_ref).lock();
+    let (metadata, loop_back) = guard.prepare_send(dest_addr, packet.len())?;
+    let local_port = guard.local_port;
+    drop(guard); // The packet may be looped back to this socket
+
+    if loop_back {
+        loop_back_packet(&packet, local_port, dest_addr, dest_port, &metadata);
+    }
+
+    build_header(&mut packet, dest_addr, local_port, dest_port, &metadata);
+    ip::ip_output(packet, ip::PROTO_UDP, dest_addr, &metadata);
+
+    Ok(())
+}
+
+/// Send several UDP packets, each with its own destination address and
+/// port. The socket lock is only taken once and the packets are written to
+/// the interface together, which is faster than calling udp_send_buffer for
+/// each one. If a packet can't be sent, the ones before it are still sent
+/// and the rest are discarded. This returns how many were sent (which may
+/// also be fewer if writing to the interface fails), or the error if the
+/// first one couldn't be sent.
+pub fn udp_send_batch(
+    socket_ref: &mut SocketReference,
+    datagrams: Vec<(util::IPAddr, u16, buf::NetBuffer)>,
+) -> Result<usize, &'static str> {
+    let (guard, _) = (*socket_ref).lock();
+    let local_port = guard.local_port;
+    let mut packets = Vec::with_capacity(datagrams.len());
+    let mut loop_backs = Vec::new();
+    for (dest_addr, dest_port, packet) in datagrams {
+        let (metadata, loop_back) = match guard.prepare_send(dest_addr, packet.len()) {
+            Ok(result) => result,
+            Err(msg) if packets.is_empty() => return Err(msg),
+            Err(_) => break,
+        };
+
+        if loop_back {
+            loop_backs.push(packets.len());
+        }
+
+        packets.push((packet, dest_addr, dest_port, metadata));
+    }
+
+    drop(guard);
+
+    for index in loop_backs {
+        let (packet, dest_addr, dest_port, metadata) = &packets[index];
+        loop_back_packet(packet, local_port, *dest_addr, *dest_port, metadata);
+    }
+
+    let packets = packets
+        .into_iter()
+        .map(|(mut packet, dest_addr, dest_port, metadata)| {
+            build_header(&mut packet, dest_addr, local_port, dest_port, &metadata);
+            (packet, dest_addr, metadata)
+        })
+        .collect();
+    Ok(ip::ip_output_batch(packets, ip::PROTO_UDP))
+}
+
+// Deliver a copy of an outgoing multicast packet to local sockets in the
+// group.
+fn loop_back_packet(
+    packet: &buf::NetBuffer,
+    local_port: u16,
+    dest_addr: util::IPAddr,
+    dest_port: u16,
+    metadata: &ip::IPMetadata,
+) {
+    let mut copy = buf::NetBuffer::new();
+    copy.append_from_buffer(packet, usize::MAX);
+    let source_addr = ip::get_source_addr(dest_addr, metadata);
+    deliver(copy, source_addr, local_port, dest_addr, dest_port);
+}
+
+/// Send a UDP packet to the peer set by udp_connect.
+pub fn udp_send_connected(
+    socket_ref: &mut SocketReference,
+    data: &[u8],
+) -> Result<(), &'static str> {
+    let (guard, _) = (*socket_ref).lock();
+    if !guard.is_connected() {
+        return Err("Socket not connected");
+    }
+
+    let (remote_addr, remote_port) = (guard.remote_addr, guard.remote_port);
+    drop(guard);
+
+    udp_send(socket_ref, remote_addr, remote_port, data)
+}
+
+/// Return the largest payload that can be sent to the given address in a
+/// single datagram. This is derived from the path MTU, so it may shrink if a
+/// router along the way reports that it can't forward packets that large.
+pub fn udp_max_payload(dest_addr: util::IPAddr) -> usize {
+    ip::get_path_mtu(dest_addr) - ip::header_len(dest_addr) - UDP_HEADER_LEN
+}
+
+/// Set the IP header parameters (TTL/hop limit, DSCP, ECN, and flow label)
+/// that will be used for packets sent from this socket.
+pub fn udp_set_ip_metadata(
+    socket_ref: &mut SocketReference,
+    metadata: ip::IPMetadata,
+) -> Result<(), &'static str> {
+    metadata.validate()?;
+    let (mut guard, _) = (*socket_ref).lock();
+    guard.ip_metadata = metadata;
 
     Ok(())
 }
 
+/// Return the IP header parameters currently used by this socket.
+pub fn udp_get_ip_metadata(socket_ref: &mut SocketReference) -> ip::IPMetadata {
+    let (guard, _) = (*socket_ref).lock();
+    guard.ip_metadata
+}
+
+/// Set the most payload bytes that can be waiting in this socket's receive
+/// queue. Datagrams that arrive when they wouldn't fit are dropped. If the
+/// queue is already over the new size, nothing is discarded, but new
+/// datagrams are dropped until it drains.
+pub fn udp_set_receive_buffer_size(
+    socket_ref: &mut SocketReference,
+    size: usize,
+) -> Result<(), &'static str> {
+    if size == 0 {
+        return Err("Buffer size must be non-zero");
+    }
+
+    let (mut guard, _) = (*socket_ref).lock();
+    guard.receive_buffer_size = size;
+
+    Ok(())
+}
+
+/// Return the receive queue limit in bytes.
+pub fn udp_get_receive_buffer_size(socket_ref: &mut SocketReference) -> usize {
+    let (guard, _) = (*socket_ref).lock();
+    guard.receive_buffer_size
+}
+
+/// Return how many datagrams this socket has dropped because its receive
+/// queue was full.
+pub fn udp_get_receive_drops(socket_ref: &mut SocketReference) -> u64 {
+    let (guard, _) = (*socket_ref).lock();
+    guard.receive_drops
+}
+
+/// Allow sending to the limited (255.255.255.255) or subnet directed
+/// broadcast address. This is off by default so a mistyped address doesn't
+/// go to every host on the network.
+pub fn udp_set_broadcast(socket_ref: &mut SocketReference, enabled: bool) {
+    let (mut guard, _) = (*socket_ref).lock();
+    guard.broadcast = enabled;
+}
+
+/// Set the TTL/hop limit for packets sent to multicast groups. This
+/// replaces the one in the IP metadata for those packets. The default of 1
+/// keeps them on the local network.
+pub fn udp_set_multicast_ttl(
+    socket_ref: &mut SocketReference,
+    ttl: u8,
+) -> Result<(), &'static str> {
+    if ttl == 0 {
+        return Err("TTL must be non-zero");
+    }
+
+    let (mut guard, _) = (*socket_ref).lock();
+    guard.multicast_ttl = ttl;
+
+    Ok(())
+}
+
+/// Control whether packets sent to a multicast group this host has joined
+/// are also delivered to local sockets (including this one). This is on by
+/// default.
+pub fn udp_set_multicast_loop(socket_ref: &mut SocketReference, enabled: bool) {
+    let (mut guard, _) = (*socket_ref).lock();
+    guard.multicast_loop = enabled;
+}
+
+/// Join a multicast group so packets sent to it are received. Every socket
+/// on the destination port gets a copy of each one, like broadcasts. The
+/// group is left when the socket is closed.
+pub fn udp_join_group(
+    socket_ref: &mut SocketReference,
+    group: util::IPAddr,
+) -> Result<(), &'static str> {
+    let (mut guard, _) = (*socket_ref).lock();
+    if guard.closed {
+        return Err("Socket closed");
+    }
+
+    if !guard.bind_addr.allows_family(group) {
+        return Err("Address family mismatch");
+    }
+
+    if guard.groups.contains(&group) {
+        return Err("Already a member of group");
+    }
+
+    igmp::join_group(group)?;
+    guard.groups.push(group);
+
+    Ok(())
+}
+
+/// Leave a multicast group joined with udp_join_group.
+pub fn udp_leave_group(
+    socket_ref: &mut SocketReference,
+    group: util::IPAddr,
+) -> Result<(), &'static str> {
+    let (mut guard, _) = (*socket_ref).lock();
+    let index = match guard.groups.iter().position(|member| *member == group) {
+        Some(index) => index,
+        None => return Err("Not a member of group"),
+    };
+
+    guard.groups.remove(index);
+    igmp::leave_group(group)
+}
+
+/// Return the most recent ICMP error reported for this socket (such as
+/// "Connection refused" when nothing is listening on the destination port),
+/// along with the address of the host or router that sent it. If the socket
+/// is closed, the address is unspecified.
+pub fn udp_get_error(socket_ref: &mut SocketReference) -> Option<(&'static str, util::IPAddr)> {
+    let (guard, _) = (*socket_ref).lock();
+    if guard.closed {
+        return Some(("Socket closed", util::IPAddr::new()));
+    }
+
+    guard
+        .last_error
+        .map(|error| (error.message, error.reporter))
+}
+
+/// Return the most recent ICMP error reported for this socket, including the
+/// destination of the packet that caused it. Returns None if no error has been
+/// reported or the socket is closed.
+pub fn udp_get_icmp_error(socket_ref: &mut SocketReference) -> Option<ICMPError> {
+    let (guard, _) = (*socket_ref).lock();
+    if guard.closed {
+        return None;
+    }
+
+    guard.last_error
+}
+
+/// Called by the ICMP layer when an error is received for a packet sent from
+/// the given local address and port to the given remote address and port.
+/// Wakes up any thread waiting in udp_recv.
+pub fn udp_icmp_error(
+    local_addr: util::IPAddr,
+    local_port: u16,
+    remote_addr: util::IPAddr,
+    remote_port: u16,
+    reporter: util::IPAddr,
+    message: &'static str,
+) {
+    let socket_ref = match find_socket(remote_addr, remote_port, local_addr, local_port) {
+        Some(socket_ref) => socket_ref,
+        None => return,
+    };
+
+    let (mut guard, cond) = (*socket_ref).lock();
+    guard.last_error = Some(ICMPError {
+        message,
+        reporter,
+        dest_addr: remote_addr,
+        dest_port: remote_port,
+    });
+    guard.error_pending = true;
+    cond.notify_all();
+}
+
 //    0               1               2               3
 //    +-------------------------------+-------------------------------+
 //  0 |         Source Port           |          Dest Port            |
@@ -120,26 +934,144 @@ pub fn udp_send(
 //  4 |            Length             |           Checksum            |
 //    +-------------------------------+-------------------------------+
 
-const UDP_HEADER_LEN: usize = 8;
+pub const UDP_HEADER_LEN: usize = 8;
+
+// Check the length and checksum of a received datagram, which starts
+// ip_header_len bytes into the packet, and remove any padding after the end
+// of it. Returns false if it should be dropped.
+fn validate_datagram(
+    packet: &mut buf::NetBuffer,
+    ip_header_len: usize,
+    source_addr: util::IPAddr,
+    dest_addr: util::IPAddr,
+) -> bool {
+    if packet.header().len() < ip_header_len + UDP_HEADER_LEN {
+        util::METRICS.udp_length_errors.inc();
+        return false;
+    }
+
+    let header = &packet.header()[ip_header_len..];
+    let length = util::get_be16(&header[4..6]) as usize;
+    let checksum = util::get_be16(&header[6..8]);
+    let datagram_len = packet.len() - ip_header_len;
+    if length < UDP_HEADER_LEN || length > datagram_len {
+        println!("UDP: Invalid length {}", length);
+        util::METRICS.udp_length_errors.inc();
+        return false;
+    }
+
+    if length < datagram_len {
+        packet.trim_tail(datagram_len - length);
+    }
+
+    // A zero checksum means the sender didn't compute one. This is allowed
+    // for IPv4, but not IPv6, which has no header checksum (RFC 8200, 8.1).
+    if checksum == 0 {
+        if matches!(source_addr, util::IPAddr::V6(_)) {
+            println!("UDP: Missing checksum");
+            util::METRICS.udp_checksum_errors.inc();
+            return false;
+        }
+
+        return true;
+    }
+
+    // Skip the IP header. Its length is a multiple of 4, so the 16 bit
+    // words of the datagram stay aligned.
+    let mut sum =
+        util::compute_pseudo_header_checksum(source_addr, dest_addr, length, ip::PROTO_UDP);
+    let mut skip = ip_header_len;
+    for frag in packet.iter(usize::MAX) {
+        if skip >= frag.len() {
+            skip -= frag.len();
+            continue;
+        }
+
+        sum = util::compute_ones_comp(sum, &frag[skip..]);
+        skip = 0;
+    }
+
+    if sum ^ 0xffff != 0 {
+        println!("UDP: Checksum error");
+        util::METRICS.udp_checksum_errors.inc();
+        return false;
+    }
+
+    true
+}
 
-/// Called by IP layer to handle received packets.
-pub fn udp_input(mut packet: buf::NetBuffer, source_addr: util::IPAddr) {
-    let header = packet.header();
+/// Called by IP layer to handle received packets. The packet still starts
+/// with the IP header (ip_header_len bytes). If the datagram is valid, but
+/// there is no socket to receive it, the packet is returned unchanged so
+/// the IP layer can quote it in a port unreachable error.
+pub fn udp_input(
+    mut packet: buf::NetBuffer,
+    ip_header_len: usize,
+    source_addr: util::IPAddr,
+    dest_addr: util::IPAddr,
+) -> Option<buf::NetBuffer> {
+    if !validate_datagram(&mut packet, ip_header_len, source_addr, dest_addr) {
+        return None;
+    }
+
+    let header = &packet.header()[ip_header_len..];
     let source_port = util::get_be16(&header[0..2]);
     let dest_port = util::get_be16(&header[2..4]);
-    packet.trim_head(UDP_HEADER_LEN);
+    if dest_addr.is_multicast() || ip::is_broadcast(dest_addr) {
+        packet.trim_head(ip_header_len + UDP_HEADER_LEN);
+        deliver(packet, source_addr, source_port, dest_addr, dest_port);
+        return None;
+    }
 
-    let mut port_map_guard = PORT_MAP.lock().unwrap();
-    let pm_entry = port_map_guard.get_mut(&dest_port);
-    if pm_entry.is_none() {
-        println!("No socket listening on port {}", dest_port);
-        return;
+    let socket = match find_socket(source_addr, source_port, dest_addr, dest_port) {
+        Some(socket) => socket,
+        None => {
+            println!("No socket listening on port {}", dest_port);
+            return Some(packet);
+        }
+    };
+
+    packet.trim_head(ip_header_len + UDP_HEADER_LEN);
+    enqueue(&socket, packet, source_addr, source_port);
+
+    None
+}
+
+// Queue a received broadcast or multicast payload on every socket that
+// should get it.
+fn deliver(
+    packet: buf::NetBuffer,
+    source_addr: util::IPAddr,
+    source_port: u16,
+    dest_addr: util::IPAddr,
+    dest_port: u16,
+) {
+    let sockets = find_all_sockets(source_addr, source_port, dest_addr, dest_port);
+    for socket in sockets {
+        let mut copy = buf::NetBuffer::new();
+        copy.append_from_buffer(&packet, usize::MAX);
+        enqueue(&socket, copy, source_addr, source_port);
     }
+}
 
-    let socket = pm_entry
-        .expect("just checked if pm_entry is none above")
-        .clone();
+fn enqueue(
+    socket: &SocketReference,
+    packet: buf::NetBuffer,
+    source_addr: util::IPAddr,
+    source_port: u16,
+) {
     let (mut guard, cond) = (*socket).lock();
+    if guard.closed {
+        return;
+    }
+
+    if guard.receive_queue_bytes + packet.len() > guard.receive_buffer_size {
+        guard.receive_drops += 1;
+        util::METRICS.udp_receive_drops.inc();
+        return;
+    }
+
+    guard.receive_queue_bytes += packet.len();
     guard
         .receive_queue
         .push_back((source_addr, source_port, packet));
@@ -147,7 +1079,13 @@ pub fn udp_input(mut packet: buf::NetBuffer, source_addr: util::IPAddr) {
     cond.notify_all();
 }
 
-fn udp_output(mut packet: buf::NetBuffer, dest_ip: util::IPAddr, source_port: u16, dest_port: u16) {
+fn build_header(
+    packet: &mut buf::NetBuffer,
+    dest_ip: util::IPAddr,
+    source_port: u16,
+    dest_port: u16,
+    metadata: &ip::IPMetadata,
+) {
     packet.alloc_header(UDP_HEADER_LEN);
     let length = packet.len() as u16;
     let header = packet.header_mut();
@@ -156,18 +1094,586 @@ fn udp_output(mut packet: buf::NetBuffer, dest_ip: util::IPAddr, source_port: u1
     util::set_be16(&mut header[4..6], length);
 
     let ph_checksum = util::compute_pseudo_header_checksum(
-        if matches!(dest_ip, util::IPAddr::V4(_)) {
-            netif::get_ipaddr().0
-        } else {
-            netif::get_ipaddr().1
-        },
+        ip::get_source_addr(dest_ip, metadata),
         dest_ip,
         length as usize,
         ip::PROTO_UDP,
     );
-    let checksum = util::compute_buffer_ones_comp(ph_checksum, &packet) ^ 0xffff;
+    let mut checksum = util::compute_buffer_ones_comp(ph_checksum, packet) ^ 0xffff;
+
+    // Zero means no checksum, so send the equivalent ones' complement value
+    // instead (RFC 768).
+    if checksum == 0 {
+        checksum = 0xffff;
+    }
 
     let header = packet.header_mut();
     util::set_be16(&mut header[6..8], checksum);
-    ip::ip_output(packet, ip::PROTO_UDP, dest_ip);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE_V4: util::IPAddr = util::IPAddr::V4([10, 0, 0, 1]);
+    const DEST_V4: util::IPAddr = util::IPAddr::V4([10, 0, 0, 2]);
+    const SOURCE_V6: util::IPAddr =
+        util::IPAddr::V6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    const DEST_V6: util::IPAddr =
+        util::IPAddr::V6([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+
+    fn make_datagram(
+        source_addr: util::IPAddr,
+        dest_addr: util::IPAddr,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        make_datagram_to_port(source_addr, dest_addr, 5678, payload)
+    }
+
+    fn make_datagram_to_port(
+        source_addr: util::IPAddr,
+        dest_addr: util::IPAddr,
+        dest_port: u16,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let length = UDP_HEADER_LEN + payload.len();
+        let mut data = vec![0u8; UDP_HEADER_LEN];
+        util::set_be16(&mut data[0..2], 1234);
+        util::set_be16(&mut data[2..4], dest_port);
+        util::set_be16(&mut data[4..6], length as u16);
+        data.extend_from_slice(payload);
+
+        let ph_checksum =
+            util::compute_pseudo_header_checksum(source_addr, dest_addr, length, ip::PROTO_UDP);
+        let checksum = util::compute_ones_comp(ph_checksum, &data) ^ 0xffff;
+        util::set_be16(&mut data[6..8], checksum);
+        data
+    }
+
+    fn make_buffer(data: &[u8]) -> buf::NetBuffer {
+        let mut packet = buf::NetBuffer::new();
+        packet.append_from_slice(data);
+        packet
+    }
+
+    fn validate(data: &[u8], source_addr: util::IPAddr, dest_addr: util::IPAddr) -> Option<usize> {
+        let mut packet = make_buffer(data);
+        if validate_datagram(&mut packet, 0, source_addr, dest_addr) {
+            Some(packet.len())
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn test_valid_checksum() {
+        let data = make_datagram(SOURCE_V4, DEST_V4, b"hello");
+        assert_eq!(validate(&data, SOURCE_V4, DEST_V4), Some(13));
+
+        let data = make_datagram(SOURCE_V6, DEST_V6, b"hello");
+        assert_eq!(validate(&data, SOURCE_V6, DEST_V6), Some(13));
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let mut data = make_datagram(SOURCE_V4, DEST_V4, b"hello");
+        data[9] ^= 1;
+        assert_eq!(validate(&data, SOURCE_V4, DEST_V4), None);
+
+        // Pseudo header is covered
+        let data = make_datagram(SOURCE_V4, DEST_V4, b"hello");
+        assert_eq!(validate(&data, DEST_V4, SOURCE_V4), Some(13));
+        assert_eq!(
+            validate(&data, util::IPAddr::V4([10, 0, 0, 3]), DEST_V4),
+            None
+        );
+    }
+
+    #[test]
+    fn test_zero_checksum() {
+        let mut data = make_datagram(SOURCE_V4, DEST_V4, b"hello");
+        data[6] = 0;
+        data[7] = 0;
+        assert_eq!(validate(&data, SOURCE_V4, DEST_V4), Some(13));
+
+        let mut data = make_datagram(SOURCE_V6, DEST_V6, b"hello");
+        data[6] = 0;
+        data[7] = 0;
+        assert_eq!(validate(&data, SOURCE_V6, DEST_V6), None);
+    }
+
+    #[test]
+    fn test_length() {
+        // Padding after the datagram is removed
+        let mut data = make_datagram(SOURCE_V4, DEST_V4, b"hello");
+        data.extend_from_slice(&[0, 0, 0]);
+        assert_eq!(validate(&data, SOURCE_V4, DEST_V4), Some(13));
+
+        // Length is larger than the packet
+        let data = make_datagram(SOURCE_V4, DEST_V4, b"hello");
+        assert_eq!(validate(&data[..12], SOURCE_V4, DEST_V4), None);
+
+        // Length is smaller than the header
+        let mut data = make_datagram(SOURCE_V4, DEST_V4, b"");
+        util::set_be16(&mut data[4..6], 7);
+        assert_eq!(validate(&data, SOURCE_V4, DEST_V4), None);
+
+        assert_eq!(validate(&[0; 4], SOURCE_V4, DEST_V4), None);
+    }
+
+    #[test]
+    fn test_close() {
+        let mut socket = udp_open(61001).unwrap();
+        assert!(udp_open(61001).is_err());
+        assert!(udp_has_socket(SOURCE_V4, 1234, DEST_V4, 61001));
+
+        udp_close(&mut socket);
+        assert!(udp_is_closed(&mut socket));
+        assert!(!udp_has_socket(SOURCE_V4, 1234, DEST_V4, 61001));
+        assert_eq!(
+            udp_recv(
+                &mut socket,
+                &mut [0u8; 16],
+                &mut util::IPAddr::new(),
+                &mut 0
+            ),
+            -1
+        );
+        assert_eq!(udp_get_error(&mut socket).unwrap().0, "Socket closed");
+
+        // Dropping the closed socket must not release the new one.
+        let _socket2 = udp_open(61001).unwrap();
+        drop(socket);
+        assert!(udp_has_socket(SOURCE_V4, 1234, DEST_V4, 61001));
+    }
+
+    #[test]
+    fn test_release_on_drop() {
+        let socket = udp_open(61002).unwrap();
+        let clone = socket.clone();
+        drop(socket);
+        assert!(udp_has_socket(SOURCE_V4, 1234, DEST_V4, 61002));
+
+        drop(clone);
+        assert!(!udp_has_socket(SOURCE_V4, 1234, DEST_V4, 61002));
+        assert!(udp_open(61002).is_ok());
+    }
+
+    #[test]
+    fn test_close_wakes_receiver() {
+        let socket = udp_open(61003).unwrap();
+        let mut recv_socket = socket.clone();
+        let thread = std::thread::spawn(move || {
+            udp_recv(
+                &mut recv_socket,
+                &mut [0u8; 16],
+                &mut util::IPAddr::new(),
+                &mut 0,
+            )
+        });
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        udp_close(&mut socket.clone());
+        assert_eq!(thread.join().unwrap(), -1);
+    }
+
+    #[test]
+    fn test_ephemeral_port() {
+        let mut socket1 = udp_open(0).unwrap();
+        let mut socket2 = udp_open(0).unwrap();
+        let port1 = udp_get_local_port(&mut socket1);
+        let port2 = udp_get_local_port(&mut socket2);
+        assert!(port1 >= EPHEMERAL_PORT_BASE);
+        assert!(port2 >= EPHEMERAL_PORT_BASE);
+        assert_ne!(port1, port2);
+        assert!(udp_open(port1).is_err());
+    }
+
+    #[test]
+    fn test_connect() {
+        // Port 5678 is the destination in make_datagram
+        let other_v4 = util::IPAddr::V4([10, 0, 0, 3]);
+        let mut connected = udp_open(5678).unwrap();
+        assert!(udp_send_connected(&mut connected, b"hello").is_err());
+        assert!(udp_connect(&mut connected, util::IPAddr::new(), 1234).is_err());
+
+        // Packet from another peer queued before connecting is discarded
+        udp_input(
+            make_buffer(&make_datagram(other_v4, DEST_V4, b"early")),
+            0,
+            other_v4,
+            DEST_V4,
+        );
+        udp_connect(&mut connected, SOURCE_V4, 1234).unwrap();
+
+        // Port can now be shared with a socket that isn't connected
+        let mut wildcard = udp_open(5678).unwrap();
+        assert!(udp_has_socket(SOURCE_V4, 1234, DEST_V4, 5678));
+        assert!(udp_has_socket(other_v4, 1234, DEST_V4, 5678));
+
+        udp_input(
+            make_buffer(&make_datagram(other_v4, DEST_V4, b"other")),
+            0,
+            other_v4,
+            DEST_V4,
+        );
+        udp_input(
+            make_buffer(&make_datagram(SOURCE_V4, DEST_V4, b"peer")),
+            0,
+            SOURCE_V4,
+            DEST_V4,
+        );
+
+        let mut data = [0u8; 16];
+        let mut addr = util::IPAddr::new();
+        let mut port = 0;
+        let len = udp_recv(&mut connected, &mut data, &mut addr, &mut port);
+        assert_eq!(&data[..len as usize], b"peer");
+        assert_eq!(addr, SOURCE_V4);
+
+        let len = udp_recv(&mut wildcard, &mut data, &mut addr, &mut port);
+        assert_eq!(&data[..len as usize], b"other");
+        assert_eq!(addr, other_v4);
+
+        // Closing the connected socket leaves the other one bound
+        udp_close(&mut connected);
+        assert!(udp_has_socket(SOURCE_V4, 1234, DEST_V4, 5678));
+        udp_close(&mut wildcard);
+        assert!(!udp_has_socket(SOURCE_V4, 1234, DEST_V4, 5678));
+    }
+
+    #[test]
+    fn test_bind() {
+        let mut socket_v4 = udp_open_bind(util::BindAddr::AnyV4, 5679).unwrap();
+        let mut socket_v6 = udp_open_bind(util::BindAddr::AnyV6, 5679).unwrap();
+        assert!(udp_open_bind(util::BindAddr::AnyV6, 5679).is_err());
+        assert!(
+            udp_open_bind(util::BindAddr::Addr(util::IPAddr::V4([192, 0, 2, 1])), 5679).is_err()
+        );
+        assert!(udp_connect(&mut socket_v4, SOURCE_V6, 1234).is_err());
+        assert!(udp_send(&mut socket_v6, SOURCE_V4, 1234, b"hello").is_err());
+
+        let mut data = [0u8; 16];
+        let mut addr = util::IPAddr::new();
+        let mut port = 0;
+        let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, 5679, b"v4");
+        udp_input(make_buffer(&datagram), 0, SOURCE_V4, DEST_V4);
+        let datagram = make_datagram_to_port(SOURCE_V6, DEST_V6, 5679, b"v6");
+        udp_input(make_buffer(&datagram), 0, SOURCE_V6, DEST_V6);
+
+        let len = udp_recv(&mut socket_v4, &mut data, &mut addr, &mut port);
+        assert_eq!(&data[..len as usize], b"v4");
+        let len = udp_recv(&mut socket_v6, &mut data, &mut addr, &mut port);
+        assert_eq!(&data[..len as usize], b"v6");
+
+        // A dual stack socket gets whatever the more specific ones don't
+        let socket_any = udp_open(5679).unwrap();
+        let found = find_socket(SOURCE_V4, 1234, DEST_V4, 5679).unwrap();
+        assert!(Arc::ptr_eq(&found, &socket_v4));
+        udp_close(&mut socket_v4);
+        let found = find_socket(SOURCE_V4, 1234, DEST_V4, 5679).unwrap();
+        assert!(Arc::ptr_eq(&found, &socket_any));
+        let found = find_socket(SOURCE_V6, 1234, DEST_V6, 5679).unwrap();
+        assert!(Arc::ptr_eq(&found, &socket_v6));
+    }
+
+    #[test]
+    fn test_broadcast_delivery() {
+        let broadcast_addr = util::IPAddr::V4([255, 255, 255, 255]);
+        let all_hosts = util::IPAddr::V4([224, 0, 0, 1]);
+        let other_v4 = util::IPAddr::V4([10, 0, 0, 3]);
+        let mut socket_any = udp_open(5680).unwrap();
+        let mut socket_v4 = udp_open_bind(util::BindAddr::AnyV4, 5680).unwrap();
+        let mut socket_v6 = udp_open_bind(util::BindAddr::AnyV6, 5680).unwrap();
+        let mut connected = udp_open(0).unwrap();
+        let connected_port = udp_get_local_port(&mut connected);
+        udp_connect(&mut connected, other_v4, 1234).unwrap();
+
+        assert_eq!(
+            udp_send(&mut socket_any, broadcast_addr, 5680, b"hello"),
+            Err("Broadcast not enabled")
+        );
+        assert!(udp_set_multicast_ttl(&mut socket_any, 0).is_err());
+
+        let datagram = make_datagram_to_port(SOURCE_V4, broadcast_addr, 5680, b"broadcast");
+        udp_input(make_buffer(&datagram), 0, SOURCE_V4, broadcast_addr);
+        let datagram = make_datagram_to_port(SOURCE_V4, all_hosts, 5680, b"multicast");
+        udp_input(make_buffer(&datagram), 0, SOURCE_V4, all_hosts);
+
+        // Connected sockets only get packets from their peer.
+        let datagram = make_datagram_to_port(SOURCE_V4, all_hosts, connected_port, b"wrong");
+        udp_input(make_buffer(&datagram), 0, SOURCE_V4, all_hosts);
+        let datagram = make_datagram_to_port(other_v4, all_hosts, connected_port, b"peer");
+        udp_input(make_buffer(&datagram), 0, other_v4, all_hosts);
+
+        let mut data = [0u8; 16];
+        let mut addr = util::IPAddr::new();
+        let mut port = 0;
+        for socket in [&mut socket_any, &mut socket_v4] {
+            let len = udp_recv(socket, &mut data, &mut addr, &mut port);
+            assert_eq!(&data[..len as usize], b"broadcast");
+            let len = udp_recv(socket, &mut data, &mut addr, &mut port);
+            assert_eq!(&data[..len as usize], b"multicast");
+        }
+
+        let len = udp_recv(&mut connected, &mut data, &mut addr, &mut port);
+        assert_eq!(&data[..len as usize], b"peer");
+
+        let (guard, _) = socket_v6.lock();
+        assert!(guard.receive_queue.is_empty());
+        drop(guard);
+        udp_close(&mut socket_v6);
+    }
+
+    #[test]
+    fn test_receive_buffer_limit() {
+        let mut socket = udp_open(5681).unwrap();
+        assert_eq!(
+            udp_get_receive_buffer_size(&mut socket),
+            DEFAULT_RECEIVE_BUFFER_SIZE
+        );
+        assert!(udp_set_receive_buffer_size(&mut socket, 0).is_err());
+        udp_set_receive_buffer_size(&mut socket, 10).unwrap();
+        assert_eq!(udp_get_receive_buffer_size(&mut socket), 10);
+
+        for payload in [&b"hello"[..], b"world", b"!"] {
+            let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, 5681, payload);
+            udp_input(make_buffer(&datagram), 0, SOURCE_V4, DEST_V4);
+        }
+
+        assert_eq!(udp_get_receive_drops(&mut socket), 1);
+
+        // Reading frees up space
+        let mut data = [0u8; 16];
+        let mut addr = util::IPAddr::new();
+        let mut port = 0;
+        let len = udp_recv(&mut socket, &mut data, &mut addr, &mut port);
+        assert_eq!(&data[..len as usize], b"hello");
+
+        let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, 5681, b"again");
+        udp_input(make_buffer(&datagram), 0, SOURCE_V4, DEST_V4);
+        assert_eq!(udp_get_receive_drops(&mut socket), 1);
+
+        let len = udp_recv(&mut socket, &mut data, &mut addr, &mut port);
+        assert_eq!(&data[..len as usize], b"world");
+        let len = udp_recv(&mut socket, &mut data, &mut addr, &mut port);
+        assert_eq!(&data[..len as usize], b"again");
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let mut socket = udp_open(0).unwrap();
+        let port = udp_get_local_port(&mut socket);
+        let mut data = [0u8; 16];
+        let mut addr = util::IPAddr::new();
+        let mut source_port = 0;
+        assert_eq!(
+            udp_try_recv(&mut socket, &mut data, &mut addr, &mut source_port),
+            RecvResult::WouldBlock
+        );
+
+        let start = Instant::now();
+        assert_eq!(
+            udp_recv_timeout(
+                &mut socket,
+                &mut data,
+                &mut addr,
+                &mut source_port,
+                Some(50)
+            ),
+            RecvResult::TimedOut
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, port, b"hello");
+        udp_input(make_buffer(&datagram), 0, SOURCE_V4, DEST_V4);
+        assert_eq!(
+            udp_try_recv(&mut socket, &mut data, &mut addr, &mut source_port),
+            RecvResult::Data(5)
+        );
+
+        udp_icmp_error(
+            DEST_V4,
+            port,
+            SOURCE_V4,
+            1234,
+            SOURCE_V4,
+            "Connection refused",
+        );
+        assert_eq!(
+            udp_recv_timeout(
+                &mut socket,
+                &mut data,
+                &mut addr,
+                &mut source_port,
+                Some(50)
+            ),
+            RecvResult::Error
+        );
+        assert_eq!(
+            udp_get_icmp_error(&mut socket),
+            Some(ICMPError {
+                message: "Connection refused",
+                reporter: SOURCE_V4,
+                dest_addr: SOURCE_V4,
+                dest_port: 1234,
+            })
+        );
+
+        udp_close(&mut socket);
+        assert_eq!(
+            udp_recv_timeout(&mut socket, &mut data, &mut addr, &mut source_port, None),
+            RecvResult::Closed
+        );
+        assert_eq!(
+            udp_try_recv(&mut socket, &mut data, &mut addr, &mut source_port),
+            RecvResult::Closed
+        );
+    }
+
+    #[test]
+    fn test_recv_buffer() {
+        let mut socket = udp_open(0).unwrap();
+        let port = udp_get_local_port(&mut socket);
+        for payload in [&b"hello world"[..], b"hello world", b"hello world"] {
+            let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, port, payload);
+            udp_input(make_buffer(&datagram), 0, SOURCE_V4, DEST_V4);
+        }
+
+        let datagram = udp_recv_buffer(&mut socket, usize::MAX, Some(0))
+            .ok()
+            .unwrap();
+        assert_eq!(datagram.payload.len(), 11);
+        assert_eq!(datagram.source_addr, SOURCE_V4);
+        assert_eq!(datagram.source_port, 1234);
+        assert!(!datagram.truncated);
+
+        let datagram = udp_recv_buffer(&mut socket, 5, Some(0)).ok().unwrap();
+        let mut data = [0u8; 5];
+        datagram.payload.copy_to_slice(&mut data);
+        assert_eq!(datagram.payload.len(), 5);
+        assert_eq!(&data, b"hello");
+        assert!(datagram.truncated);
+
+        // The slice versions truncate too
+        let mut data = [0u8; 5];
+        let mut addr = util::IPAddr::new();
+        let mut source_port = 0;
+        assert_eq!(
+            udp_try_recv(&mut socket, &mut data, &mut addr, &mut source_port),
+            RecvResult::Data(5)
+        );
+
+        assert!(matches!(
+            udp_recv_buffer(&mut socket, usize::MAX, Some(0)),
+            Err(RecvResult::TimedOut)
+        ));
+    }
+
+    #[test]
+    fn test_input_with_ip_header() {
+        let mut ip_header = vec![0x45u8];
+        ip_header.resize(20, 0xcc);
+        let with_header = |datagram: &[u8]| {
+            let mut data = ip_header.clone();
+            data.extend_from_slice(datagram);
+            make_buffer(&data)
+        };
+
+        // Nothing is listening, so the whole packet comes back for the
+        // port unreachable error.
+        let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, 61005, b"hello");
+        let packet = udp_input(with_header(&datagram), 20, SOURCE_V4, DEST_V4).unwrap();
+        let mut data = [0u8; 33];
+        assert_eq!(packet.len(), 33);
+        packet.copy_to_slice(&mut data);
+        assert_eq!(&data[..20], &ip_header[..]);
+        assert_eq!(&data[20..], &datagram[..]);
+
+        // Corrupted packets are dropped without an error
+        let mut bad_datagram = datagram.clone();
+        bad_datagram[8] ^= 1;
+        assert!(udp_input(with_header(&bad_datagram), 20, SOURCE_V4, DEST_V4).is_none());
+
+        let mut socket = udp_open(61004).unwrap();
+        let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, 61004, b"hello");
+        assert!(udp_input(with_header(&datagram), 20, SOURCE_V4, DEST_V4).is_none());
+        let datagram = udp_recv_buffer(&mut socket, usize::MAX, Some(0))
+            .ok()
+            .unwrap();
+        assert_eq!(datagram.payload.len(), 5);
+    }
+
+    #[test]
+    fn test_recv_batch() {
+        let mut socket = udp_open(0).unwrap();
+        let port = udp_get_local_port(&mut socket);
+        for payload in [&b"one"[..], b"two", b"three"] {
+            let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, port, payload);
+            udp_input(make_buffer(&datagram), 0, SOURCE_V4, DEST_V4);
+        }
+
+        let datagrams = udp_recv_batch(&mut socket, 2, usize::MAX, Some(0)).unwrap();
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].payload.len(), 3);
+        assert_eq!(datagrams[1].payload.len(), 3);
+        assert_eq!(datagrams[1].source_port, 1234);
+
+        // Returns fewer than asked for rather than waiting for more
+        let datagrams = udp_recv_batch(&mut socket, 8, 4, Some(0)).unwrap();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].payload.len(), 4);
+        assert!(datagrams[0].truncated);
+
+        assert!(matches!(
+            udp_recv_batch(&mut socket, 8, usize::MAX, Some(0)),
+            Err(RecvResult::TimedOut)
+        ));
+
+        // A close after the first packet is reported by the next call
+        let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, port, b"four");
+        udp_input(make_buffer(&datagram), 0, SOURCE_V4, DEST_V4);
+        let datagram = make_datagram_to_port(SOURCE_V4, DEST_V4, port, b"five");
+        udp_input(make_buffer(&datagram), 0, SOURCE_V4, DEST_V4);
+        let (mut guard, _) = socket.lock();
+        assert_eq!(
+            guard
+                .try_recv(usize::MAX)
+                .unwrap()
+                .ok()
+                .unwrap()
+                .payload
+                .len(),
+            4
+        );
+        guard.closed = true;
+        drop(guard);
+        assert!(matches!(
+            udp_recv_batch(&mut socket, 8, usize::MAX, Some(0)),
+            Err(RecvResult::Closed)
+        ));
+    }
+
+    #[test]
+    fn test_send_batch_error() {
+        let broadcast_addr = util::IPAddr::V4([255, 255, 255, 255]);
+        let mut socket = udp_open(0).unwrap();
+        assert_eq!(
+            udp_send_batch(
+                &mut socket,
+                vec![
+                    (broadcast_addr, 1234, make_buffer(b"hello")),
+                    (DEST_V4, 1234, make_buffer(b"hello")),
+                ]
+            ),
+            Err("Broadcast not enabled")
+        );
+        assert_eq!(udp_send_batch(&mut socket, Vec::new()), Ok(0));
+
+        udp_close(&mut socket);
+        assert_eq!(
+            udp_send_batch(&mut socket, vec![(DEST_V4, 1234, make_buffer(b"hello"))]),
+            Err("Socket closed")
+        );
+    }
 }
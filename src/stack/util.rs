@@ -48,6 +48,29 @@ impl IPAddr {
         }
     }
 
+    pub fn is_multicast(&self) -> bool {
+        match self {
+            IPAddr::V4(addr) => (addr[0] & 0xf0) == 0xe0, // 224.0.0.0/4
+            IPAddr::V6(addr) => addr[0] == 0xff,          // ff00::/8
+        }
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        match self {
+            IPAddr::V4(addr) => *addr == [0; 4],
+            IPAddr::V6(addr) => *addr == [0; 16],
+        }
+    }
+
+    /// Parse an address in the usual text form (e.g. "10.0.0.1" or
+    /// "fe80::1"). Returns None if it isn't a valid address.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.parse::<std::net::IpAddr>().ok()? {
+            std::net::IpAddr::V4(addr) => Some(IPAddr::V4(addr.octets())),
+            std::net::IpAddr::V6(addr) => Some(IPAddr::V6(addr.octets())),
+        }
+    }
+
     pub fn copy_to(&self, buffer: &mut [u8]) {
         match self {
             IPAddr::V4(addr) => buffer.copy_from_slice(addr),
@@ -56,6 +79,59 @@ impl IPAddr {
     }
 }
 
+/// Local address a socket is bound to. This determines which incoming
+/// packets it will receive, based on their destination address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BindAddr {
+    /// Any local address, IPv4 or IPv6
+    Any,
+
+    /// Any local IPv4 address
+    AnyV4,
+
+    /// Any local IPv6 address, but not IPv4 (like the IPV6_V6ONLY option)
+    AnyV6,
+
+    /// One specific local address
+    Addr(IPAddr),
+}
+
+impl BindAddr {
+    /// Convert from the form used by the sockets API, where an unspecified
+    /// address means any address of that family. Binding to the unspecified
+    /// IPv6 address also accepts IPv4 packets unless v6_only is set.
+    pub fn new(addr: IPAddr, v6_only: bool) -> Self {
+        match addr {
+            IPAddr::V4(_) if addr.is_unspecified() => BindAddr::AnyV4,
+            IPAddr::V6(_) if addr.is_unspecified() && v6_only => BindAddr::AnyV6,
+            IPAddr::V6(_) if addr.is_unspecified() => BindAddr::Any,
+            _ => BindAddr::Addr(addr),
+        }
+    }
+
+    /// Bindings that would receive a packet sent to dest_addr, with the
+    /// most specific first.
+    pub fn lookup_order(dest_addr: IPAddr) -> [BindAddr; 3] {
+        match dest_addr {
+            IPAddr::V4(_) => [BindAddr::Addr(dest_addr), BindAddr::AnyV4, BindAddr::Any],
+            IPAddr::V6(_) => [BindAddr::Addr(dest_addr), BindAddr::AnyV6, BindAddr::Any],
+        }
+    }
+
+    /// Check if a socket with this binding can communicate with a remote
+    /// host at the given address (that is, if it's the same family).
+    pub fn allows_family(&self, addr: IPAddr) -> bool {
+        match self {
+            BindAddr::Any => true,
+            BindAddr::AnyV4 => matches!(addr, IPAddr::V4(_)),
+            BindAddr::AnyV6 => matches!(addr, IPAddr::V6(_)),
+            BindAddr::Addr(local_addr) => {
+                matches!(local_addr, IPAddr::V4(_)) == matches!(addr, IPAddr::V4(_))
+            }
+        }
+    }
+}
+
 impl Default for IPAddr {
     fn default() -> Self {
         IPAddr::new()
@@ -236,6 +312,9 @@ pub struct Metrics {
     pub packets_received: PerfCounter,
     pub packets_sent: PerfCounter,
     pub packets_retransmitted: PerfCounter,
+    pub udp_checksum_errors: PerfCounter,
+    pub udp_length_errors: PerfCounter,
+    pub udp_receive_drops: PerfCounter,
     pub buffers_allocated: PerfCounter,
     pub buffers_freed: PerfCounter,
     pub buffers_created: PerfCounter,
@@ -245,6 +324,9 @@ pub static METRICS: Metrics = Metrics {
     packets_received: PerfCounter::new(),
     packets_sent: PerfCounter::new(),
     packets_retransmitted: PerfCounter::new(),
+    udp_checksum_errors: PerfCounter::new(),
+    udp_length_errors: PerfCounter::new(),
+    udp_receive_drops: PerfCounter::new(),
     buffers_allocated: PerfCounter::new(),
     buffers_freed: PerfCounter::new(),
     buffers_created: PerfCounter::new(),
@@ -258,6 +340,9 @@ pub fn print_metrics() {
         "Packets retransmitted: {}",
         METRICS.packets_retransmitted.get()
     );
+    println!("UDP checksum errors: {}", METRICS.udp_checksum_errors.get());
+    println!("UDP length errors: {}", METRICS.udp_length_errors.get());
+    println!("UDP receive drops: {}", METRICS.udp_receive_drops.get());
     println!("Buffers allocated: {}", METRICS.buffers_allocated.get());
     println!("Buffers freed: {}", METRICS.buffers_freed.get());
     println!("Buffers created: {}", METRICS.buffers_created.get());
@@ -272,6 +357,7 @@ pub fn print_metrics() {
     );
 }
 
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     #[test]
     fn test_compute_ones_comp() {
@@ -397,6 +483,38 @@ mod tests {
         assert_eq!(buffer, [192, 168, 1, 1]);
     }
 
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            super::IPAddr::parse("10.0.0.1"),
+            Some(super::IPAddr::new_from(&[10, 0, 0, 1]))
+        );
+        assert_eq!(
+            super::IPAddr::parse("fe80::2"),
+            Some(super::IPAddr::new_from(&[
+                0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2
+            ]))
+        );
+        assert_eq!(super::IPAddr::parse("example.com"), None);
+        assert_eq!(super::IPAddr::parse("10.0.0.256"), None);
+    }
+
+    #[test]
+    fn test_is_multicast() {
+        assert!(super::IPAddr::new_from(&[224, 0, 0, 1]).is_multicast());
+        assert!(super::IPAddr::new_from(&[239, 255, 255, 250]).is_multicast());
+        assert!(!super::IPAddr::new_from(&[10, 0, 0, 1]).is_multicast());
+        assert!(!super::IPAddr::new_from(&[255, 255, 255, 255]).is_multicast());
+        assert!(super::IPAddr::new_from(&[
+            0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xfb
+        ])
+        .is_multicast());
+        assert!(
+            !super::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])
+                .is_multicast()
+        );
+    }
+
     #[test]
     fn test_seq_compare() {
         assert_eq!(super::seq_gt(0x00000001, 0x00000000), true);
@@ -455,4 +573,53 @@ mod tests {
             0xafb2
         );
     }
+
+    #[test]
+    fn test_bind_addr() {
+        let v4 = super::IPAddr::V4([10, 0, 0, 2]);
+        let v6 = super::IPAddr::parse("fe80::2").unwrap();
+        assert_eq!(
+            super::BindAddr::new(super::IPAddr::V4([0; 4]), false),
+            super::BindAddr::AnyV4
+        );
+        assert_eq!(
+            super::BindAddr::new(super::IPAddr::V4([0; 4]), true),
+            super::BindAddr::AnyV4
+        );
+        assert_eq!(
+            super::BindAddr::new(super::IPAddr::V6([0; 16]), false),
+            super::BindAddr::Any
+        );
+        assert_eq!(
+            super::BindAddr::new(super::IPAddr::V6([0; 16]), true),
+            super::BindAddr::AnyV6
+        );
+        assert_eq!(super::BindAddr::new(v4, false), super::BindAddr::Addr(v4));
+        assert_eq!(super::BindAddr::new(v6, true), super::BindAddr::Addr(v6));
+
+        assert_eq!(
+            super::BindAddr::lookup_order(v4),
+            [
+                super::BindAddr::Addr(v4),
+                super::BindAddr::AnyV4,
+                super::BindAddr::Any
+            ]
+        );
+        assert_eq!(
+            super::BindAddr::lookup_order(v6),
+            [
+                super::BindAddr::Addr(v6),
+                super::BindAddr::AnyV6,
+                super::BindAddr::Any
+            ]
+        );
+
+        assert!(super::BindAddr::Any.allows_family(v4));
+        assert!(super::BindAddr::Any.allows_family(v6));
+        assert!(super::BindAddr::AnyV4.allows_family(v4));
+        assert!(!super::BindAddr::AnyV4.allows_family(v6));
+        assert!(!super::BindAddr::AnyV6.allows_family(v4));
+        assert!(super::BindAddr::Addr(v6).allows_family(super::IPAddr::parse("fe80::3").unwrap()));
+        assert!(!super::BindAddr::Addr(v6).allows_family(v4));
+    }
 }
@@ -0,0 +1,94 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use netstack::{dns, init_netstack, ping};
+use std::env;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        println!("Usage: ping <host> [count] [payload size]");
+        return;
+    }
+
+    init_netstack();
+
+    let mut options = ping::PingOptions::default();
+    if args.len() > 2 {
+        options.count = args[2].parse().expect("Invalid count");
+    }
+
+    if args.len() > 3 {
+        options.payload_size = args[3].parse().expect("Invalid payload size");
+    }
+
+    let result = dns::dns_resolve(&args[1]);
+    if result.is_err() {
+        println!("Failed to resolve {}: {}", args[1], result.err().unwrap());
+        return;
+    }
+
+    let dest_addr = result.unwrap()[0];
+    println!(
+        "PING {} ({}) {} bytes of data",
+        args[1], dest_addr, options.payload_size
+    );
+
+    let result = ping::ping(dest_addr, &options, |result| match result {
+        ping::PingResult::Reply {
+            seq,
+            source_addr,
+            len,
+            rtt,
+        } => println!(
+            "{} bytes from {}: seq={} time={:.2} ms",
+            len,
+            source_addr,
+            seq,
+            rtt.as_secs_f64() * 1000.0
+        ),
+        ping::PingResult::Error {
+            seq,
+            source_addr,
+            message,
+            ..
+        } => println!("From {}: seq={} {}", source_addr, seq, message),
+        ping::PingResult::Timeout { seq } => println!("Request timeout for seq={}", seq),
+    });
+
+    if result.is_err() {
+        println!("Failed to ping: {}", result.err().unwrap());
+        return;
+    }
+
+    let statistics = result.unwrap();
+    println!("--- {} ping statistics ---", args[1]);
+    println!(
+        "{} packets transmitted, {} received, {:.1}% packet loss",
+        statistics.transmitted,
+        statistics.received,
+        statistics.loss_percent()
+    );
+
+    if let Some(average) = statistics.average_rtt() {
+        println!(
+            "rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
+            statistics.min_rtt.as_secs_f64() * 1000.0,
+            average.as_secs_f64() * 1000.0,
+            statistics.max_rtt.as_secs_f64() * 1000.0
+        );
+    }
+}
@@ -30,13 +30,18 @@ fn main() {
     println!("Press key to connect");
     let _ = std::io::stdin().read(&mut [0u8]).unwrap();
 
-    let addr = if ipv6 {
-        util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1])
+    // Any other argument is a host name to look up.
+    let result = if args.len() > 1 && !ipv6 {
+        tcp::tcp_connect_host(&args[1], 3000)
+    } else if ipv6 {
+        tcp::tcp_open(
+            util::IPAddr::new_from(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x1]),
+            3000,
+        )
     } else {
-        util::IPAddr::new_from(&[10, 0, 0, 1])
+        tcp::tcp_open(util::IPAddr::new_from(&[10, 0, 0, 1]), 3000)
     };
 
-    let result = tcp::tcp_open(addr, 3000);
     if result.is_err() {
         println!("Failed to open socket: {}", result.err().unwrap());
         return;
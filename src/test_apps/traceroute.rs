@@ -0,0 +1,262 @@
+//
+// Copyright 2025 Jeff Bush
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Send probes with increasing TTLs. Each router along the path drops the
+// probe whose TTL runs out there and sends back a Time Exceeded error, which
+// identifies it. Probes can be UDP datagrams to an unused port (the
+// destination responds with Port Unreachable), ICMP echo requests, or TCP
+// SYNs (the destination responds with SYN-ACK or RST).
+
+use netstack::{dns, icmp, init_netstack, ip, ping, tcp, udp, util};
+use std::env;
+use std::time::{Duration, Instant};
+
+const MAX_HOPS: u8 = 30;
+const PROBES_PER_HOP: u32 = 3;
+const PROBE_TIMEOUT: u32 = 2000; // ms
+const UDP_BASE_PORT: u16 = 33434;
+const DEFAULT_TCP_PORT: u16 = 80;
+
+enum ProbeMode {
+    Udp,
+    Icmp,
+    Tcp(u16),
+}
+
+// Address that responded, round trip time, and whether it was the
+// destination rather than a router along the way.
+type ProbeResult = Option<(util::IPAddr, Duration, bool)>;
+
+struct UDPProber {
+    socket: udp::SocketReference,
+    next_port: u16,
+}
+
+impl UDPProber {
+    fn new() -> Result<UDPProber, &'static str> {
+        Ok(UDPProber {
+            socket: udp::udp_open(0)?,
+            next_port: UDP_BASE_PORT,
+        })
+    }
+
+    fn probe(&mut self, dest_addr: util::IPAddr, ttl: u8) -> ProbeResult {
+        let mut data = [0u8; 1500];
+        let mut source_addr = util::IPAddr::new();
+        let mut source_port = 0;
+
+        // Discard responses to earlier probes that timed out.
+        while !matches!(
+            udp::udp_try_recv(
+                &mut self.socket,
+                &mut data,
+                &mut source_addr,
+                &mut source_port
+            ),
+            udp::RecvResult::WouldBlock | udp::RecvResult::Closed
+        ) {}
+
+        let metadata = ip::IPMetadata {
+            ttl,
+            ..ip::IPMetadata::new()
+        };
+        udp::udp_set_ip_metadata(&mut self.socket, metadata).unwrap();
+
+        let send_time = Instant::now();
+        let port = self.next_port;
+        self.next_port = self.next_port.wrapping_add(1);
+        if udp::udp_send(&mut self.socket, dest_addr, port, &[0u8; 32]).is_err() {
+            return None;
+        }
+
+        // Errors for earlier probes can still arrive after they timed out.
+        // Ignore any that don't quote this probe's port and keep waiting.
+        let deadline = send_time + Duration::from_millis(PROBE_TIMEOUT as u64);
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let result = udp::udp_recv_timeout(
+                &mut self.socket,
+                &mut data,
+                &mut source_addr,
+                &mut source_port,
+                Some(remaining.as_millis() as u32),
+            );
+            let rtt = send_time.elapsed();
+            match result {
+                udp::RecvResult::Error => {
+                    let error = udp::udp_get_icmp_error(&mut self.socket)?;
+                    if error.dest_addr != dest_addr || error.dest_port != port {
+                        continue;
+                    }
+
+                    return Some((
+                        error.reporter,
+                        rtt,
+                        error.message != icmp::ERROR_TIME_EXCEEDED,
+                    ));
+                }
+
+                // Something is listening on the port and replied.
+                udp::RecvResult::Data(_) => return Some((source_addr, rtt, true)),
+
+                _ => return None,
+            }
+        }
+    }
+}
+
+fn icmp_probe(dest_addr: util::IPAddr, ttl: u8) -> ProbeResult {
+    let options = ping::PingOptions {
+        count: 1,
+        timeout: PROBE_TIMEOUT,
+        ip_metadata: ip::IPMetadata {
+            ttl,
+            ..ip::IPMetadata::new()
+        },
+        ..ping::PingOptions::default()
+    };
+
+    let mut result = None;
+    ping::ping(dest_addr, &options, |probe_result| {
+        result = match *probe_result {
+            ping::PingResult::Reply {
+                source_addr, rtt, ..
+            } => Some((source_addr, rtt, true)),
+            ping::PingResult::Error {
+                source_addr,
+                message,
+                rtt,
+                ..
+            } => Some((source_addr, rtt, message != icmp::ERROR_TIME_EXCEEDED)),
+            ping::PingResult::Timeout { .. } => None,
+        };
+    })
+    .ok()?;
+
+    result
+}
+
+fn tcp_probe(dest_addr: util::IPAddr, port: u16, ttl: u8) -> ProbeResult {
+    let metadata = ip::IPMetadata {
+        ttl,
+        ..ip::IPMetadata::new()
+    };
+
+    let send_time = Instant::now();
+    let mut socket = tcp::tcp_connect_start(dest_addr, port, metadata).ok()?;
+    let result = tcp::tcp_connect_wait(&mut socket, Some(PROBE_TIMEOUT));
+    let rtt = send_time.elapsed();
+    tcp::tcp_close(&mut socket);
+
+    match result {
+        Ok(()) => Some((dest_addr, rtt, true)),
+        Err(_) => match tcp::tcp_get_error(&mut socket) {
+            Some((message, reporter)) => {
+                Some((reporter, rtt, message != icmp::ERROR_TIME_EXCEEDED))
+            }
+
+            // Either the destination reset the connection, or nothing
+            // came back.
+            None if rtt < Duration::from_millis(PROBE_TIMEOUT as u64) => {
+                Some((dest_addr, rtt, true))
+            }
+            None => None,
+        },
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut mode = ProbeMode::Udp;
+    let mut host = None;
+    for arg in &args[1..] {
+        match arg.as_str() {
+            "-I" => mode = ProbeMode::Icmp,
+            "-T" => mode = ProbeMode::Tcp(DEFAULT_TCP_PORT),
+            _ => {
+                if let (ProbeMode::Tcp(_), Some(_)) = (&mode, &host) {
+                    mode = ProbeMode::Tcp(arg.parse().expect("Invalid port"));
+                } else {
+                    host = Some(arg.clone());
+                }
+            }
+        }
+    }
+
+    let host = match host {
+        Some(host) => host,
+        None => {
+            println!("Usage: traceroute [-I | -T] <host> [tcp port]");
+            return;
+        }
+    };
+
+    init_netstack();
+
+    let result = dns::dns_resolve(&host);
+    if result.is_err() {
+        println!("Failed to resolve {}: {}", host, result.err().unwrap());
+        return;
+    }
+
+    let dest_addr = result.unwrap()[0];
+    println!(
+        "traceroute to {} ({}), {} hops max",
+        host, dest_addr, MAX_HOPS
+    );
+
+    let mut udp_prober = None;
+    if let ProbeMode::Udp = mode {
+        match UDPProber::new() {
+            Ok(prober) => udp_prober = Some(prober),
+            Err(message) => {
+                println!("Failed to open socket: {}", message);
+                return;
+            }
+        }
+    }
+
+    for ttl in 1..=MAX_HOPS {
+        let mut line = format!("{:2} ", ttl);
+        let mut last_addr = None;
+        let mut reached = false;
+        for _ in 0..PROBES_PER_HOP {
+            let result = match mode {
+                ProbeMode::Udp => udp_prober.as_mut().unwrap().probe(dest_addr, ttl),
+                ProbeMode::Icmp => icmp_probe(dest_addr, ttl),
+                ProbeMode::Tcp(port) => tcp_probe(dest_addr, port, ttl),
+            };
+
+            match result {
+                Some((addr, rtt, is_dest)) => {
+                    if last_addr != Some(addr) {
+                        line += &format!(" {}", addr);
+                        last_addr = Some(addr);
+                    }
+
+                    line += &format!("  {:.3} ms", rtt.as_secs_f64() * 1000.0);
+                    reached |= is_dest;
+                }
+                None => line += "  *",
+            }
+        }
+
+        println!("{}", line);
+        if reached {
+            break;
+        }
+    }
+}
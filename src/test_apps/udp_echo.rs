@@ -32,19 +32,35 @@ fn main() {
         let mut source_port: u16 = 0;
         let mut data = [0; 1500];
 
-        let received = udp::udp_recv(&mut socket, &mut data, &mut source_addr, &mut source_port);
+        let received = match udp::udp_recv_timeout(
+            &mut socket,
+            &mut data,
+            &mut source_addr,
+            &mut source_port,
+            None,
+        ) {
+            udp::RecvResult::Data(len) => len,
+            udp::RecvResult::Error => {
+                if let Some((message, reporter)) = udp::udp_get_error(&mut socket) {
+                    println!("Error reported by {}: {}", reporter, message);
+                }
+
+                continue;
+            }
+
+            _ => {
+                println!("Socket closed");
+                return;
+            }
+        };
+
         println!(
             "Received UDP packet from {}:{} ({} bytes)",
             source_addr, source_port, received
         );
 
-        util::print_binary(&data[..received as usize]);
-        let result = udp::udp_send(
-            &mut socket,
-            source_addr,
-            source_port,
-            &data[..received as usize],
-        );
+        util::print_binary(&data[..received]);
+        let result = udp::udp_send(&mut socket, source_addr, source_port, &data[..received]);
         if result.is_err() {
             println!("Failed to send packet: {}", result.err().unwrap());
             return;
@@ -14,7 +14,7 @@
 // limitations under the License.
 //
 
-use netstack::{init_netstack, tcp};
+use netstack::{init_netstack, mdns, tcp};
 
 const PORT: u16 = 8080;
 
@@ -28,6 +28,12 @@ fn main() {
 
     println!("Listening on port {}", PORT);
 
+    let result = mdns::mdns_start("netstack")
+        .and_then(|_| mdns::mdns_register_service("netstack web", "_http._tcp", PORT, Vec::new()));
+    if result.is_err() {
+        println!("Failed to start mDNS: {}", result.err().unwrap());
+    }
+
     loop {
         let result = tcp::tcp_accept(listen_sock.as_mut().unwrap());
         if result.is_err() {